@@ -0,0 +1,123 @@
+#ifndef CRIER_H
+#define CRIER_H
+
+/* Generated by cbindgen from src/ffi.rs - do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of every `crier_*` call
+ */
+typedef enum CrierStatus {
+  CRIER_STATUS_OK = 0,
+  /**
+   * A required pointer was NULL or a string was not valid UTF-8
+   */
+  CRIER_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * Preset missing, or no usable target/topic
+   */
+  CRIER_STATUS_CONFIG = 2,
+  /**
+   * Bind, connect or socket error
+   */
+  CRIER_STATUS_IO = 3,
+  /**
+   * MQTT client or broker error
+   */
+  CRIER_STATUS_MQTT = 4,
+  /**
+   * The listener rejected the message (e.g. wrong auth)
+   */
+  CRIER_STATUS_REJECTED = 5,
+  /**
+   * The broker did not answer in time
+   */
+  CRIER_STATUS_TIMEOUT = 6,
+  /**
+   * TLS setup or handshake failure
+   */
+  CRIER_STATUS_TLS = 7,
+} CrierStatus;
+
+/**
+ * Connection settings, mirroring the CLI flags
+ */
+typedef struct CrierOptions {
+  /**
+   * Preset name to load from the config file
+   */
+  const char *preset;
+  /**
+   * Config file path (default: ~/.config/crier.yml)
+   */
+  const char *config;
+  /**
+   * MQTT broker; selects relay mode
+   */
+  const char *relay;
+  /**
+   * MQTT broker port, 0 for the default (1883)
+   */
+  uint16_t port;
+  /**
+   * Topic for relay mode
+   */
+  const char *topic;
+  /**
+   * Authentication token
+   */
+  const char *auth;
+} CrierOptions;
+
+/**
+ * Called for every received message; the strings are only valid during the call
+ */
+typedef void (*CrierCallback)(const char *message, const char *source, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Send `message` to `target` (a direct-mode address, may be NULL when a
+ * preset or relay is given).
+ *
+ * # Safety
+ *
+ * `target`, `message` and every string in `options` must be NULL or valid
+ * NUL-terminated strings; `options` must be NULL or point to a valid struct.
+ */
+enum CrierStatus crier_send(const char *target,
+                            const char *message,
+                            const struct CrierOptions *options);
+
+/**
+ * Listen on `target` (a direct-mode bind address, may be NULL when a preset
+ * or relay is given), calling `callback` for every message. Blocks until
+ * the listener fails.
+ *
+ * # Safety
+ *
+ * Same requirements as [`crier_send`]; `user_data` is passed through to
+ * `callback` untouched.
+ */
+enum CrierStatus crier_listen(const char *target,
+                              const struct CrierOptions *options,
+                              CrierCallback callback,
+                              void *user_data);
+
+/**
+ * Message describing the last failed call on this thread, or NULL.
+ * Valid until the next `crier_*` call on the same thread.
+ */
+const char *crier_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CRIER_H */
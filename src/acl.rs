@@ -0,0 +1,173 @@
+//! Peer address allow/deny lists for the direct listener
+
+use crate::{Error, Result};
+use std::net::IpAddr;
+
+/// An address block such as `192.168.1.0/24`; a bare address is a block of one.
+/// IPv4-mapped blocks (`::ffff:10.0.0.0/104`) are taken as the IPv4 block,
+/// the way peers' addresses are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(text: &str) -> Result<Network> {
+        let invalid = || {
+            Error::Config(format!(
+                "Invalid network '{}' (expected e.g. 192.168.1.0/24)",
+                text
+            ))
+        };
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .trim_matches(['[', ']'])
+            .parse()
+            .map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(invalid());
+        }
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix >= 96) {
+                return Ok(Network {
+                    addr: IpAddr::V4(v4),
+                    prefix: prefix - 96,
+                });
+            }
+        }
+        Ok(Network { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `a` and `b` agree
+fn same_prefix(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+/// Which peers may connect: anything in `deny` is refused; if `allow` is not
+/// empty, only peers in it are accepted
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<Network>,
+    deny: Vec<Network>,
+}
+
+impl Acl {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Acl> {
+        let parse = |list: &[String]| {
+            list.iter()
+                .map(|net| Network::parse(net))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Acl {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    /// No restrictions at all
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(net: &str, ip: &str) -> bool {
+        Network::parse(net).unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_blocks_and_addresses() {
+        assert_eq!(Network::parse("10.0.0.1").unwrap().prefix, 32);
+        assert_eq!(Network::parse("[::1]").unwrap().prefix, 128);
+        assert_eq!(Network::parse("fd00::/8").unwrap().prefix, 8);
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "host",
+            "",
+        ] {
+            assert!(Network::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn prefix_edges() {
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("10.0.0.5/32", "10.0.0.5"));
+        assert!(!contains("10.0.0.5/32", "10.0.0.4"));
+        // /7 covers 10.0.0.0-11.255.255.255
+        assert!(contains("10.0.0.0/7", "11.255.255.255"));
+        assert!(!contains("10.0.0.0/7", "12.0.0.0"));
+        assert!(!contains("10.0.0.0/7", "9.255.255.255"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("2001:db8::/33", "2001:db8:7fff::1"));
+        assert!(!contains("2001:db8::/33", "2001:db8:8000::1"));
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        assert!(!contains("10.0.0.0/8", "::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_and_blocks() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "::ffff:10.1.2.3"));
+        assert!(!contains("::ffff:10.0.0.0/104", "11.0.0.1"));
+        assert!(contains("::ffff:192.168.1.1", "192.168.1.1"));
+        assert_eq!(
+            Network::parse("::ffff:10.0.0.0/104").unwrap(),
+            Network::parse("10.0.0.0/8").unwrap()
+        );
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let acl = Acl::new(&["10.0.0.0/8".into()], &["10.0.0.13".into()]).unwrap();
+        assert!(acl.permits("10.0.0.1".parse().unwrap()));
+        assert!(!acl.permits("10.0.0.13".parse().unwrap()));
+        assert!(!acl.permits("192.168.1.1".parse().unwrap()));
+        let open = Acl::default();
+        assert!(open.is_empty() && open.permits("192.168.1.1".parse().unwrap()));
+    }
+}
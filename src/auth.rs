@@ -0,0 +1,240 @@
+//! Tokens a listener accepts: the `--auth` token and any named ones from the
+//! config file, so each sender can be told apart and revoked on its own.
+//! Any of them may be stored as an argon2 or bcrypt hash instead of the token.
+
+use crate::{tls, Error, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+
+/// An accepted token; `label` is None for the unnamed `--auth` token
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub label: Option<String>,
+    secret: String,
+}
+
+impl Token {
+    /// Stored as a hash (`$argon2…` or bcrypt's `$2b$…`) rather than the token itself
+    pub fn is_hashed(&self) -> bool {
+        is_hash(&self.secret)
+    }
+
+    /// Whether the sender presented this token
+    pub fn matches(&self, presented: &str) -> bool {
+        if self.secret.starts_with("$argon2") {
+            PasswordHash::new(&self.secret).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(presented.as_bytes(), &hash)
+                    .is_ok()
+            })
+        } else if self.is_hashed() {
+            bcrypt::verify(presented, &self.secret).unwrap_or(false)
+        } else {
+            constant_time_eq(presented.as_bytes(), self.secret.as_bytes())
+        }
+    }
+}
+
+/// Every token a listener accepts; empty means no auth
+#[derive(Debug, Clone, Default)]
+pub struct Tokens(Vec<Token>);
+
+impl Tokens {
+    pub fn new(auth: Option<&str>, named: Option<&HashMap<String, String>>) -> Tokens {
+        let mut tokens: Vec<Token> = named
+            .into_iter()
+            .flatten()
+            .map(|(label, secret)| Token {
+                label: Some(label.clone()),
+                secret: secret.clone(),
+            })
+            .collect();
+        tokens.sort_by(|a, b| a.label.cmp(&b.label));
+        if let Some(secret) = auth {
+            tokens.insert(
+                0,
+                Token {
+                    label: None,
+                    secret: secret.to_string(),
+                },
+            );
+        }
+        Tokens(tokens)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any token is only known by its hash
+    pub fn any_hashed(&self) -> bool {
+        self.0.iter().any(Token::is_hashed)
+    }
+
+    /// Labels of the named tokens
+    pub fn labels(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter_map(|token| token.label.as_deref())
+            .collect()
+    }
+
+    /// The token the sender presented
+    pub fn check(&self, presented: &str) -> Option<&Token> {
+        self.0.iter().find(|token| token.matches(presented))
+    }
+
+    /// The token `text` starts with (followed by `:`), and the rest of `text`.
+    /// A hashed token cannot be told from the rest if it contains `:` itself,
+    /// so it is taken to end at the first one.
+    pub fn split<'a>(&self, text: &'a str) -> Option<(&Token, &'a str)> {
+        self.0.iter().find_map(|token| {
+            let rest = if token.is_hashed() {
+                let (presented, rest) = text.split_once(':')?;
+                token.matches(presented).then_some(rest)?
+            } else {
+                text.strip_prefix(token.secret.as_str())?
+                    .strip_prefix(':')?
+            };
+            Some((token, rest))
+        })
+    }
+
+    /// The first plain token for which `accepts` holds, for proofs that need
+    /// the token itself (HMACs); hashed tokens never match
+    pub fn find(&self, accepts: impl Fn(&str) -> bool) -> Option<&Token> {
+        self.0
+            .iter()
+            .find(|token| !token.is_hashed() && accepts(&token.secret))
+    }
+}
+
+fn is_hash(secret: &str) -> bool {
+    ["$argon2", "$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| secret.starts_with(prefix))
+}
+
+/// argon2id hash of `token` (PHC string format) to put in a listener's config
+pub fn hash(token: &str) -> Result<String> {
+    let mut salt = [0; 16];
+    tls::provider()
+        .secure_random
+        .fill(&mut salt)
+        .map_err(|_| Error::Config("No randomness available for a salt".into()))?;
+    let salt = SaltString::encode_b64(&salt)
+        .map_err(|e| Error::Config(format!("Hashing failed: {}", e)))?;
+    Argon2::default()
+        .hash_password(token.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::Config(format!("Hashing failed: {}", e)))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(tokens: &[(&str, &str)]) -> HashMap<String, String> {
+        tokens
+            .iter()
+            .map(|(label, secret)| (label.to_string(), secret.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn plain_tokens() {
+        let tokens = Tokens::new(Some("secret"), None);
+        assert!(tokens
+            .check("secret")
+            .is_some_and(|token| token.label.is_none()));
+        assert!(tokens.check("secre").is_none());
+        assert!(tokens.check("secrets").is_none());
+        assert!(tokens.check("").is_none());
+        assert!(Tokens::default().is_empty());
+    }
+
+    #[test]
+    fn hashed_tokens() {
+        let argon2 = hash("ci token").unwrap();
+        let bcrypt = bcrypt::hash("laptop token", 4).unwrap();
+        let tokens = Tokens::new(
+            None,
+            Some(&named(&[
+                ("ci", &argon2),
+                ("laptop", &bcrypt),
+                ("phone", "plain"),
+            ])),
+        );
+        assert!(tokens.any_hashed());
+        assert_eq!(tokens.labels(), ["ci", "laptop", "phone"]);
+        let label = |presented| {
+            tokens
+                .check(presented)
+                .and_then(|token| token.label.as_deref())
+        };
+        assert_eq!(label("ci token"), Some("ci"));
+        assert_eq!(label("laptop token"), Some("laptop"));
+        assert_eq!(label("plain"), Some("phone"));
+        // The hash itself is no token
+        assert_eq!(label(&argon2), None);
+        assert_eq!(label(&bcrypt), None);
+    }
+
+    #[test]
+    fn split_plain_tokens() {
+        let tokens = Tokens::new(Some("secret"), Some(&named(&[("colons", "a:b")])));
+        let split = |text| {
+            tokens
+                .split(text)
+                .map(|(token, rest)| (token.label.as_deref(), rest))
+        };
+        assert_eq!(split("secret:hello: world"), Some((None, "hello: world")));
+        assert_eq!(split("secret:"), Some((None, "")));
+        assert_eq!(split("a:b:hello"), Some((Some("colons"), "hello")));
+        assert_eq!(split("a:hello"), None);
+        assert_eq!(split("secret"), None);
+        assert_eq!(split("secrethello"), None);
+        assert_eq!(split("hello"), None);
+    }
+
+    #[test]
+    fn split_hashed_tokens() {
+        let tokens = Tokens::new(
+            None,
+            Some(&named(&[
+                ("ci", &hash("ci token").unwrap()),
+                ("colons", &hash("a:b").unwrap()),
+            ])),
+        );
+        let split = |text| {
+            tokens
+                .split(text)
+                .map(|(token, rest)| (token.label.as_deref(), rest))
+        };
+        assert_eq!(
+            split("ci token:hello: world"),
+            Some((Some("ci"), "hello: world"))
+        );
+        assert_eq!(split("other:hello"), None);
+        // A hashed token ends at its first `:`, so one holding `:` never matches
+        assert_eq!(split("a:b:hello"), None);
+    }
+
+    #[test]
+    fn find_skips_hashed_tokens() {
+        let tokens = Tokens::new(
+            Some("plain"),
+            Some(&named(&[("ci", &hash("hashed").unwrap())])),
+        );
+        assert!(tokens.find(|secret| secret == "plain").is_some());
+        assert!(tokens.find(|secret| secret == "hashed").is_none());
+        assert!(tokens
+            .find(|secret| secret.starts_with("$argon2"))
+            .is_none());
+    }
+}
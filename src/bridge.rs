@@ -0,0 +1,151 @@
+//! A gateway between direct mode and an MQTT broker (`crier bridge`):
+//! messages sent to it over TCP are published to a topic and, with `to`,
+//! messages published there go on to a direct listener, so senders that can
+//! only reach the LAN get to remote listeners and the other way round.
+//! Payloads pass through as they are, so signed or encrypted messages stay
+//! that way and the bridge needs no keys.
+
+use crate::acl::Acl;
+use crate::auth::Tokens;
+use crate::direct::{self, AuthMode, Limits};
+use crate::{relay, shutdown, Result};
+use rumqttc::{Event, Outgoing, Packet, QoS};
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use tracing::{info, warn};
+
+/// Most payloads remembered as published by the bridge itself, so they
+/// aren't sent back to the LAN when the broker echoes them
+const MAX_ECHOES: usize = 64;
+
+/// The broker side of a bridge, and where its messages go on the LAN
+pub struct Bridge {
+    pub broker: String,
+    pub port: u16,
+    pub topic: String,
+    /// TLS, client id, keep-alive, timeout and packet size for the broker
+    /// connection (listener settings such as `qos` don't apply)
+    pub mqtt: relay::Options,
+    /// Direct listener to send what is published to `topic` to
+    pub to: Option<String>,
+    /// Token to pass messages on with, on either side
+    pub auth: Option<String>,
+    pub mode: AuthMode,
+    pub limits: Limits,
+}
+
+/// Publish every message sent to `listeners` (from senders `tokens` and `acl`
+/// accept) to the broker and, with `to`, send everything published to the
+/// topic by others to that listener. Runs until the listener fails.
+pub fn run(listeners: Vec<TcpListener>, tokens: &Tokens, acl: &Acl, bridge: &Bridge) -> Result<()> {
+    let id = relay::client_id(bridge.mqtt.client_id.as_deref(), "bridge", false)?;
+    let opts = relay::client_options(
+        id,
+        &bridge.broker,
+        bridge.port,
+        &bridge.mqtt,
+        relay::LISTENER_KEEP_ALIVE,
+    );
+    let (client, mut connection) = relay::connect(opts, &bridge.mqtt);
+    let (disconnected, gone) = mpsc::channel();
+    let stopping = client.clone();
+    shutdown::on_stop(move || relay::disconnect(|| stopping.disconnect().is_ok(), gone));
+    let echoes = Mutex::new(VecDeque::new());
+
+    thread::scope(|scope| {
+        let echoes = &echoes;
+        let subscriber = &client;
+        scope.spawn(move || {
+            let mut reconnect = relay::Reconnect::default();
+            for event in connection.iter() {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        reconnect.failed(e);
+                        continue;
+                    }
+                };
+                if let Event::Incoming(Packet::ConnAck(ack)) = &event {
+                    reconnect.connected(ack.session_present);
+                    if bridge.to.is_some() {
+                        if let Err(e) = subscriber.try_subscribe(&bridge.topic, QoS::AtLeastOnce) {
+                            warn!("Cannot subscribe: {}", e);
+                        }
+                    }
+                }
+                if let Event::Outgoing(Outgoing::Disconnect) = event {
+                    let _ = disconnected.send(());
+                    break;
+                }
+                let (Event::Incoming(Packet::Publish(msg)), Some(to)) = (event, &bridge.to) else {
+                    continue;
+                };
+                let payload = String::from_utf8_lossy(&msg.payload);
+                if forget(echoes, &payload) {
+                    continue;
+                }
+                let Some(incoming) = relay::open(&payload, tokens, msg.topic) else {
+                    warn!("Auth failed, ignoring message");
+                    continue;
+                };
+                let source = incoming.topic.unwrap_or_default();
+                match direct::send(
+                    to,
+                    &incoming.message,
+                    bridge.auth.as_deref(),
+                    bridge.mode,
+                    None,
+                    None,
+                    None,
+                ) {
+                    Ok(()) => info!("[{}] -> {}", source, to),
+                    Err(e) => warn!("[{}] Sending to {} failed: {}", source, to, e),
+                }
+            }
+        });
+
+        let served = direct::serve(
+            listeners,
+            tokens,
+            bridge.mode,
+            None,
+            acl,
+            false,
+            bridge.limits,
+            |incoming| {
+                let payload = relay::seal(&incoming.message, bridge.auth.as_deref());
+                if bridge.to.is_some() {
+                    let mut echoes = echoes.lock().unwrap_or_else(PoisonError::into_inner);
+                    if echoes.len() == MAX_ECHOES {
+                        echoes.pop_front();
+                    }
+                    echoes.push_back(payload.clone());
+                }
+                match client.publish(&bridge.topic, QoS::AtLeastOnce, false, payload.into_bytes()) {
+                    Ok(()) => info!("[{}] -> {}", incoming.source, bridge.topic),
+                    Err(e) => warn!(
+                        "[{}] Publishing to {} failed: {}",
+                        incoming.source, bridge.topic, e
+                    ),
+                }
+            },
+        );
+        // Let the broker side finish too
+        let _ = client.disconnect();
+        served
+    })
+}
+
+/// Whether `payload` is one the bridge published itself (forgetting it)
+fn forget(echoes: &Mutex<VecDeque<String>>, payload: &str) -> bool {
+    let mut echoes = echoes.lock().unwrap_or_else(PoisonError::into_inner);
+    match echoes.iter().position(|echo| echo == payload) {
+        Some(i) => {
+            echoes.remove(i);
+            true
+        }
+        None => false,
+    }
+}
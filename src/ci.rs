@@ -0,0 +1,46 @@
+//! CI job annotations, so a sent message also shows up in the pipeline UI
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CiPlatform {
+    /// GitHub Actions workflow command (`::notice::`)
+    Github,
+    /// GitLab CI collapsible log section
+    Gitlab,
+}
+
+impl CiPlatform {
+    /// Format `message` as this platform's job annotation
+    pub fn annotation(self, message: &str) -> String {
+        match self {
+            CiPlatform::Github => format!("::notice title=crier::{}", escape_github(message)),
+            CiPlatform::Gitlab => {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                let mut lines = message.lines();
+                let header = lines.next().unwrap_or_default();
+                let mut out = format!(
+                    "\x1b[0Ksection_start:{}:crier\r\x1b[0K\x1b[1;36m📣 {}\x1b[0m\n",
+                    ts, header
+                );
+                for line in lines {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out.push_str(&format!("\x1b[0Ksection_end:{}:crier\r\x1b[0K", ts));
+                out
+            }
+        }
+    }
+}
+
+/// Workflow commands are line based; newlines and `%` must be percent-encoded
+fn escape_github(message: &str) -> String {
+    message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
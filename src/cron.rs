@@ -0,0 +1,164 @@
+//! Cron job wrapper: run a job, remember whether it last failed, and decide
+//! whether this run is worth a notification.
+
+use crate::{template, Error, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+pub const FAILURE_TEMPLATE: &str = "{name} failed with exit code {code} after {duration}";
+pub const RECOVERY_TEMPLATE: &str = "{name} recovered after {duration}";
+pub const SUCCESS_TEMPLATE: &str = "{name} succeeded in {duration}";
+
+/// Which outcomes produce a notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NotifyOn {
+    /// Every failed run
+    Failure,
+    /// The first successful run after a failure
+    Recovery,
+    /// Every successful run (including recoveries)
+    Success,
+}
+
+/// What happened, compared to the previous run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Failed,
+    Recovered,
+    Succeeded,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Failed => "failed",
+            Outcome::Recovered => "recovered",
+            Outcome::Succeeded => "succeeded",
+        }
+    }
+
+    pub fn should_notify(self, notify_on: &[NotifyOn]) -> bool {
+        match self {
+            Outcome::Failed => notify_on.contains(&NotifyOn::Failure),
+            Outcome::Recovered => {
+                notify_on.contains(&NotifyOn::Recovery) || notify_on.contains(&NotifyOn::Success)
+            }
+            Outcome::Succeeded => notify_on.contains(&NotifyOn::Success),
+        }
+    }
+
+    fn default_template(self) -> &'static str {
+        match self {
+            Outcome::Failed => FAILURE_TEMPLATE,
+            Outcome::Recovered => RECOVERY_TEMPLATE,
+            Outcome::Succeeded => SUCCESS_TEMPLATE,
+        }
+    }
+}
+
+/// A finished run of the wrapped command
+#[derive(Debug, Clone)]
+pub struct JobRun {
+    /// Exit code; 127 if the command could not be started, -1 if killed by a signal
+    pub code: i32,
+    pub duration: Duration,
+}
+
+impl JobRun {
+    pub fn succeeded(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Run `command` (no shell), passing its output through
+pub fn run(command: &[String]) -> JobRun {
+    let start = Instant::now();
+    let code = match command.split_first() {
+        Some((program, args)) => match Command::new(program).args(args).status() {
+            Ok(status) => status.code().unwrap_or(-1),
+            Err(e) => {
+                error!("Failed to run {}: {}", program, e);
+                127
+            }
+        },
+        None => 127,
+    };
+    JobRun {
+        code,
+        duration: start.elapsed(),
+    }
+}
+
+/// Compare with the stored state for `name`, then store this run's state
+pub fn record(name: &str, run: &JobRun) -> Result<Outcome> {
+    let path = state_path(name)?;
+    let previously_failed = fs::read_to_string(&path).is_ok_and(|s| s.trim() == "failed");
+
+    let outcome = match (run.succeeded(), previously_failed) {
+        (false, _) => Outcome::Failed,
+        (true, true) => Outcome::Recovered,
+        (true, false) => Outcome::Succeeded,
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, if run.succeeded() { "ok\n" } else { "failed\n" })?;
+    Ok(outcome)
+}
+
+/// Message for this run; `template` overrides the per-outcome default and may use
+/// `{name}`, `{status}`, `{code}`, `{duration}` and `{command}`
+pub fn message(
+    name: &str,
+    command: &[String],
+    run: &JobRun,
+    outcome: Outcome,
+    template: Option<&str>,
+) -> String {
+    template::render(template.unwrap_or(outcome.default_template()), |field| {
+        Some(match field {
+            "name" => name.to_string(),
+            "status" => outcome.name().to_string(),
+            "code" => run.code.to_string(),
+            "duration" => format_duration(run.duration),
+            "command" => command.join(" "),
+            _ => return None,
+        })
+    })
+}
+
+/// `3725s` -> `1h 2m 5s`
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, 0) => format!("{}ms", d.as_millis()),
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+/// `~/.local/state/crier/cron/<name>` (or the platform's local data dir)
+fn state_path(name: &str) -> Result<PathBuf> {
+    let file: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if file.is_empty() {
+        return Err(Error::Config("--name must not be empty".into()));
+    }
+    let base = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    Ok(base.join("crier").join("cron").join(file))
+}
@@ -0,0 +1,204 @@
+//! End-to-end encryption of message bodies with a shared key
+//! (XChaCha20-Poly1305), so brokers and relays only ever see ciphertext
+
+use crate::{tls, Error, Incoming, Result};
+use argon2::Argon2;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Marks an encrypted message: `ENC:` + base64(nonce || ciphertext)
+const PREFIX: &str = "ENC:";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// A shared key, given directly or as a file holding it
+#[derive(Clone)]
+pub struct Key {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    /// `spec` is a path to an existing file (whose contents are the key) or
+    /// the key itself. Any text works; the 256-bit key is derived from it with
+    /// argon2id, once, with a salt that comes from the text too (so that
+    /// every side derives the same key and a listener never has to derive one
+    /// for a message), but use something long and random anyway (e.g.
+    /// `openssl rand -base64 32`).
+    pub fn load(spec: &str) -> Result<Key> {
+        let text = key_text(spec)?;
+        Ok(Key {
+            cipher: derive(&text, &salt(&text))?,
+        })
+    }
+
+    pub fn encrypt(&self, message: &str) -> Result<String> {
+        let nonce: [u8; NONCE_LEN] = random()?;
+        let ciphertext = self
+            .cipher
+            .encrypt(&XNonce::from(nonce), message.as_bytes())
+            .map_err(|_| Error::Config("Encryption failed".into()))?;
+        let sealed = [nonce.as_slice(), &ciphertext].concat();
+        Ok(format!(
+            "{}{}",
+            PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    /// The plaintext, or None if `message` was not encrypted with this key
+    pub fn decrypt(&self, message: &str) -> Option<String> {
+        let sealed = base64::engine::general_purpose::STANDARD
+            .decode(message.strip_prefix(PREFIX)?.trim())
+            .ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+        let plaintext = self.cipher.decrypt(&XNonce::from(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// The salt for the key derived from `text`: the start of its SHA-256,
+/// under a label of its own
+fn salt(text: &str) -> [u8; SALT_LEN] {
+    let digest = Sha256::new()
+        .chain_update("crier encryption key\0")
+        .chain_update(text)
+        .finalize();
+    let mut salt = [0; SALT_LEN];
+    salt.copy_from_slice(&digest[..SALT_LEN]);
+    salt
+}
+
+fn derive(text: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(text.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::Config(format!("Cannot derive the key: {}", e)))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    tls::provider()
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| Error::Config("No randomness available".into()))?;
+    Ok(bytes)
+}
+
+/// The key in `spec`: the contents of the file it names, or `spec` itself.
+/// A `spec` that looks like a path (see [`looks_like_path`]) must name an
+/// existing file, so a typo isn't quietly taken for the key.
+pub(crate) fn key_text(spec: &str) -> Result<String> {
+    // Config files get no shell expansion
+    let path = match (spec.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(spec),
+    };
+    let text = if path.is_file() {
+        fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("Failed to read key {}: {}", path.display(), e)))?
+    } else if looks_like_path(spec, &path) {
+        return Err(Error::Config(format!(
+            "Key file {} does not exist",
+            path.display()
+        )));
+    } else {
+        spec.to_string()
+    };
+    match text.trim() {
+        "" => Err(Error::Config("The key is empty".into())),
+        text => Ok(text.to_string()),
+    }
+}
+
+/// Whether `spec` (resolved to `path`) is meant as a file: it starts with
+/// `~`, `./` or `../`, or its directory exists and isn't `/`. A bare key may
+/// hold `/` itself, even first (base64 does).
+fn looks_like_path(spec: &str, path: &Path) -> bool {
+    ["~", "./", "../"]
+        .iter()
+        .any(|prefix| spec.starts_with(prefix))
+        || (spec.contains('/')
+            && path
+                .parent()
+                .is_some_and(|dir| dir.is_dir() && dir.parent().is_some()))
+}
+
+/// Wrap `handler` so that, with a key, it only sees messages that decrypt
+/// with it (as plaintext); anything else is logged and dropped
+pub fn decrypting(key: Option<Key>, mut handler: impl FnMut(&Incoming)) -> impl FnMut(&Incoming) {
+    move |incoming: &Incoming| match &key {
+        None => handler(incoming),
+        Some(key) => match key.decrypt(&incoming.message) {
+            Some(message) => handler(&Incoming {
+                message,
+                ..incoming.clone()
+            }),
+            None => warn!("[{}] Cannot decrypt message, ignoring", incoming.source),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let (sender, listener) = (
+            Key::load("shared secret").unwrap(),
+            Key::load("shared secret").unwrap(),
+        );
+        let sealed = sender.encrypt("hello").unwrap();
+        assert!(sealed.starts_with(PREFIX));
+        assert_eq!(listener.decrypt(&sealed).as_deref(), Some("hello"));
+        assert_ne!(sender.encrypt("hello").unwrap(), sealed);
+    }
+
+    #[test]
+    fn rejects_other_keys_and_plaintext() {
+        let sealed = Key::load("shared secret")
+            .unwrap()
+            .encrypt("hello")
+            .unwrap();
+        let other = Key::load("other secret").unwrap();
+        assert_eq!(other.decrypt(&sealed), None);
+        assert_eq!(other.decrypt("hello"), None);
+        assert_eq!(other.decrypt("ENC:"), None);
+        assert_eq!(other.decrypt("ENC:not base64"), None);
+    }
+
+    #[test]
+    fn keys_may_start_with_a_slash() {
+        for key in [
+            "/6OalBJzsbR6Su3YcdNtQNCHUWvfk6hY1CP6qtqTyTI=",
+            "/5SjPdh1Lj4/tgUoE++1YIVFFmdxiZxzXXuschFtSIY=",
+        ] {
+            assert_eq!(key_text(key).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn paths_must_exist() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("crier-missing-{}.key", std::process::id()));
+        assert!(key_text(&missing.to_string_lossy()).is_err());
+        assert!(key_text("./crier-missing.key").is_err());
+        assert!(key_text("~/crier-missing.key").is_err());
+
+        let file = dir.join(format!("crier-crypto-{}.key", std::process::id()));
+        fs::write(&file, "  from a file\n").unwrap();
+        let text = key_text(&file.to_string_lossy());
+        fs::remove_file(&file).unwrap();
+        assert_eq!(text.unwrap(), "from a file");
+    }
+}
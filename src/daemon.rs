@@ -0,0 +1,349 @@
+//! Running a listener in the background without a service manager
+//! (`--daemon`), and stopping or restarting it (`crier stop`, `crier restart`)
+
+use crate::{args, Error, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long `stop` waits for the listener to exit before killing it
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the background process tells the one that started it how it went:
+/// `OK` once it is listening, or the error it stopped with
+static STARTUP: Mutex<Option<File>> = Mutex::new(None);
+/// The background process's pidfile, removed when it stops with an error
+static PIDFILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// `~/.local/state/crier/<name>.<extension>` (or the platform's local data
+/// dir), where `name` is the preset's or `crier`
+pub fn default_path(preset: Option<&str>, extension: &str) -> PathBuf {
+    let name: String = preset
+        .unwrap_or("crier")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let base = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("crier").join(format!("{}.{}", name, extension))
+}
+
+/// Create `path` for writing, readable by its owner only; fails if it exists
+pub(crate) fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Create `dir` and its parents, `dir` itself closed to everyone but its owner
+pub(crate) fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o700))?;
+    Ok(())
+}
+
+/// The command line `pidfile`'s process was started with, for `restart`:
+/// NUL-separated, the working directory first, then the arguments less any
+/// secrets. If some were left out, their names come before it in one entry
+/// starting with `!`.
+fn cmdline_path(pidfile: &Path) -> PathBuf {
+    let mut path = pidfile.as_os_str().to_owned();
+    path.push(".cmdline");
+    PathBuf::from(path)
+}
+
+/// Fork into the background with output appended to `log`, and write the
+/// child's pid to `pidfile`, which must not belong to a live process. Only
+/// the child returns; the parent waits until it calls [`ready`] or
+/// [`failed`] and exits accordingly. Call it before starting any threads.
+#[cfg(unix)]
+pub fn start(pidfile: &Path, log: &Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if let Some(pid) = running(pidfile)? {
+        return Err(Error::Config(format!(
+            "crier is already running (pid {}, see {}); stop it or give another --pidfile",
+            pid,
+            pidfile.display()
+        )));
+    }
+    for path in [pidfile, log] {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    // Created before forking, so that of two starts only one gets it
+    let mut pid_output = create_private(pidfile).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => Error::Config(format!(
+            "crier is already starting (see {}); stop it or give another --pidfile",
+            pidfile.display()
+        )),
+        _ => Error::Config(format!("Cannot create {}: {}", pidfile.display(), e)),
+    })?;
+    let mut cmdline = Vec::new();
+    let mut argv = std::env::args_os().map(|arg| arg.to_string_lossy().into_owned());
+    let program = argv.next().unwrap_or_default();
+    let (kept, secrets) = args::without_secrets(argv, &[]);
+    if !secrets.is_empty() {
+        cmdline.extend_from_slice(format!("!{}", secrets.join(",")).as_bytes());
+        cmdline.push(0);
+    }
+    for part in std::iter::once(std::env::current_dir()?.into_os_string())
+        .chain(std::iter::once(program.into()))
+        .chain(kept.into_iter().map(Into::into))
+    {
+        cmdline.extend_from_slice(part.as_bytes());
+        cmdline.push(0);
+    }
+    let _ = fs::remove_file(cmdline_path(pidfile));
+    let saved =
+        create_private(&cmdline_path(pidfile)).and_then(|mut file| file.write_all(&cmdline));
+    if let Err(e) = saved {
+        remove(pidfile);
+        return Err(Error::Config(format!(
+            "Cannot write {}: {}",
+            cmdline_path(pidfile).display(),
+            e
+        )));
+    }
+    let forked = fork(pidfile, log, &mut pid_output);
+    if forked.is_err() {
+        remove(pidfile);
+    }
+    forked
+}
+
+/// [`start`] once `pidfile` is created, as `pid_output`
+#[cfg(unix)]
+fn fork(pidfile: &Path, log: &Path, pid_output: &mut File) -> Result<()> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let output = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .map_err(|e| Error::Config(format!("Cannot open log file {}: {}", log.display(), e)))?;
+    let null = File::open("/dev/null")?;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => {
+            drop(reader);
+            unsafe {
+                libc::setsid();
+                libc::dup2(null.as_raw_fd(), 0);
+                libc::dup2(output.as_raw_fd(), 1);
+                libc::dup2(output.as_raw_fd(), 2);
+            }
+            let _ = PIDFILE.set(pidfile.to_path_buf());
+            *STARTUP.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+            if let Err(e) = writeln!(pid_output, "{}", std::process::id()) {
+                failed(&format!("Cannot write {}: {}", pidfile.display(), e));
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        pid => {
+            drop(writer);
+            let mut status = String::new();
+            let _ = reader.read_to_string(&mut status);
+            match status.strip_prefix("OK") {
+                Some(_) => {
+                    println!(
+                        "Running in the background (pid {}), logging to {}",
+                        pid,
+                        log.display()
+                    );
+                    std::process::exit(0)
+                }
+                None if !status.is_empty() => {
+                    eprintln!("Error: {}", status);
+                    std::process::exit(1)
+                }
+                None => {
+                    remove(pidfile);
+                    eprintln!("Error: crier stopped while starting; see {}", log.display());
+                    std::process::exit(1)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn start(_pidfile: &Path, _log: &Path) -> Result<()> {
+    Err(Error::Unsupported("--daemon on Windows"))
+}
+
+/// The listener is up: let the process that started it in the background exit
+pub fn ready() {
+    report("OK");
+}
+
+/// The listener stopped with `error`: have the process that started it in
+/// the background report it if it is still waiting, and remove the pidfile
+pub fn failed(error: &str) {
+    report(error);
+    if let Some(pidfile) = PIDFILE.get() {
+        remove(pidfile);
+    }
+}
+
+fn report(status: &str) {
+    if let Some(mut startup) = STARTUP.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = startup.write_all(status.as_bytes());
+    }
+}
+
+/// The listener is stopping cleanly: remove its pidfile
+pub fn stopped() {
+    if let Some(pidfile) = PIDFILE.get() {
+        remove(pidfile);
+    }
+}
+
+/// The pid in `pidfile` if that process is still running; a stale pidfile
+/// is removed
+#[cfg(unix)]
+fn running(pidfile: &Path) -> Result<Option<i32>> {
+    let text = match fs::read_to_string(pidfile) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::Config(format!(
+                "Cannot read {}: {}",
+                pidfile.display(),
+                e
+            )))
+        }
+    };
+    if text.is_empty() {
+        return Err(Error::Config(format!(
+            "crier is still starting (see {})",
+            pidfile.display()
+        )));
+    }
+    let pid: i32 = text
+        .trim()
+        .parse()
+        .map_err(|_| Error::Config(format!("{} does not hold a pid", pidfile.display())))?;
+    if pid > 0 && alive(pid) {
+        return Ok(Some(pid));
+    }
+    remove(pidfile);
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn alive(pid: i32) -> bool {
+    unsafe {
+        libc::kill(pid, 0) == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+fn remove(pidfile: &Path) {
+    let _ = fs::remove_file(pidfile);
+    let _ = fs::remove_file(cmdline_path(pidfile));
+}
+
+/// Stop the listener in `pidfile`: SIGTERM, then SIGKILL if it is still
+/// running after [`STOP_TIMEOUT`]
+#[cfg(unix)]
+pub fn stop(pidfile: &Path) -> Result<()> {
+    let Some(pid) = running(pidfile)? else {
+        return Err(Error::Config(format!(
+            "crier is not running (no live pid in {})",
+            pidfile.display()
+        )));
+    };
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while alive(pid) {
+        if Instant::now() >= deadline {
+            eprintln!(
+                "Still running after {}s, killing it",
+                STOP_TIMEOUT.as_secs()
+            );
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    remove(pidfile);
+    println!("Stopped crier (pid {})", pid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn stop(_pidfile: &Path) -> Result<()> {
+    Err(Error::Unsupported("crier stop on Windows"))
+}
+
+/// Stop the listener in `pidfile` and start it again the way it was
+/// started, returning once it is running in the background again
+#[cfg(unix)]
+pub fn restart(pidfile: &Path) -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let cmdline = fs::read(cmdline_path(pidfile)).map_err(|e| {
+        Error::Config(format!(
+            "Cannot tell how crier was started ({}): {}",
+            pidfile.display(),
+            e
+        ))
+    })?;
+    let mut parts = cmdline
+        .strip_suffix(&[0])
+        .unwrap_or_default()
+        .split(|b| *b == 0)
+        .map(OsStr::from_bytes);
+    let first = parts.next();
+    if let Some(secrets) = first.and_then(|first| first.as_bytes().strip_prefix(b"!")) {
+        return Err(Error::Config(format!(
+            "crier was started with {} on its command line, which isn't kept on disk; \
+             stop it and start it again by hand, or keep it in a preset or the keyring",
+            String::from_utf8_lossy(secrets).replace(',', ", ")
+        )));
+    }
+    let (Some(dir), Some(_program)) = (first, parts.next()) else {
+        return Err(Error::Config(format!(
+            "{} is garbled",
+            cmdline_path(pidfile).display()
+        )));
+    };
+    let args: Vec<_> = parts.collect();
+    stop(pidfile)?;
+    let status = std::process::Command::new(std::env::current_exe()?)
+        .args(args)
+        .current_dir(dir)
+        .status()?;
+    if !status.success() {
+        return Err(Error::Rejected("crier did not start again".into()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn restart(_pidfile: &Path) -> Result<()> {
+    Err(Error::Unsupported("crier restart on Windows"))
+}
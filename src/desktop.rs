@@ -0,0 +1,105 @@
+//! Native desktop notifications: freedesktop (Linux/BSD), Notification Center
+//! (macOS) and toasts (Windows), without shelling out to `notify-send`, with
+//! the `desktop` feature
+
+use crate::{Error, Incoming, Result};
+#[cfg(feature = "desktop")]
+use notify_rust::Notification;
+#[cfg(feature = "desktop")]
+use std::io;
+
+/// Platform-specific notification settings
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Application the notification is shown as: an AppUserModelID on Windows
+    /// (default: PowerShell's, which every install has registered) or a bundle
+    /// identifier on macOS (default: Finder)
+    pub app_id: Option<String>,
+}
+
+/// Show `incoming` as a notification: its title (or source) as the summary,
+/// the message (without formatting) as the body, and its image if it came with one
+#[cfg(feature = "desktop")]
+#[cfg_attr(
+    not(any(target_os = "windows", target_os = "macos")),
+    allow(unused_variables)
+)]
+pub fn notify(incoming: &Incoming, options: &Options) -> Result<()> {
+    let mut notification = Notification::new();
+    notification
+        .appname("crier")
+        .summary(incoming.title.as_deref().unwrap_or(&incoming.source))
+        .body(&incoming.text());
+    // macOS and Windows show a subtitle line; the freedesktop spec has none
+    if incoming.title.is_some() {
+        notification.subtitle(&incoming.source);
+    }
+    if let Some(image) = &incoming.image {
+        notification.image_path(&image.to_string_lossy());
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        use crate::Priority;
+        use notify_rust::{Timeout, Urgency};
+        notification.urgency(match incoming.priority {
+            Priority::Low => Urgency::Low,
+            Priority::Normal => Urgency::Normal,
+            Priority::High | Priority::Urgent => Urgency::Critical,
+        });
+        // Urgent messages stay until dismissed
+        if incoming.priority == Priority::Urgent {
+            notification.timeout(Timeout::Never);
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use crate::Priority;
+        use notify_rust::{Timeout, Urgency};
+        if let Some(app_id) = &options.app_id {
+            notification.app_id(app_id);
+        }
+        // Critical toasts use the reminder scenario and stay until dismissed;
+        // high priority ones get the long (25s) duration instead
+        match incoming.priority {
+            Priority::Urgent => {
+                notification.urgency(Urgency::Critical);
+            }
+            Priority::High => {
+                notification.timeout(Timeout::Never);
+            }
+            Priority::Low | Priority::Normal => {}
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use crate::Priority;
+        use std::sync::Once;
+        // Notification Center only lets a process pick its application once
+        static APPLICATION: Once = Once::new();
+        if let Some(app_id) = &options.app_id {
+            APPLICATION.call_once(|| {
+                if let Err(e) = notify_rust::set_application(app_id) {
+                    tracing::warn!("Cannot show notifications as {}: {}", app_id, e);
+                }
+            });
+        }
+        if incoming.priority >= Priority::High {
+            notification.sound_name("Default");
+        }
+    }
+
+    notification
+        .show()
+        .map(|_| ())
+        .map_err(|e| Error::Io(io::Error::other(e)))
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn notify(_incoming: &Incoming, _options: &Options) -> Result<()> {
+    Err(Error::Unsupported(
+        "Desktop notifications without the desktop feature",
+    ))
+}
@@ -0,0 +1,165 @@
+//! Running a listener's command once per window for all the messages that
+//! arrived during it (`--digest`), instead of once per message
+
+use crate::exec::{Action, Options};
+use crate::{health, shutdown, Format, Incoming};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Messages waiting for the same command
+struct Batch {
+    action: Action,
+    messages: Vec<Incoming>,
+    /// When the window that opened with the first of them closes
+    due: Instant,
+}
+
+#[derive(Default)]
+struct Pending {
+    batches: Mutex<Vec<Batch>>,
+    added: Condvar,
+}
+
+/// Collects messages per command and runs each command once its window is
+/// over, on a thread of its own so the listener keeps receiving meanwhile
+pub struct Digest {
+    window: Duration,
+    pending: Arc<Pending>,
+}
+
+impl Digest {
+    /// Start the thread; a window opens with the first message for a command
+    pub fn new(window: Duration, options: Options) -> Digest {
+        let pending = Arc::new(Pending::default());
+        let shared = Arc::clone(&pending);
+        thread::spawn(move || loop {
+            for batch in shared.wait_due() {
+                let count = batch.messages.len();
+                health::dequeued(count);
+                let incoming = combine(batch.messages);
+                info!(
+                    "[{}] Digest of {}: running the command",
+                    incoming.source,
+                    plural(count)
+                );
+                batch.action.run(&incoming, &options);
+            }
+        });
+        let stopping = Arc::clone(&pending);
+        shutdown::on_stop(move || {
+            let batches = stopping
+                .batches
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let left: usize = batches.iter().map(|batch| batch.messages.len()).sum();
+            if left > 0 {
+                warn!("Dropping the digest of {}", plural(left));
+            }
+        });
+        Digest { window, pending }
+    }
+
+    /// Add `incoming` to the next run of `action`
+    pub fn push(&self, action: &Action, incoming: &Incoming) {
+        health::queued(1);
+        let mut batches = self
+            .pending
+            .batches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match batches.iter_mut().find(|batch| batch.action == *action) {
+            Some(batch) => batch.messages.push(incoming.clone()),
+            None => {
+                batches.push(Batch {
+                    action: action.clone(),
+                    messages: vec![incoming.clone()],
+                    due: Instant::now() + self.window,
+                });
+                self.pending.added.notify_one();
+            }
+        }
+    }
+}
+
+impl Pending {
+    /// Wait for one or more windows to close, and take their batches
+    fn wait_due(&self) -> Vec<Batch> {
+        let mut batches = self.batches.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let now = Instant::now();
+            if batches.iter().any(|batch| batch.due <= now) {
+                let (due, waiting) = batches.drain(..).partition(|batch| batch.due <= now);
+                *batches = waiting;
+                return due;
+            }
+            batches = match batches.iter().map(|batch| batch.due).min() {
+                Some(next) => {
+                    self.added
+                        .wait_timeout(batches, next - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .added
+                    .wait(batches)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+/// One message standing for `messages`: their text a line each (after the
+/// title, if any), the highest priority among them, and the topic, token and
+/// source they share. `{count}` is how many there were.
+fn combine(mut messages: Vec<Incoming>) -> Incoming {
+    let count = messages.len();
+    if count == 1 {
+        let mut incoming = messages.remove(0);
+        incoming
+            .properties
+            .push(("count".to_string(), "1".to_string()));
+        return incoming;
+    }
+    let first = &messages[0];
+    let shared = |field: fn(&Incoming) -> Option<&String>| {
+        field(first)
+            .filter(|value| {
+                messages
+                    .iter()
+                    .all(|incoming| field(incoming) == Some(*value))
+            })
+            .cloned()
+    };
+    let source = shared(|incoming| Some(&incoming.source)).unwrap_or_else(|| "digest".to_string());
+    let topic = shared(|incoming| incoming.topic.as_ref());
+    let token = shared(|incoming| incoming.token.as_ref());
+    let text = messages
+        .iter()
+        .map(|incoming| match &incoming.title {
+            Some(title) => format!("{}: {}", title, incoming.text()),
+            None => incoming.text(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut incoming = Incoming::new(text, source);
+    incoming.priority = messages
+        .iter()
+        .map(|incoming| incoming.priority)
+        .max()
+        .unwrap_or_default();
+    incoming.title = Some(format!("{} messages", count));
+    incoming.format = Format::Plain;
+    incoming.topic = topic;
+    incoming.token = token;
+    incoming
+        .properties
+        .push(("count".to_string(), count.to_string()));
+    incoming
+}
+
+/// "N messages", for the log
+fn plural(count: usize) -> String {
+    format!("{} message{}", count, if count == 1 { "" } else { "s" })
+}
@@ -0,0 +1,1029 @@
+use crate::acl::Acl;
+use crate::auth::Tokens;
+use crate::exec::Reply;
+use crate::proxy::Proxy;
+use crate::shutdown::{self, Busy};
+use crate::{cron, metrics, tls, Error, Incoming, ReplySlot, Result};
+use hmac::{Hmac, KeyInit, Mac};
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+
+/// Announces a framed message: `LEN:<bytes>` on its own line, then exactly
+/// that many bytes. Senders use it for messages a line cannot hold.
+const FRAME_PREFIX: &str = "LEN:";
+/// Largest framed message a listener reads (by default) and a sender takes
+/// as a reply
+const MAX_FRAME: usize = 1024 * 1024;
+/// Follows the `OK` for a sender waiting on the command (`--reply`):
+/// `EXIT:<code>` on its own line, then the command's output as a frame.
+/// A listener that ran nothing for the message sends [`SKIPPED`] instead.
+const EXIT_PREFIX: &str = "EXIT:";
+const SKIPPED: &str = "SKIPPED";
+/// Connections a listener reads from at once; more are closed right away
+const MAX_CONNECTIONS: usize = 64;
+/// How long a listener gives a sender, by default, for the TLS handshake,
+/// its auth and each message in full, and for every write
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a listener keeps a connection open for the sender's next message
+/// (a session); an empty line from the sender keeps it open for this long again
+const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often a session sends that empty line while it has nothing to send
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How the sender proves it knows the auth token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Send the token as an `AUTH:<token>` line
+    #[default]
+    Plain,
+    /// The listener sends `CHALLENGE:<nonce>` and the sender answers
+    /// `HMAC:<hex>`, the HMAC-SHA256 of the nonce keyed with the token, so the
+    /// token never crosses the wire and an answer cannot be replayed
+    Challenge,
+}
+
+/// How much a listener reads from a sender, and how long it waits for it
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest message, as a line or a frame, in bytes
+    pub max_message: usize,
+    /// How long a sender may take over the TLS handshake, its auth and each
+    /// message, however it trickles them in
+    pub read_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message: MAX_FRAME,
+            read_timeout: CONNECTION_TIMEOUT,
+        }
+    }
+}
+
+/// How a listener treats every connection
+struct Policy<'a> {
+    auth: &'a Tokens,
+    mode: AuthMode,
+    with_reply: bool,
+    limits: Limits,
+}
+
+/// Listen on every one of `addrs`. IPv6 sockets take only IPv6 connections
+/// when an IPv4 address is among them, so `0.0.0.0:5555` and `[::]:5555` can
+/// go together (elsewhere, `[::]` usually takes IPv4 as well).
+pub fn bind(addrs: &[String]) -> Result<Vec<TcpListener>> {
+    let resolved = addrs
+        .iter()
+        .map(|addr| {
+            addr.to_socket_addrs()
+                .map(Iterator::collect::<Vec<_>>)
+                .map_err(|source| Error::Bind {
+                    addr: addr.clone(),
+                    source,
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let only_v6 = resolved.iter().flatten().any(SocketAddr::is_ipv4);
+    addrs
+        .iter()
+        .zip(resolved)
+        .map(|(addr, candidates)| {
+            // Like TcpListener::bind, the first of a name's addresses that works
+            let mut error = std::io::Error::new(ErrorKind::InvalidInput, "no address to bind");
+            for candidate in candidates {
+                match bind_one(candidate, only_v6) {
+                    Ok(listener) => return Ok(listener),
+                    Err(e) => error = e,
+                }
+            }
+            Err(Error::Bind {
+                addr: addr.clone(),
+                source: error,
+            })
+        })
+        .collect()
+}
+
+fn bind_one(addr: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// A message read by a connection's thread, and where to say it was handled
+/// (holding off a stop until the sender has its answer)
+type Received = (Incoming, mpsc::Sender<Busy>);
+
+/// Accept connections on every one of `listeners` forever, calling `handler`
+/// for every authenticated message, whichever it came in on.
+/// Peers `acl` refuses are disconnected before anything is read; with `tls`,
+/// every other connection must complete a TLS handshake first.
+///
+/// Each connection is read on a thread of its own, within `limits`, so a
+/// slow or oversized sender holds up nobody else. `handler`
+/// runs on the calling thread, one message at a time in the order they
+/// arrive, and the sender gets its `OK` once it returns. With `reply`, every
+/// message has an `Incoming::reply` slot, and what `handler` leaves there
+/// follows the `OK`. A sender may then send more messages on the same
+/// connection (see [`Session`]); it is closed after [`SESSION_TIMEOUT`]
+/// without one.
+#[allow(clippy::too_many_arguments)]
+pub fn serve(
+    listeners: Vec<TcpListener>,
+    auth: &Tokens,
+    mode: AuthMode,
+    tls: Option<Arc<ServerConfig>>,
+    acl: &Acl,
+    reply: bool,
+    limits: Limits,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let (messages, received) = mpsc::channel::<Received>();
+    let connections = AtomicUsize::new(0);
+    let policy = Policy {
+        auth,
+        mode,
+        with_reply: reply,
+        limits,
+    };
+    thread::scope(|scope| {
+        let (tls, connections, policy) = (&tls, &connections, &policy);
+        for listener in listeners {
+            let messages = messages.clone();
+            scope.spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Connection error: {}", e);
+                            continue;
+                        }
+                    };
+                    // IPv4 senders on a dual-stack IPv6 socket show up as ::ffff:a.b.c.d
+                    let addr = stream
+                        .peer_addr()
+                        .map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+                    let peer = addr.as_ref().map(ToString::to_string).unwrap_or_default();
+                    if !addr.map_or(acl.is_empty(), |addr| acl.permits(addr.ip())) {
+                        warn!("[{}] Not allowed, closing", peer);
+                        continue;
+                    }
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        warn!(
+                            "[{}] Over {} connections at once, closing",
+                            peer, MAX_CONNECTIONS
+                        );
+                        continue;
+                    }
+                    debug!("[{}] Connected", peer);
+                    let messages = messages.clone();
+                    scope.spawn(move || {
+                        connection(stream, peer, tls.as_ref(), policy, &messages);
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            });
+        }
+        drop(messages);
+        for (incoming, done) in received {
+            // Once stopping, senders get no OK for messages nobody acted on
+            if shutdown::stopping() {
+                continue;
+            }
+            let held = shutdown::hold();
+            handler(&incoming);
+            let _ = done.send(held);
+        }
+    });
+    Ok(())
+}
+
+/// Read one connection: time limits, then TLS if configured, then the messages
+fn connection(
+    stream: TcpStream,
+    peer: String,
+    tls: Option<&Arc<ServerConfig>>,
+    policy: &Policy,
+    messages: &mpsc::Sender<Received>,
+) {
+    let timeout = policy.limits.read_timeout;
+    // Same socket, kept to change the read timeout as a message comes in
+    let socket = match stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| stream.set_write_timeout(Some(timeout)))
+        .and_then(|()| stream.try_clone())
+    {
+        Ok(socket) => socket,
+        Err(e) => return error!("[{}] Connection error: {}", peer, e),
+    };
+    match tls {
+        Some(config) => match accept_tls(config, stream, timeout) {
+            Ok(stream) => handle(stream, &socket, &peer, policy, messages),
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    read_error(&peer, e)
+                }
+                _ => warn!("[{}] TLS handshake failed: {}", peer, e),
+            },
+        },
+        None => handle(stream, &socket, &peer, policy, messages),
+    }
+}
+
+/// Finish the handshake up front, within `timeout`, so a failed one is never
+/// answered in plaintext
+fn accept_tls(
+    config: &Arc<ServerConfig>,
+    mut stream: TcpStream,
+    timeout: Duration,
+) -> std::result::Result<StreamOwned<ServerConnection, TcpStream>, Box<dyn std::error::Error>> {
+    let deadline = Instant::now() + timeout;
+    let mut conn = ServerConnection::new(config.clone())?;
+    while conn.is_handshaking() {
+        stream.set_read_timeout(Some(left(deadline)?))?;
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(conn, stream))
+}
+
+/// Authenticate the sender and pass its messages on, answering `OK` (and,
+/// with `with_reply`, the command's result) once each has been handled
+fn handle(
+    stream: impl Read + Write,
+    socket: &TcpStream,
+    peer: &str,
+    policy: &Policy,
+    messages: &mpsc::Sender<Received>,
+) {
+    let mut input = Input {
+        reader: BufReader::new(stream),
+        socket,
+        peer,
+        limits: policy.limits,
+    };
+    let auth = policy.auth;
+
+    let mut token = None;
+    if !auth.is_empty() {
+        let deadline = Instant::now() + policy.limits.read_timeout;
+        let accepted = match policy.mode {
+            AuthMode::Plain => input.line(deadline).and_then(|line| {
+                line.strip_prefix("AUTH:")
+                    .and_then(|presented| auth.check(presented))
+            }),
+            AuthMode::Challenge => {
+                let nonce = match nonce() {
+                    Ok(nonce) => nonce,
+                    Err(e) => {
+                        error!("[{}] Cannot create a challenge: {}", peer, e);
+                        return;
+                    }
+                };
+                if let Err(e) = input.reply(format!("CHALLENGE:{}\n", nonce).as_bytes()) {
+                    warn!("[{}] Write error: {}", peer, e);
+                    return;
+                }
+                input
+                    .line(deadline)
+                    .and_then(|line| {
+                        line.strip_prefix("HMAC:")
+                            .and_then(|answer| hex::decode(answer).ok())
+                    })
+                    .and_then(|answer| {
+                        auth.find(|secret| mac(secret, &nonce).verify_slice(&answer).is_ok())
+                    })
+            }
+        };
+        match accepted {
+            Some(accepted) => token = accepted.label.clone(),
+            None => {
+                warn!("[{}] Auth failed", peer);
+                metrics::auth_failed();
+                let _ = input.reply(b"ERR:AUTH\n");
+                return;
+            }
+        }
+    }
+
+    for received in 0.. {
+        // After the first message, wait longer for the next one
+        let idle = if received == 0 {
+            policy.limits.read_timeout
+        } else {
+            SESSION_TIMEOUT
+        };
+        match input.wait(idle) {
+            Ok(true) => {}
+            Ok(false) => return,
+            // Senders hang up without a TLS close_notify once they're done
+            Err(e) if received > 0 && e.kind() == ErrorKind::UnexpectedEof => return,
+            Err(e)
+                if received > 0
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return warn!(
+                    "[{}] No message for {}, closing",
+                    peer,
+                    cron::format_duration(idle)
+                );
+            }
+            Err(e) => return read_error(peer, &e),
+        }
+        let deadline = Instant::now() + policy.limits.read_timeout;
+        let Some(line) = input.line(deadline) else {
+            return;
+        };
+        // An empty line only keeps the session open
+        if line.is_empty() {
+            continue;
+        }
+        let Some(message) = input.message(line, deadline) else {
+            return;
+        };
+        let mut incoming = Incoming::new(message, peer.to_string());
+        incoming.token = token.clone();
+        incoming.peer = socket.peer_addr().ok().map(|addr| addr.ip());
+        let slot = policy.with_reply.then(ReplySlot::default);
+        incoming.reply = slot.clone();
+        let (done, handled) = mpsc::channel();
+        if messages.send((incoming, done)).is_err() {
+            return;
+        }
+        let Ok(_held) = handled.recv() else {
+            return;
+        };
+        let response = match slot.map(|slot| slot.take()) {
+            Some(Some(result)) => format!(
+                "OK\n{}{}\n{}{}\n{}",
+                EXIT_PREFIX,
+                result.code,
+                FRAME_PREFIX,
+                result.output.len(),
+                result.output
+            ),
+            Some(None) => format!("OK\n{}\n", SKIPPED),
+            None => "OK\n".to_string(),
+        };
+        if let Err(e) = input.reply(response.as_bytes()) {
+            return warn!("[{}] Write error: {}", peer, e);
+        }
+    }
+}
+
+/// What a sender sends, read within the listener's [`Limits`]
+struct Input<'a, S> {
+    reader: BufReader<S>,
+    /// The connection's socket, to set each read's timeout on (even under TLS)
+    socket: &'a TcpStream,
+    peer: &'a str,
+    limits: Limits,
+}
+
+impl<S: Read + Write> Input<'_, S> {
+    /// Wait up to `idle` for the sender to send anything; false on EOF
+    fn wait(&mut self, idle: Duration) -> std::io::Result<bool> {
+        self.socket.set_read_timeout(Some(idle))?;
+        Ok(!self.reader.fill_buf()?.is_empty())
+    }
+
+    /// The next line, without its terminator, read by `deadline`. None on
+    /// EOF, error (logged) or a line over the size limit (refused).
+    fn line(&mut self, deadline: Instant) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            if let Err(e) = left(deadline).and_then(|left| self.socket.set_read_timeout(Some(left)))
+            {
+                read_error(self.peer, &e);
+                return None;
+            }
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    read_error(self.peer, &e);
+                    return None;
+                }
+            };
+            if available.is_empty() {
+                // EOF: a last line without a terminator still counts
+                if line.is_empty() {
+                    return None;
+                }
+                break;
+            }
+            let (used, ended) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (available.len(), false),
+            };
+            line.extend_from_slice(&available[..used]);
+            self.reader.consume(used);
+            if line.len() > self.limits.max_message + 2 {
+                self.too_big(None);
+                return None;
+            }
+            if ended {
+                break;
+            }
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.len() > self.limits.max_message {
+            self.too_big(None);
+            return None;
+        }
+        Some(line.to_string())
+    }
+
+    /// The message: `line` itself or, if it is a `LEN:` line, a frame of that
+    /// many bytes after it, read by `deadline`. None on EOF, error (logged)
+    /// or an oversized frame (refused).
+    fn message(&mut self, line: String, deadline: Instant) -> Option<String> {
+        let Some(len) = line
+            .strip_prefix(FRAME_PREFIX)
+            .and_then(|len| len.parse::<usize>().ok())
+        else {
+            return Some(line);
+        };
+        if len > self.limits.max_message {
+            self.too_big(Some(len));
+            return None;
+        }
+        let mut frame = vec![0; len];
+        let mut filled = 0;
+        while filled < len {
+            let read = left(deadline)
+                .and_then(|left| self.socket.set_read_timeout(Some(left)))
+                .and_then(|()| self.reader.read(&mut frame[filled..]));
+            match read {
+                Ok(0) => {
+                    read_error(self.peer, &ErrorKind::UnexpectedEof.into());
+                    return None;
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    read_error(self.peer, &e);
+                    return None;
+                }
+            }
+        }
+        Some(String::from_utf8_lossy(&frame).into_owned())
+    }
+
+    /// Refuse a message over the size limit (of `len` bytes, if announced)
+    fn too_big(&mut self, len: Option<usize>) {
+        let size = len
+            .map(|len| format!(" of {} bytes", len))
+            .unwrap_or_default();
+        warn!(
+            "[{}] Message{} is over the {} byte limit",
+            self.peer, size, self.limits.max_message
+        );
+        let _ = self.reply(b"ERR:SIZE\n");
+    }
+
+    fn reply(&mut self, response: &[u8]) -> std::io::Result<()> {
+        reply(self.reader.get_mut(), response)
+    }
+}
+
+/// Time left until `deadline`, or a timeout error once it has passed
+fn left(deadline: Instant) -> std::io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+fn read_error(peer: &str, e: &std::io::Error) {
+    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+        warn!("[{}] Timed out waiting for the sender, closing", peer);
+    } else {
+        warn!("[{}] Read error: {}", peer, e);
+    }
+}
+
+/// `message` as sent: a line, unless it has line breaks of its own, could
+/// be taken for a frame header or is empty (which would keep a session
+/// alive instead), in which case it is framed
+fn frame(message: &str) -> String {
+    if message.is_empty() || message.contains(['\n', '\r']) || message.starts_with(FRAME_PREFIX) {
+        format!("{}{}\n{}", FRAME_PREFIX, message.len(), message)
+    } else {
+        format!("{}\n", message)
+    }
+}
+
+fn reply(stream: &mut impl Write, response: &[u8]) -> std::io::Result<()> {
+    stream.write_all(response)?;
+    stream.flush()
+}
+
+/// 128 random bits, hex encoded
+fn nonce() -> std::result::Result<String, rustls::Error> {
+    let mut nonce = [0; 16];
+    tls::provider().secure_random.fill(&mut nonce)?;
+    Ok(hex::encode(nonce))
+}
+
+/// HMAC-SHA256 of the challenge as sent, keyed with the auth token
+fn mac(token: &str, nonce: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+/// Send one message, through `proxy` if given; with `tls`, the listener's
+/// certificate is verified against the host part of `addr`. With `timeout`,
+/// connecting and then waiting for the listener's `OK` may each take that
+/// long; without, they wait as long as the system lets them.
+pub fn send(
+    addr: &str,
+    message: &str,
+    auth: Option<&str>,
+    mode: AuthMode,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    connect(addr, message, auth, mode, tls, proxy, timeout, None).map(|_| ())
+}
+
+/// Like [`send`], to a listener running with `--reply`: wait up to
+/// `reply_timeout` for the command it runs and return how that went
+#[allow(clippy::too_many_arguments)]
+pub fn request(
+    addr: &str,
+    message: &str,
+    auth: Option<&str>,
+    mode: AuthMode,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+    reply_timeout: Duration,
+) -> Result<Reply> {
+    connect(
+        addr,
+        message,
+        auth,
+        mode,
+        tls,
+        proxy,
+        timeout,
+        Some(reply_timeout),
+    )
+    .map(Option::unwrap_or_default)
+}
+
+/// A connection to a listener kept open for many messages, so each one after
+/// the first costs no connect, TLS handshake or auth. It connects on the
+/// first message, and connects again once if the listener has since closed
+/// the connection (say, after [`SESSION_TIMEOUT`], or an older listener
+/// taking one message per connection). The message is only sent again when
+/// the listener can't have taken it: writing it failed, or the connection
+/// closed before any answer.
+pub struct Session {
+    addr: String,
+    auth: Option<String>,
+    mode: AuthMode,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<Proxy>,
+    timeout: Option<Duration>,
+    connection: Option<Connection>,
+}
+
+impl Session {
+    pub fn new(
+        addr: &str,
+        auth: Option<&str>,
+        mode: AuthMode,
+        tls: Option<Arc<ClientConfig>>,
+        proxy: Option<Proxy>,
+        timeout: Option<Duration>,
+    ) -> Session {
+        Session {
+            addr: addr.to_string(),
+            auth: auth.map(str::to_string),
+            mode,
+            tls,
+            proxy,
+            timeout,
+            connection: None,
+        }
+    }
+
+    /// Send one message and wait for its `OK`
+    pub fn send(&mut self, message: &str) -> Result<()> {
+        if let Some(mut connection) = self.connection.take() {
+            if write_request(&mut connection, message, None, self.mode).is_ok() {
+                if let Some(response) = read_response(&mut connection, None)? {
+                    answer(&mut connection, &response, None)?;
+                    self.connection = Some(connection);
+                    return Ok(());
+                }
+            }
+        }
+        let mut connection = open(
+            &self.addr,
+            self.tls.clone(),
+            self.proxy.as_ref(),
+            self.timeout,
+        )?;
+        exchange(
+            &mut connection,
+            message,
+            self.auth.as_deref(),
+            self.mode,
+            None,
+        )?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Keep the connection open while there is nothing to send; call it
+    /// every [`KEEPALIVE_INTERVAL`]. A connection that turns out to be closed
+    /// is dropped, to be made again for the next message.
+    pub fn keep_alive(&mut self) {
+        if let Some(connection) = &mut self.connection {
+            let writer = connection.reader.get_mut();
+            if writer
+                .write_all(b"\n")
+                .and_then(|()| writer.flush())
+                .is_err()
+            {
+                self.connection = None;
+            }
+        }
+    }
+}
+
+/// Connect and exchange the message; with `reply_timeout`, read the reply too
+#[allow(clippy::too_many_arguments)]
+fn connect(
+    addr: &str,
+    message: &str,
+    auth: Option<&str>,
+    mode: AuthMode,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+    reply_timeout: Option<Duration>,
+) -> Result<Option<Reply>> {
+    let mut connection = open(addr, tls, proxy, timeout)?;
+    exchange(&mut connection, message, auth, mode, reply_timeout)
+}
+
+/// Both ends of a sender's connection, with or without TLS
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// A sender's connection: the stream to talk over, and the same socket, kept
+/// to set timeouts even under TLS
+struct Connection {
+    reader: BufReader<Box<dyn Stream>>,
+    socket: TcpStream,
+    /// How long the listener may take to answer
+    timeout: Option<Duration>,
+}
+
+fn open(
+    addr: &str,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+) -> Result<Connection> {
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(addr, timeout)?,
+        None => dial(addr, timeout).map_err(|source| Error::Connect {
+            addr: addr.to_string(),
+            source,
+        })?,
+    };
+    stream.set_write_timeout(timeout)?;
+    let socket = stream.try_clone()?;
+    let stream: Box<dyn Stream> = match tls {
+        Some(config) => {
+            let conn = ClientConnection::new(config, tls::server_name(addr)?)
+                .map_err(|e| Error::Tls(e.to_string()))?;
+            Box::new(StreamOwned::new(conn, stream))
+        }
+        None => Box::new(stream),
+    };
+    Ok(Connection {
+        reader: BufReader::new(stream),
+        socket,
+        timeout,
+    })
+}
+
+/// A TCP connection to `addr`, giving each address it resolves to up to
+/// `timeout`
+pub(crate) fn dial(addr: &str, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addr);
+    };
+    let mut failed = None;
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failed = Some(e),
+        }
+    }
+    Err(failed.unwrap_or_else(|| {
+        std::io::Error::new(ErrorKind::InvalidInput, "no address to connect to")
+    }))
+}
+
+/// Send `message` over `connection`, authenticating first if `auth` is given
+fn exchange(
+    connection: &mut Connection,
+    message: &str,
+    auth: Option<&str>,
+    mode: AuthMode,
+    reply_timeout: Option<Duration>,
+) -> Result<Option<Reply>> {
+    write_request(connection, message, auth, mode)?;
+    let response = read_response(connection, reply_timeout)?;
+    answer(
+        connection,
+        response.as_deref().unwrap_or_default(),
+        reply_timeout,
+    )
+}
+
+/// Write `message`, after the auth line (answering the challenge first in
+/// challenge mode) if `auth` is given
+fn write_request(
+    connection: &mut Connection,
+    message: &str,
+    auth: Option<&str>,
+    mode: AuthMode,
+) -> Result<()> {
+    let Connection {
+        reader,
+        socket,
+        timeout,
+    } = connection;
+    // Everything goes out in one write, so a listener that hangs up after a
+    // failed auth still gets to answer instead of the sender seeing a broken pipe
+    let mut request = String::new();
+    match (auth, mode) {
+        (Some(auth_token), AuthMode::Challenge) => {
+            let previous = socket.read_timeout()?;
+            socket.set_read_timeout(Some(timeout.unwrap_or(CONNECTION_TIMEOUT)))?;
+            let mut challenge = String::new();
+            let read = reader.read_line(&mut challenge);
+            socket.set_read_timeout(previous)?;
+            match read {
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(Error::Rejected(
+                        "The listener sent no challenge; is it using --challenge?".into(),
+                    ))
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let Some(nonce) = challenge.trim().strip_prefix("CHALLENGE:") else {
+                return Err(rejected(challenge.trim()));
+            };
+            let answer = hex::encode(mac(auth_token, nonce).finalize().into_bytes());
+            request.push_str(&format!("HMAC:{}\n", answer));
+        }
+        (Some(auth_token), AuthMode::Plain) => request.push_str(&format!("AUTH:{}\n", auth_token)),
+        (None, _) => {}
+    }
+    request.push_str(&frame(message));
+    reader.get_mut().write_all(request.as_bytes())?;
+    reader.get_mut().flush()?;
+    Ok(())
+}
+
+/// The listener's answer line, or None if it closed the connection without
+/// sending any
+fn read_response(
+    connection: &mut Connection,
+    reply_timeout: Option<Duration>,
+) -> Result<Option<String>> {
+    let Connection {
+        reader,
+        socket,
+        timeout,
+    } = connection;
+    // The OK only comes once the listener has run the command
+    socket.set_read_timeout(reply_timeout.or(*timeout))?;
+    let mut response = String::new();
+    match reader.read_line(&mut response) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(response)),
+        Err(e)
+            if response.is_empty()
+                && matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::UnexpectedEof
+                ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => {
+            let timed_out = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+            Err(match (reply_timeout, *timeout) {
+                (Some(reply_timeout), _) if timed_out => no_reply(reply_timeout),
+                (None, Some(timeout)) if timed_out => Error::Io(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "No answer from the listener within {}",
+                        cron::format_duration(timeout)
+                    ),
+                )),
+                _ => e.into(),
+            })
+        }
+    }
+}
+
+/// What the listener's `response` line means, reading the reply after an
+/// `OK` if waiting for one
+fn answer(
+    connection: &mut Connection,
+    response: &str,
+    reply_timeout: Option<Duration>,
+) -> Result<Option<Reply>> {
+    let reader = &mut connection.reader;
+    match response.trim() {
+        "OK" => match reply_timeout {
+            Some(timeout) => read_reply(reader, timeout).map(Some),
+            None => Ok(None),
+        },
+        challenge if challenge.starts_with("CHALLENGE:") => {
+            Err(Error::Rejected("The listener requires --challenge".into()))
+        }
+        response => Err(rejected(response)),
+    }
+}
+
+/// What follows the listener's `OK`: the exit code and the output frame
+fn read_reply(reader: &mut impl BufRead, timeout: Duration) -> Result<Reply> {
+    let mut line = || -> Result<String> {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(_) => Ok(line.trim_end().to_string()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(no_reply(timeout))
+            }
+            Err(e) => Err(e.into()),
+        }
+    };
+    let status = line()?;
+    if status == SKIPPED {
+        return Err(Error::Rejected(
+            "The listener ran no command for the message".into(),
+        ));
+    }
+    let Some(code) = status
+        .strip_prefix(EXIT_PREFIX)
+        .and_then(|code| code.parse().ok())
+    else {
+        return Err(Error::Rejected(
+            "The listener sent no reply; is it using --reply?".into(),
+        ));
+    };
+    let len = line()?
+        .strip_prefix(FRAME_PREFIX)
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|len| *len <= MAX_FRAME)
+        .ok_or_else(|| Error::Rejected("The listener's reply is garbled".into()))?;
+    let mut output = vec![0; len];
+    reader.read_exact(&mut output)?;
+    Ok(Reply {
+        code,
+        output: String::from_utf8_lossy(&output).into_owned(),
+    })
+}
+
+fn no_reply(timeout: Duration) -> Error {
+    Error::Rejected(format!(
+        "No reply within {}; is the listener using --reply?",
+        cron::format_duration(timeout)
+    ))
+}
+
+/// The listener's refusal; a listener that drops the connection unanswered
+/// (e.g. for `--allow`/`--deny`) sends nothing
+fn rejected(response: &str) -> Error {
+    match response {
+        "" => Error::Rejected("The listener closed the connection".into()),
+        response => Error::Rejected(response.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A challenge-mode listener for `token` on a free port, and the
+    /// messages it accepts
+    fn listener(token: &'static str) -> (String, mpsc::Receiver<String>) {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let (accepted, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            let auth = Tokens::new(Some(token), None);
+            let handler = |incoming: &Incoming| {
+                let _ = accepted.send(incoming.message.clone());
+            };
+            serve(
+                vec![socket],
+                &auth,
+                AuthMode::Challenge,
+                None,
+                &Acl::default(),
+                false,
+                Limits::default(),
+                handler,
+            )
+        });
+        (addr, received)
+    }
+
+    fn send_with(addr: &str, token: &str, timeout: Duration) -> Result<()> {
+        send(
+            addr,
+            "hello",
+            Some(token),
+            AuthMode::Challenge,
+            None,
+            None,
+            Some(timeout),
+        )
+    }
+
+    #[test]
+    fn mac_is_hmac_sha256() {
+        let mac = mac("key", "The quick brown fox jumps over the lazy dog");
+        assert_eq!(
+            hex::encode(mac.finalize().into_bytes()),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn challenge_round_trip() {
+        let (addr, received) = listener("secret");
+        send_with(&addr, "secret", Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn challenge_rejects_wrong_token() {
+        let (addr, received) = listener("secret");
+        let sent = send_with(&addr, "wrong", Duration::from_secs(5));
+        assert!(
+            matches!(sent, Err(Error::Rejected(ref reason)) if reason.contains("AUTH")),
+            "{:?}",
+            sent
+        );
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn challenge_wait_follows_timeout() {
+        // Accepts, but never sends a challenge
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let _connection = socket.accept();
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let started = Instant::now();
+        let sent = send_with(&addr, "secret", Duration::from_millis(300));
+        assert!(
+            matches!(sent, Err(Error::Rejected(ref reason)) if reason.contains("no challenge")),
+            "{:?}",
+            sent
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,48 @@
+//! Durations in flags and the config file: a number with a unit (`250ms`,
+//! `30s`, `5m`, `1h`, `1d`), or a bare number of seconds
+
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+/// Parse `text` (for clap's `value_parser`)
+pub fn parse(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration (e.g. 30s, 5m, 1h)", text))?;
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.saturating_mul(60)),
+        "h" => Duration::from_secs(number.saturating_mul(3600)),
+        "d" => Duration::from_secs(number.saturating_mul(86400)),
+        unit => {
+            return Err(format!(
+                "Unknown unit '{}' in '{}' (use ms, s, m, h or d)",
+                unit, text
+            ))
+        }
+    };
+    Ok(duration)
+}
+
+/// Deserialize an optional duration from a string or a number of seconds
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Seconds(u64),
+        Text(String),
+    }
+    match Option::<Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Value::Seconds(secs)) => Ok(Some(Duration::from_secs(secs))),
+        Some(Value::Text(text)) => parse(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,178 @@
+//! SMTP email backend (send only), with the `email` feature
+
+#[cfg(feature = "email")]
+use crate::{tls, Error, Format, Priority, Result};
+#[cfg(feature = "email")]
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+#[cfg(feature = "email")]
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+#[cfg(feature = "email")]
+use lettre::transport::smtp::authentication::Credentials;
+#[cfg(feature = "email")]
+use lettre::transport::smtp::client::{Certificate, CertificateStore, Tls, TlsParameters};
+#[cfg(feature = "email")]
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+#[cfg(feature = "email")]
+use std::fs;
+#[cfg(feature = "email")]
+use std::io;
+#[cfg(feature = "email")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "email")]
+use std::time::Duration;
+
+/// Subject used when no title is given
+pub const DEFAULT_SUBJECT: &str = "crier";
+
+/// How the SMTP connection is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Plain connection upgraded with STARTTLS (port 587)
+    #[default]
+    Starttls,
+    /// TLS from the start (port 465)
+    Tls,
+    /// No encryption (port 25; local relays only)
+    None,
+}
+
+#[cfg(feature = "email")]
+impl Security {
+    fn default_port(self) -> u16 {
+        match self {
+            Security::Starttls => 587,
+            Security::Tls => 465,
+            Security::None => 25,
+        }
+    }
+}
+
+/// Where and as whom to send mail
+#[cfg(feature = "email")]
+#[derive(Debug, Clone)]
+pub struct Smtp {
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: Security,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// CA certificate(s) to trust instead of the system roots (PEM)
+    pub ca: Option<PathBuf>,
+}
+
+/// Mail `message` to every recipient; `title` becomes the subject and
+/// `image`, if given, an attachment. Markdown and HTML messages go out as
+/// HTML with a plain text alternative.
+#[cfg(feature = "email")]
+pub fn send(
+    smtp: &Smtp,
+    message: &str,
+    title: Option<&str>,
+    priority: Priority,
+    format: Format,
+    image: Option<&Path>,
+) -> Result<()> {
+    let mut builder = Message::builder()
+        .from(mailbox(&smtp.from)?)
+        .subject(title.unwrap_or(DEFAULT_SUBJECT));
+    for to in &smtp.to {
+        builder = builder.to(mailbox(to)?);
+    }
+    if priority >= Priority::High {
+        let name = HeaderName::new_from_ascii_str("X-Priority");
+        builder = builder.raw_header(HeaderValue::new(name, "1".into()));
+    }
+    let text = format.to_plain(message);
+    let email = match (format.to_html(message), image) {
+        (Some(html), Some(path)) => builder.multipart(
+            MultiPart::mixed()
+                .multipart(MultiPart::alternative_plain_html(text, html))
+                .singlepart(attachment(path)?),
+        ),
+        (Some(html), None) => builder.multipart(MultiPart::alternative_plain_html(text, html)),
+        (None, Some(path)) => builder.multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(text))
+                .singlepart(attachment(path)?),
+        ),
+        (None, None) => builder.header(ContentType::TEXT_PLAIN).body(text),
+    }
+    .map_err(|e| Error::Config(e.to_string()))?;
+
+    let port = smtp.port.unwrap_or(smtp.security.default_port());
+    let mut transport = SmtpTransport::builder_dangerous(&smtp.host)
+        .port(port)
+        .timeout(Some(Duration::from_secs(10)));
+    transport = match smtp.security {
+        Security::Starttls => transport.tls(Tls::Required(tls_parameters(smtp)?)),
+        Security::Tls => transport.tls(Tls::Wrapper(tls_parameters(smtp)?)),
+        Security::None => transport.tls(Tls::None),
+    };
+    if let Some(user) = &smtp.user {
+        let password = smtp.password.clone().unwrap_or_default();
+        transport = transport.credentials(Credentials::new(user.clone(), password));
+    }
+
+    transport.build().send(&email).map(|_| ()).map_err(|e| {
+        if e.is_response() || e.is_permanent() || e.is_transient() {
+            Error::Rejected(e.to_string())
+        } else if e.is_tls() {
+            Error::Tls(e.to_string())
+        } else {
+            Error::Connect {
+                addr: format!("{}:{}", smtp.host, port),
+                source: io::Error::other(e),
+            }
+        }
+    })
+}
+
+/// The file at `path` as an attachment, typed by its extension
+#[cfg(feature = "email")]
+fn attachment(path: &Path) -> Result<SinglePart> {
+    let data = fs::read(path)
+        .map_err(|e| Error::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    let mime = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    };
+    let content_type = ContentType::parse(mime).map_err(|e| Error::Config(e.to_string()))?;
+    Ok(Attachment::new(name).body(data, content_type))
+}
+
+#[cfg(feature = "email")]
+fn mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .map_err(|e| Error::Config(format!("Invalid email address '{}': {}", address, e)))
+}
+
+/// Verify the server against `ca` or the system roots, like the other senders
+#[cfg(feature = "email")]
+fn tls_parameters(smtp: &Smtp) -> Result<TlsParameters> {
+    let mut builder =
+        TlsParameters::builder(smtp.host.clone()).certificate_store(CertificateStore::None);
+    for cert in tls::root_certs(smtp.ca.as_deref())? {
+        if let Ok(cert) = Certificate::from_der(cert.to_vec()) {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    builder
+        .build_rustls()
+        .map_err(|e| Error::Tls(e.to_string()))
+}
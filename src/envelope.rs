@@ -0,0 +1,227 @@
+//! Metadata for transports that only carry text: `META:` + JSON holding the
+//! message and whatever is known about it (its title, priority and format,
+//! who sent it from where with `--with-meta`, and the id of the receipt the
+//! sender waits for with `--receipt`). Gotify, email and MQTT v5 have fields
+//! of their own for these and don't need it.
+
+use crate::{exec, relay, user, Format, Incoming, Priority};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Marks a message in an envelope
+const PREFIX: &str = "META:";
+
+/// MQTT v5 user property with the id of the receipt the sender waits for
+pub const RECEIPT_PROPERTY: &str = "receipt";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<Priority>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<Format>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<Meta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receipt: Option<String>,
+    message: String,
+}
+
+/// Where and when a message was sent, and by whom (`--with-meta`); a
+/// listener's `{host}`, `{user}` and `{sent_at}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Meta {
+    pub host: String,
+    pub user: String,
+    /// RFC 3339 (UTC)
+    pub sent_at: String,
+}
+
+impl Meta {
+    /// This machine, the user crier runs as, and now
+    pub fn now() -> Meta {
+        Meta {
+            host: hostname::get()
+                .map(|host| host.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            user: user::current_name().unwrap_or_default(),
+            sent_at: exec::timestamp(SystemTime::now()),
+        }
+    }
+
+    /// As properties, by name
+    pub fn properties(&self) -> Vec<(String, String)> {
+        vec![
+            ("host".to_string(), self.host.clone()),
+            ("user".to_string(), self.user.clone()),
+            ("sent_at".to_string(), self.sent_at.clone()),
+        ]
+    }
+}
+
+/// `message` with its metadata, or `message` itself when there is none worth
+/// sending (so listeners from before envelopes still understand it)
+pub fn seal(
+    message: &str,
+    title: Option<&str>,
+    priority: Option<Priority>,
+    format: Option<Format>,
+    meta: Option<Meta>,
+    receipt: Option<&str>,
+) -> String {
+    let title = title.filter(|title| !title.is_empty());
+    let priority = priority.filter(|priority| *priority != Priority::Normal);
+    let format = format.filter(|format| *format != Format::Plain);
+    if title.is_none()
+        && priority.is_none()
+        && format.is_none()
+        && meta.is_none()
+        && receipt.is_none()
+    {
+        return message.to_string();
+    }
+    let envelope = Envelope {
+        title: title.map(str::to_string),
+        priority,
+        format,
+        meta,
+        receipt: receipt.map(str::to_string),
+        message: message.to_string(),
+    };
+    match serde_json::to_string(&envelope) {
+        Ok(json) => format!("{}{}", PREFIX, json),
+        Err(_) => message.to_string(),
+    }
+}
+
+/// Wrap `handler` so that it sees messages out of their envelopes, with the
+/// metadata applied. Anything else (including text that merely starts like an
+/// envelope) passes through unchanged. A receipt asked for, in the envelope or
+/// an MQTT v5 `receipt` property, goes in `Incoming::receipt` for the relay
+/// listener to send once the message has been handled, if its id is one a
+/// sender could have made.
+pub fn opening(mut handler: impl FnMut(&Incoming)) -> impl FnMut(&Incoming) {
+    move |incoming: &Incoming| {
+        let envelope = incoming
+            .message
+            .strip_prefix(PREFIX)
+            .and_then(|json| serde_json::from_str::<Envelope>(json).ok());
+        if let Some(slot) = &incoming.receipt {
+            let id = envelope
+                .as_ref()
+                .and_then(|envelope| envelope.receipt.as_deref());
+            match id.or(incoming.property(RECEIPT_PROPERTY)) {
+                Some(id) if relay::is_receipt_id(id) => slot.set(id.to_string()),
+                Some(_) => warn!(
+                    "[{}] Invalid receipt id, not acknowledging",
+                    incoming.source
+                ),
+                None => {}
+            }
+        }
+        match envelope {
+            Some(envelope) => {
+                let mut properties = incoming.properties.clone();
+                properties.extend(envelope.meta.iter().flat_map(Meta::properties));
+                handler(&Incoming {
+                    message: envelope.message,
+                    title: envelope.title.or_else(|| incoming.title.clone()),
+                    priority: envelope.priority.unwrap_or(incoming.priority),
+                    format: envelope.format.unwrap_or(incoming.format),
+                    properties,
+                    ..incoming.clone()
+                })
+            }
+            None => handler(incoming),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReceiptSlot;
+
+    /// The message `handler` sees for `message`, and the receipt id left
+    fn open(message: &str, properties: &[(&str, &str)]) -> (Incoming, Option<String>) {
+        let mut incoming = Incoming::new(message.to_string(), "alerts".into());
+        incoming.properties = properties
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let slot = ReceiptSlot::default();
+        incoming.receipt = Some(slot.clone());
+        let mut opened = None;
+        opening(|incoming: &Incoming| opened = Some(incoming.clone()))(&incoming);
+        (opened.unwrap(), slot.take())
+    }
+
+    #[test]
+    fn plain_messages_pass_through() {
+        assert_eq!(
+            seal(
+                "hello",
+                None,
+                Some(Priority::Normal),
+                Some(Format::Plain),
+                None,
+                None
+            ),
+            "hello"
+        );
+        let (incoming, receipt) = open("META: not json", &[]);
+        assert_eq!(incoming.message, "META: not json");
+        assert_eq!(receipt, None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let id = "0123456789abcdef0123456789ABCDEF";
+        let sealed = seal(
+            "hello",
+            Some("Build"),
+            Some(Priority::High),
+            Some(Format::Markdown),
+            None,
+            Some(id),
+        );
+        let (incoming, receipt) = open(&sealed, &[]);
+        assert_eq!(incoming.message, "hello");
+        assert_eq!(incoming.title.as_deref(), Some("Build"));
+        assert_eq!(incoming.priority, Priority::High);
+        assert_eq!(incoming.format, Format::Markdown);
+        assert_eq!(receipt.as_deref(), Some(id));
+    }
+
+    #[test]
+    fn takes_the_receipt_property() {
+        let id = "0123456789abcdef0123456789abcdef";
+        assert_eq!(
+            open("hello", &[(RECEIPT_PROPERTY, id)]).1.as_deref(),
+            Some(id)
+        );
+    }
+
+    #[test]
+    fn ignores_receipt_ids_a_sender_wouldnt_make() {
+        for id in [
+            "#",
+            "+",
+            "a/b",
+            "../../x",
+            "0123456789abcdef0123456789abcde/",
+            "0123456789abcdef0123456789abcdeg",
+            "abc",
+            "",
+        ] {
+            let sealed = seal("hello", None, None, None, None, Some(id));
+            let (incoming, receipt) = open(&sealed, &[]);
+            assert_eq!(incoming.message, "hello");
+            assert_eq!(receipt, None, "{}", id);
+            assert_eq!(open("hello", &[(RECEIPT_PROPERTY, id)]).1, None, "{}", id);
+        }
+    }
+}
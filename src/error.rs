@@ -0,0 +1,93 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while resolving a target, sending or listening
+#[derive(Debug)]
+pub enum Error {
+    /// Preset name not present in the config file
+    PresetNotFound {
+        name: String,
+        path: PathBuf,
+        available: Vec<String>,
+    },
+    /// Neither an address nor a relay broker was given
+    NoTarget,
+    /// Relay mode without a topic
+    MissingTopic,
+    /// Could not bind the listening socket
+    Bind { addr: String, source: io::Error },
+    /// Could not reach the target listener
+    Connect { addr: String, source: io::Error },
+    /// Socket error after the connection was established
+    Io(io::Error),
+    /// MQTT client or broker error
+    Mqtt(String),
+    /// The listener answered with something other than OK
+    Rejected(String),
+    /// No answer from the broker in time
+    Timeout,
+    /// The transport cannot be used in this direction
+    Unsupported(&'static str),
+    /// Invalid or incomplete settings
+    Config(String),
+    /// TLS setup or handshake failure
+    Tls(String),
+}
+
+impl Error {
+    /// Whether trying again may help: the target or broker could not be
+    /// reached, or the connection failed on the way
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Connect { .. } | Error::Io(_) | Error::Mqtt(_) | Error::Timeout
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PresetNotFound {
+                name,
+                path,
+                available,
+            } => {
+                writeln!(f, "Preset '{}' not found in {:?}", name, path)?;
+                write!(f, "Available presets: {:?}", available)
+            }
+            Error::NoTarget => write!(f, "Provide address, --relay, or --preset"),
+            Error::MissingTopic => write!(f, "--topic is required with --relay"),
+            Error::Bind { addr, source } => write!(f, "Failed to bind {}: {}", addr, source),
+            Error::Connect { addr, source } => {
+                write!(f, "Failed to connect to {}: {}", addr, source)
+            }
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Mqtt(e) => write!(f, "{}", e),
+            Error::Rejected(response) => write!(f, "{}", response),
+            Error::Timeout => write!(f, "Timeout waiting for broker"),
+            Error::Unsupported(what) => write!(f, "{} is not supported", what),
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::Tls(msg) => write!(f, "TLS: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } | Error::Connect { source, .. } => Some(source),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
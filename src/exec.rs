@@ -0,0 +1,561 @@
+//! Running a listener's command for every message, without letting the
+//! message text anywhere near the shell's parser
+
+use crate::sandbox::Sandbox;
+use crate::user::User;
+use crate::{health, logging, metrics, shutdown, template, Incoming};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Environment variable holding the message (also given on stdin)
+pub const MESSAGE_VAR: &str = "CRIER_MESSAGE";
+/// Most of a command's output sent back to the sender; the rest is only logged
+pub const MAX_REPLY: usize = 64 * 1024;
+
+/// What a listener runs for a message
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Action {
+    /// Shell command template (see [`command`])
+    Shell(String),
+    /// Program and arguments, run without a shell (see [`arguments`])
+    Argv(Vec<String>),
+}
+
+impl Action {
+    /// Nothing to run (`''` or `[]` in the config)
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Action::Shell(cmd_template) => cmd_template.trim().is_empty(),
+            Action::Argv(argv) => argv.is_empty(),
+        }
+    }
+
+    /// Run it for `incoming` and wait for it, returning its exit code (see
+    /// [`Reply::code`])
+    pub fn run(&self, incoming: &Incoming, options: &Options) -> i32 {
+        match self {
+            Action::Shell(cmd_template) => run(cmd_template, incoming, options),
+            Action::Argv(argv) => run_argv(argv, incoming, options),
+        }
+    }
+
+    /// Run it like [`Action::run`], collecting what it prints for the sender
+    pub fn reply(&self, incoming: &Incoming, options: &Options) -> Reply {
+        let prepared = match self {
+            Action::Shell(cmd_template) => Some(shell(cmd_template, incoming, options)),
+            Action::Argv(argv) => program(argv, incoming, options),
+        };
+        match prepared {
+            Some((mut command, env)) => execute(&mut command, env, incoming, options, true),
+            None => Reply::failed(),
+        }
+    }
+
+    /// Run it once as the listener starts or stops (`on_start`, `on_stop`),
+    /// with no message and `{event}` set to `start` or `stop`. Unlike
+    /// [`Action::run`] it runs while stopping too.
+    pub fn hook(&self, event: &str, options: &Options) {
+        let mut incoming = Incoming::new(String::new(), "crier".into());
+        incoming.properties.push(("event".into(), event.into()));
+        let prepared = match self {
+            Action::Shell(cmd_template) => Some(shell(cmd_template, &incoming, options)),
+            Action::Argv(argv) => {
+                // Without the (empty) message appended
+                let (mut args, env) = arguments(argv, &incoming);
+                args.truncate(argv.len());
+                spawnable(args, env, options)
+            }
+        };
+        if let Some((mut command, env)) = prepared {
+            launch(&mut command, env, &incoming, options, false);
+        }
+    }
+}
+
+/// How a command run for a sender waiting on it (`--reply`) ended
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reply {
+    /// Exit code: 124 if it timed out, 127 if it could not be started and
+    /// 128 + the signal if one killed it, as with shells
+    pub code: i32,
+    /// What it printed on stdout, up to [`MAX_REPLY`] bytes
+    pub output: String,
+}
+
+impl Reply {
+    fn failed() -> Reply {
+        Reply {
+            code: 127,
+            output: String::new(),
+        }
+    }
+
+    fn exited(status: ExitStatus, output: String) -> Reply {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal: Option<i32> = None;
+        Reply {
+            code: status
+                .code()
+                .or(signal.map(|signal| 128 + signal))
+                .unwrap_or(1),
+            output,
+        }
+    }
+}
+
+/// Commands waiting to run on a thread of their own, one at a time in the
+/// order they were queued, so the listener keeps receiving meanwhile
+pub struct Queue {
+    waiting: SyncSender<(Action, Incoming)>,
+}
+
+impl Queue {
+    /// Start the thread; at most `capacity` commands wait behind the running one
+    pub fn new(capacity: usize, options: Options) -> Queue {
+        let (waiting, queued) = mpsc::sync_channel::<(Action, Incoming)>(capacity);
+        thread::spawn(move || {
+            for (action, incoming) in queued {
+                health::dequeued(1);
+                action.run(&incoming, &options);
+            }
+        });
+        Queue { waiting }
+    }
+
+    /// Queue `action` for `incoming`, or drop it (logged) if the queue is
+    /// full; whether it was queued
+    pub fn push(&self, action: &Action, incoming: &Incoming) -> bool {
+        // Counted first, as the thread may take it off the queue at once
+        health::queued(1);
+        let queued = self.waiting.try_send((action.clone(), incoming.clone()));
+        if queued.is_err() {
+            health::dequeued(1);
+        }
+        match queued {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("[{}] Command queue full, not running it", incoming.source);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!(
+                    "[{}] Command queue stopped, not running it",
+                    incoming.source
+                );
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Shell(cmd_template) => f.write_str(cmd_template),
+            Action::Argv(argv) => write!(f, "{:?} (no shell)", argv),
+        }
+    }
+}
+
+/// How a listener runs its command
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Paste values into a shell command instead of referring to variables (see [`command`])
+    pub substitute: bool,
+    pub sandbox: Option<Sandbox>,
+    /// Run the command as this user instead of crier's own
+    pub run_as: Option<User>,
+    /// Kill the command (and anything it started) if it runs longer
+    pub timeout: Option<Duration>,
+    /// Log the command instead of running it
+    pub dry_run: bool,
+}
+
+/// Values of the `{name}` placeholders for `incoming`, by name: its
+/// properties, then what crier knows about it. The last of several values for
+/// a name wins.
+fn values(incoming: &Incoming) -> Vec<(&str, String)> {
+    let mut values: Vec<(&str, String)> = incoming
+        .properties
+        .iter()
+        .filter(|(name, _)| template::is_name(name))
+        .map(|(name, value)| (name.as_str(), value.clone()))
+        .collect();
+    // These never come from a property, which any sender could set
+    values.extend([
+        ("title", incoming.title.clone().unwrap_or_default()),
+        ("priority", incoming.priority.to_string()),
+        ("format", incoming.format.to_string()),
+        ("text", incoming.text()),
+        ("sender", incoming.sender()),
+        ("topic", incoming.topic.clone().unwrap_or_default()),
+        ("timestamp", timestamp(SystemTime::now())),
+        ("token", incoming.token.clone().unwrap_or_default()),
+        (
+            "file",
+            incoming
+                .file
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+        ),
+    ]);
+    values
+}
+
+fn value<'a>(values: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+    values
+        .iter()
+        .rev()
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// `time` in RFC 3339 (UTC, whole seconds), e.g. `2024-05-01T12:00:00Z`
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+/// Environment for the command: every placeholder value as `CRIER_NAME`
+fn environment(values: &[(&str, String)], incoming: &Incoming) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = values
+        .iter()
+        .map(|(name, value)| (var(name), value.clone()))
+        .collect();
+    env.push((MESSAGE_VAR.to_string(), incoming.message.clone()));
+    env
+}
+
+/// The shell command for `incoming` and the environment to run it with.
+///
+/// `{}` stands for `$CRIER_MESSAGE`, `{token}` for `$CRIER_TOKEN` and any
+/// other `{name}` for the `$CRIER_NAME` property, which the shell expands
+/// without parsing (so keep them in double quotes). With `substitute` the
+/// values are pasted into the command instead, which lets a sender run
+/// anything it likes: only for trusted senders.
+pub fn command(
+    cmd_template: &str,
+    incoming: &Incoming,
+    substitute: bool,
+) -> (String, Vec<(String, String)>) {
+    let values = values(incoming);
+    let cmd = if substitute {
+        template::render(cmd_template, |name| {
+            value(&values, name).map(str::to_string)
+        })
+        .replace("{}", &incoming.message)
+    } else {
+        template::render(cmd_template, |name| {
+            value(&values, name).map(|_| reference(&var(name)))
+        })
+        .replace("{}", &reference(MESSAGE_VAR))
+    };
+
+    (cmd, environment(&values, incoming))
+}
+
+/// Arguments for running `argv` directly for `incoming`: placeholders are
+/// replaced in every argument (there is no shell to interpret them), and the
+/// message is appended as the last one unless an argument has `{}`
+pub fn arguments(argv: &[String], incoming: &Incoming) -> (Vec<String>, Vec<(String, String)>) {
+    let values = values(incoming);
+    let mut args: Vec<String> = argv
+        .iter()
+        .map(|arg| {
+            template::render(arg, |name| value(&values, name).map(str::to_string))
+                .replace("{}", &incoming.message)
+        })
+        .collect();
+    if !argv.iter().any(|arg| arg.contains("{}")) {
+        args.push(incoming.message.clone());
+    }
+    (args, environment(&values, incoming))
+}
+
+/// Run `cmd_template` for `incoming` (see [`command`]) and wait for it,
+/// returning its exit code
+pub fn run(cmd_template: &str, incoming: &Incoming, options: &Options) -> i32 {
+    let (mut shell, env) = shell(cmd_template, incoming, options);
+    execute(&mut shell, env, incoming, options, false).code
+}
+
+/// Run `argv` for `incoming` without a shell (see [`arguments`]) and wait
+/// for it, returning its exit code
+pub fn run_argv(argv: &[String], incoming: &Incoming, options: &Options) -> i32 {
+    match program(argv, incoming, options) {
+        Some((mut command, env)) => execute(&mut command, env, incoming, options, false).code,
+        None => Reply::failed().code,
+    }
+}
+
+/// The shell running `cmd_template` for `incoming`, and its environment
+fn shell(
+    cmd_template: &str,
+    incoming: &Incoming,
+    options: &Options,
+) -> (Command, Vec<(String, String)>) {
+    let (cmd, env) = command(cmd_template, incoming, options.substitute);
+    info!("{}: {}", running(options), cmd);
+
+    // Use appropriate shell based on OS
+    #[cfg(target_os = "windows")]
+    let shell = {
+        // Delayed expansion (!VAR!) happens after cmd has parsed the line
+        let mut shell = Command::new("cmd");
+        shell.arg("/V:ON").arg("/C").arg(&cmd);
+        shell
+    };
+
+    #[cfg(not(target_os = "windows"))]
+    let shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(&cmd);
+        shell
+    };
+
+    (shell, env)
+}
+
+/// The program in `argv` with its arguments for `incoming`, and its
+/// environment; None if `argv` is empty
+fn program(
+    argv: &[String],
+    incoming: &Incoming,
+    options: &Options,
+) -> Option<(Command, Vec<(String, String)>)> {
+    let (args, env) = arguments(argv, incoming);
+    spawnable(args, env, options)
+}
+
+fn spawnable(
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    options: &Options,
+) -> Option<(Command, Vec<(String, String)>)> {
+    info!("{}: {:?}", running(options), args);
+    let (program, args) = args.split_first()?;
+    let mut command = Command::new(program);
+    command.args(args);
+    Some((command, env))
+}
+
+/// Start `command` with `env` and the message on stdin, and wait for it (at
+/// most `options.timeout`). With `capture`, its stdout is collected for the
+/// reply as well as logged.
+fn execute(
+    command: &mut Command,
+    env: Vec<(String, String)>,
+    incoming: &Incoming,
+    options: &Options,
+    capture: bool,
+) -> Reply {
+    let Some(_busy) = shutdown::busy() else {
+        warn!("Stopping, not running it");
+        return Reply::failed();
+    };
+    let started = Instant::now();
+    let reply = launch(command, env, incoming, options, capture);
+    metrics::command_ran(started.elapsed(), reply.code);
+    reply
+}
+
+/// How the log introduces a command
+fn running(options: &Options) -> &'static str {
+    if options.dry_run {
+        "Would run"
+    } else {
+        "Running"
+    }
+}
+
+/// [`execute`], whether or not crier is stopping. A dry run stops short of
+/// starting it and counts as a success.
+fn launch(
+    command: &mut Command,
+    env: Vec<(String, String)>,
+    incoming: &Incoming,
+    options: &Options,
+    capture: bool,
+) -> Reply {
+    if options.dry_run {
+        return Reply::default();
+    }
+    if let Some(sandbox) = &options.sandbox {
+        if let Err(e) = sandbox.apply(command) {
+            error!("Failed to run: {}", e);
+            return Reply::failed();
+        }
+    }
+    if let Some(user) = &options.run_as {
+        user.apply(command);
+    }
+    // In a process group of its own, so a Ctrl-C meant for crier lets it
+    // finish, and a timeout kills whatever it started too
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    // JSON logs take what it prints as events, a line each
+    let logged = logging::json();
+    if capture || logged {
+        command.stdout(Stdio::piped());
+    }
+    if logged {
+        command.stderr(Stdio::piped());
+    }
+    let mut child = match command.envs(env).stdin(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to run: {}", e);
+            return Reply::failed();
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // From a thread of its own, so a command that never reads stdin can't
+        // block the listener once the pipe is full. Commands that don't read
+        // it close it early; that's fine.
+        let message = incoming.message.clone();
+        thread::spawn(move || {
+            let _ = writeln!(stdin, "{}", message);
+        });
+    }
+    let output = child
+        .stdout
+        .take()
+        .map(|stdout| thread::spawn(move || collect(stdout, logged)));
+    let errors = child
+        .stderr
+        .take()
+        .map(|stderr| thread::spawn(move || log_lines(stderr)));
+
+    let status = match options.timeout {
+        Some(timeout) => wait_timeout(&mut child, timeout),
+        None => child.wait().map(Some),
+    };
+    let output = output
+        .and_then(|output| output.join().ok())
+        .unwrap_or_default();
+    if let Some(errors) = errors {
+        let _ = errors.join();
+    }
+    match status {
+        Ok(Some(s)) => {
+            if !s.success() {
+                warn!("Command failed: {}", s);
+            }
+            Reply::exited(s, output)
+        }
+        Ok(None) => {
+            warn!(
+                "Command timed out after {}, killed",
+                crate::cron::format_duration(options.timeout.unwrap_or_default())
+            );
+            Reply { code: 124, output }
+        }
+        Err(e) => {
+            error!("Failed to run: {}", e);
+            Reply::failed()
+        }
+    }
+}
+
+/// Copy everything the command prints to our stdout (or log it, a line at a
+/// time, with `logged`), keeping the first [`MAX_REPLY`] bytes of it
+fn collect(mut stdout: impl Read, logged: bool) -> String {
+    let mut kept = Vec::new();
+    if logged {
+        for line in BufReader::new(stdout)
+            .split(b'\n')
+            .map_while(std::io::Result::ok)
+        {
+            info!(stream = "stdout", "{}", String::from_utf8_lossy(&line));
+            let room = MAX_REPLY - kept.len();
+            kept.extend(line.iter().chain(b"\n").take(room));
+        }
+        return String::from_utf8_lossy(&kept).into_owned();
+    }
+    let mut buffer = [0; 8192];
+    while let Ok(n @ 1..) = stdout.read(&mut buffer) {
+        let mut out = std::io::stdout().lock();
+        let _ = out.write_all(&buffer[..n]).and_then(|()| out.flush());
+        let room = MAX_REPLY - kept.len();
+        kept.extend_from_slice(&buffer[..n.min(room)]);
+    }
+    String::from_utf8_lossy(&kept).into_owned()
+}
+
+/// Log each line the command prints to stderr as a warning
+fn log_lines(stderr: impl Read) {
+    for line in BufReader::new(stderr)
+        .split(b'\n')
+        .map_while(std::io::Result::ok)
+    {
+        warn!(stream = "stderr", "{}", String::from_utf8_lossy(&line));
+    }
+}
+
+/// Wait for `child` for up to `timeout`; after that, kill it (and its
+/// process group on Unix) and return None
+fn wait_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(20)));
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    child.kill()?;
+    child.wait()?;
+    Ok(None)
+}
+
+fn var(name: &str) -> String {
+    format!("CRIER_{}", name.to_ascii_uppercase())
+}
+
+/// How the shell refers to environment variable `var`
+fn reference(var: &str) -> String {
+    if cfg!(target_os = "windows") {
+        format!("!{}!", var)
+    } else {
+        format!("${{{}}}", var)
+    }
+}
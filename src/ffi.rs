@@ -0,0 +1,192 @@
+//! C API, built into the `cdylib` with `--features ffi`.
+//!
+//! The generated header lives in `include/crier.h`. All strings are
+//! NUL-terminated UTF-8; any pointer in [`CrierOptions`] may be NULL.
+
+use crate::{config, Error, Incoming, Preset};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::PathBuf;
+use std::ptr;
+
+/// Result of every `crier_*` call
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrierStatus {
+    Ok = 0,
+    /// A required pointer was NULL or a string was not valid UTF-8
+    InvalidArgument = 1,
+    /// Preset missing, or no usable target/topic
+    Config = 2,
+    /// Bind, connect or socket error
+    Io = 3,
+    /// MQTT client or broker error
+    Mqtt = 4,
+    /// The listener rejected the message (e.g. wrong auth)
+    Rejected = 5,
+    /// The broker did not answer in time
+    Timeout = 6,
+    /// TLS setup or handshake failure
+    Tls = 7,
+}
+
+/// Connection settings, mirroring the CLI flags
+#[repr(C)]
+pub struct CrierOptions {
+    /// Preset name to load from the config file
+    pub preset: *const c_char,
+    /// Config file path (default: ~/.config/crier.yml)
+    pub config: *const c_char,
+    /// MQTT broker; selects relay mode
+    pub relay: *const c_char,
+    /// MQTT broker port, 0 for the default (1883)
+    pub port: u16,
+    /// Topic for relay mode
+    pub topic: *const c_char,
+    /// Authentication token
+    pub auth: *const c_char,
+}
+
+/// Called for every received message; the strings are only valid during the call
+pub type CrierCallback =
+    Option<extern "C" fn(message: *const c_char, source: *const c_char, user_data: *mut c_void)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Send `message` to `target` (a direct-mode address, may be NULL when a
+/// preset or relay is given).
+///
+/// # Safety
+///
+/// `target`, `message` and every string in `options` must be NULL or valid
+/// NUL-terminated strings; `options` must be NULL or point to a valid struct.
+#[no_mangle]
+pub unsafe extern "C" fn crier_send(
+    target: *const c_char,
+    message: *const c_char,
+    options: *const CrierOptions,
+) -> CrierStatus {
+    let message = match str_arg(message) {
+        Ok(Some(m)) => m,
+        _ => return fail_invalid("message must be a valid string"),
+    };
+    let preset = match resolve(target, options) {
+        Ok(p) => p,
+        Err(status) => return status,
+    };
+    status(crate::send(&preset, message))
+}
+
+/// Listen on `target` (a direct-mode bind address, may be NULL when a preset
+/// or relay is given), calling `callback` for every message. Blocks until
+/// the listener fails.
+///
+/// # Safety
+///
+/// Same requirements as [`crier_send`]; `user_data` is passed through to
+/// `callback` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn crier_listen(
+    target: *const c_char,
+    options: *const CrierOptions,
+    callback: CrierCallback,
+    user_data: *mut c_void,
+) -> CrierStatus {
+    let Some(callback) = callback else {
+        return fail_invalid("callback must not be NULL");
+    };
+    let preset = match resolve(target, options) {
+        Ok(p) => p,
+        Err(status) => return status,
+    };
+    status(crate::listen(&preset, |incoming: &Incoming| {
+        // Interior NULs cannot cross the C boundary; drop them
+        let message = CString::new(incoming.message.replace('\0', "")).unwrap_or_default();
+        let source = CString::new(incoming.source.replace('\0', "")).unwrap_or_default();
+        callback(message.as_ptr(), source.as_ptr(), user_data);
+    }))
+}
+
+/// Message describing the last failed call on this thread, or NULL.
+/// Valid until the next `crier_*` call on the same thread.
+#[no_mangle]
+pub extern "C" fn crier_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char) -> Result<Option<&'a str>, ()> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(ptr).to_str().map(Some).map_err(|_| ())
+}
+
+unsafe fn string_arg(ptr: *const c_char) -> Result<Option<String>, CrierStatus> {
+    str_arg(ptr)
+        .map(|s| s.map(str::to_string))
+        .map_err(|_| fail_invalid("strings must be valid UTF-8"))
+}
+
+/// Build a preset from the C arguments, with the same precedence as the CLI
+unsafe fn resolve(
+    target: *const c_char,
+    options: *const CrierOptions,
+) -> Result<Preset, CrierStatus> {
+    LAST_ERROR.with(|e| e.borrow_mut().take());
+
+    let addr = string_arg(target)?;
+    let Some(opts) = options.as_ref() else {
+        return Ok(Preset {
+            addr,
+            ..Preset::default()
+        });
+    };
+
+    let cli = Preset {
+        addr,
+        relay: string_arg(opts.relay)?,
+        port: (opts.port != 0).then_some(opts.port),
+        topic: string_arg(opts.topic)?,
+        auth: string_arg(opts.auth)?,
+        ..Preset::default()
+    };
+    let config_path = string_arg(opts.config)?.map(PathBuf::from);
+    let preset = string_arg(opts.preset)?;
+    config::resolve(cli, preset.as_deref(), config_path.as_ref()).map_err(fail)
+}
+
+fn status(result: crate::Result<()>) -> CrierStatus {
+    match result {
+        Ok(()) => CrierStatus::Ok,
+        Err(e) => fail(e),
+    }
+}
+
+fn fail(e: Error) -> CrierStatus {
+    let status = match e {
+        Error::PresetNotFound { .. }
+        | Error::NoTarget
+        | Error::MissingTopic
+        | Error::Unsupported(_)
+        | Error::Config(_) => CrierStatus::Config,
+        Error::Bind { .. } | Error::Connect { .. } | Error::Io(_) => CrierStatus::Io,
+        Error::Mqtt(_) => CrierStatus::Mqtt,
+        Error::Rejected(_) => CrierStatus::Rejected,
+        Error::Timeout => CrierStatus::Timeout,
+        Error::Tls(_) => CrierStatus::Tls,
+    };
+    set_last_error(&e.to_string());
+    status
+}
+
+fn fail_invalid(msg: &str) -> CrierStatus {
+    set_last_error(msg);
+    CrierStatus::InvalidArgument
+}
+
+fn set_last_error(msg: &str) {
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
@@ -0,0 +1,288 @@
+//! Small files sent as a series of messages (`FILE:` + JSON chunk), so they
+//! go over every text transport and through `--sign`/`--encrypt` like any
+//! other message, and put back together by the listener in `--save-dir`
+
+use crate::{tls, Error, Incoming, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Marks a file chunk: `FILE:` + JSON
+const PREFIX: &str = "FILE:";
+/// Bytes of the file per message; well under MQTT brokers' usual packet
+/// limits once base64 encoded, encrypted and signed
+const CHUNK_SIZE: usize = 48 * 1024;
+/// Largest file sent or accepted without `max_file_size`
+pub const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024;
+/// Files the listener puts together at once
+const MAX_PENDING: usize = 16;
+/// A file missing chunks for this long is given up on
+const STALE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Chunk {
+    /// Tells one file's chunks from another's
+    id: String,
+    name: String,
+    /// Size of the whole file
+    size: u64,
+    index: u32,
+    count: u32,
+    /// base64 of this part of the file
+    data: String,
+    /// Text sent along with the file, in the last chunk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// An image for the listener to show with the message (`send --image`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    image: bool,
+}
+
+/// The messages that carry the file at `path`, and `message` with it;
+/// `image` marks it as one to show in notifications
+pub fn chunks(
+    path: &Path,
+    message: Option<&str>,
+    max_size: u64,
+    image: bool,
+) -> Result<Vec<String>> {
+    let data = fs::read(path)
+        .map_err(|e| Error::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+    if data.len() as u64 > max_size {
+        return Err(Error::Config(format!(
+            "{} is {} bytes, over the {} byte limit (max_file_size)",
+            path.display(),
+            data.len(),
+            max_size
+        )));
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::Config(format!("{} is not a file", path.display())))?;
+    let id = id()?;
+    let parts: Vec<&[u8]> = if data.is_empty() {
+        vec![&[]]
+    } else {
+        data.chunks(CHUNK_SIZE).collect()
+    };
+    let count = parts.len() as u32;
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| {
+            let chunk = Chunk {
+                id: id.clone(),
+                name: name.clone(),
+                size: data.len() as u64,
+                index: index as u32,
+                count,
+                data: base64::engine::general_purpose::STANDARD.encode(part),
+                message: message
+                    .filter(|_| index as u32 + 1 == count)
+                    .map(str::to_string),
+                image,
+            };
+            let json = serde_json::to_string(&chunk).map_err(|e| Error::Config(e.to_string()))?;
+            Ok(format!("{}{}", PREFIX, json))
+        })
+        .collect()
+}
+
+/// 64 random bits, hex encoded
+fn id() -> Result<String> {
+    let mut id = [0; 8];
+    tls::provider()
+        .secure_random
+        .fill(&mut id)
+        .map_err(|_| Error::Config("No randomness available for a file id".into()))?;
+    Ok(hex::encode(id))
+}
+
+/// Where the listener saves files, and the largest it accepts
+#[derive(Debug, Clone)]
+pub struct Save {
+    pub dir: PathBuf,
+    pub max_size: u64,
+}
+
+/// A file whose chunks are still arriving
+struct Pending {
+    name: String,
+    size: u64,
+    parts: Vec<Option<Vec<u8>>>,
+    received: u64,
+    message: Option<String>,
+    image: bool,
+    last: Instant,
+}
+
+/// Wrap `handler` so that file chunks are put back together and, with
+/// `save`, each complete file is saved and passed on as one message (its
+/// text, or the file name) with `Incoming::file` (and `image`) set. Other messages pass
+/// straight through.
+pub fn receiving(save: Option<Save>, mut handler: impl FnMut(&Incoming)) -> impl FnMut(&Incoming) {
+    let mut pending: HashMap<(String, String), Pending> = HashMap::new();
+    move |incoming: &Incoming| {
+        let Some(json) = incoming.message.strip_prefix(PREFIX) else {
+            return handler(incoming);
+        };
+        let Ok(chunk) = serde_json::from_str::<Chunk>(json) else {
+            return warn!("[{}] Malformed file chunk, ignoring", incoming.source);
+        };
+        let Some(save) = &save else {
+            if chunk.index == 0 {
+                warn!(
+                    "[{}] Ignoring file {} (no --save-dir)",
+                    incoming.source, chunk.name
+                );
+            }
+            return;
+        };
+        if chunk.size > save.max_size {
+            if chunk.index == 0 {
+                warn!(
+                    "[{}] Ignoring file {}: {} bytes, over the {} byte limit",
+                    incoming.source, chunk.name, chunk.size, save.max_size
+                );
+            }
+            return;
+        }
+        let expected = chunk.size.div_ceil(CHUNK_SIZE as u64).max(1);
+        let Some(name) = safe_name(&chunk.name)
+            .filter(|_| chunk.count as u64 == expected && chunk.index < chunk.count)
+        else {
+            return warn!("[{}] Malformed file chunk, ignoring", incoming.source);
+        };
+        let Ok(data) = base64::engine::general_purpose::STANDARD.decode(&chunk.data) else {
+            return warn!("[{}] Malformed file chunk, ignoring", incoming.source);
+        };
+
+        let now = Instant::now();
+        pending.retain(|_, file| now.duration_since(file.last) < STALE);
+        let key = (incoming.sender(), chunk.id);
+        if !pending.contains_key(&key) && pending.len() >= MAX_PENDING {
+            return warn!(
+                "[{}] Too many files arriving at once, ignoring {}",
+                incoming.source, name
+            );
+        }
+        let file = pending.entry(key.clone()).or_insert_with(|| Pending {
+            name,
+            size: chunk.size,
+            parts: vec![None; chunk.count as usize],
+            received: 0,
+            message: None,
+            image: chunk.image,
+            last: now,
+        });
+        let index = chunk.index as usize;
+        if file.size != chunk.size
+            || file.parts.len() != chunk.count as usize
+            || file.parts[index].is_some()
+        {
+            pending.remove(&key);
+            return warn!(
+                "[{}] Inconsistent file chunks, ignoring the file",
+                incoming.source
+            );
+        }
+        file.received += data.len() as u64;
+        if file.received > file.size {
+            pending.remove(&key);
+            return warn!(
+                "[{}] File larger than announced, ignoring it",
+                incoming.source
+            );
+        }
+        file.parts[index] = Some(data);
+        file.last = now;
+        if chunk.message.is_some() {
+            file.message = chunk.message;
+        }
+        if file.parts.iter().any(Option::is_none) {
+            return;
+        }
+
+        let Some(file) = pending.remove(&key) else {
+            return;
+        };
+        if file.received != file.size {
+            return warn!(
+                "[{}] File smaller than announced, ignoring it",
+                incoming.source
+            );
+        }
+        let data: Vec<u8> = file.parts.into_iter().flatten().flatten().collect();
+        match write_new(&save.dir, &file.name, &data) {
+            Ok(path) => {
+                info!(
+                    "[{}] Saved {} ({} bytes)",
+                    incoming.source,
+                    path.display(),
+                    data.len()
+                );
+                handler(&Incoming {
+                    message: file.message.unwrap_or_else(|| file.name.clone()),
+                    image: file.image.then(|| path.clone()),
+                    file: Some(path),
+                    ..incoming.clone()
+                });
+            }
+            Err(e) => error!("[{}] Cannot save {}: {}", incoming.source, file.name, e),
+        }
+    }
+}
+
+/// The last component of a sender's file name, without anything a file
+/// system would treat specially; None if nothing is left
+fn safe_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect();
+    let name = name.trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+/// Write `data` to `name` in `dir`, or to `name-1`, `name-2`… (before the
+/// extension) if it exists, never replacing a file
+fn write_new(dir: &Path, name: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 0..1000 {
+        let candidate = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{}-{}{}", stem, n, extension)),
+        };
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::AlreadyExists,
+        "too many files with that name",
+    ))
+}
@@ -0,0 +1,42 @@
+//! Listener message filters: patterns a message must match for the listener
+//! to act on it (`--filter`), and patterns that silence it entirely (`--drop`)
+
+use crate::{Error, Result};
+use regex::Regex;
+
+/// Regexes matched against a message's text (without formatting)
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    keep: Vec<Regex>,
+    drop: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(keep: &[String], drop: &[String]) -> Result<Filter> {
+        Ok(Filter {
+            keep: compile(keep, "--filter")?,
+            drop: compile(drop, "--drop")?,
+        })
+    }
+
+    /// Whether the listener should act on `text`: it matches a `--filter`
+    /// pattern, or there are none
+    pub fn keeps(&self, text: &str) -> bool {
+        self.keep.is_empty() || self.keep.iter().any(|re| re.is_match(text))
+    }
+
+    /// Whether `text` matches a `--drop` pattern and should not even be logged
+    pub fn drops(&self, text: &str) -> bool {
+        self.drop.iter().any(|re| re.is_match(text))
+    }
+}
+
+fn compile(patterns: &[String], flag: &str) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|e| Error::Config(format!("Invalid {} '{}': {}", flag, pattern, e)))
+        })
+        .collect()
+}
@@ -0,0 +1,52 @@
+//! Passing messages a listener receives on to other destinations (a preset's
+//! `forward` rules): another crier listener, a broker topic, Gotify, ...
+
+use crate::{relay, Incoming, Preset, Priority, Result};
+use regex::Regex;
+
+/// A `forward` rule, ready to use
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// The destination's preset name, or its address
+    pub name: String,
+    /// Where to send it
+    pub preset: Preset,
+    /// Only messages published to a matching topic (wildcards allowed)
+    pub topic: Option<String>,
+    /// Only messages at this priority or above
+    pub min_priority: Priority,
+    /// Only messages whose text matches
+    pub filter: Option<Regex>,
+    /// Don't run the listener's command for messages this rule forwards
+    pub instead: bool,
+}
+
+impl Rule {
+    /// Whether the rule forwards `incoming`
+    pub fn matches(&self, incoming: &Incoming) -> bool {
+        if incoming.priority < self.min_priority {
+            return false;
+        }
+        if let Some(filter) = &self.topic {
+            if !incoming
+                .topic
+                .as_deref()
+                .is_some_and(|topic| relay::matches(filter, topic))
+            {
+                return false;
+            }
+        }
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(&incoming.text()))
+    }
+
+    /// Send `incoming` on, keeping its title, priority and format
+    pub fn forward(&self, incoming: &Incoming) -> Result<()> {
+        let mut preset = self.preset.clone();
+        preset.title = incoming.title.clone().or(preset.title);
+        preset.priority = Some(incoming.priority);
+        preset.format = Some(incoming.format);
+        crate::send(&preset, &incoming.message)
+    }
+}
@@ -0,0 +1,61 @@
+//! Gotify backend (send only): https://gotify.net/api-docs#/message/createMessage
+
+use crate::{http, Error, Format, Priority, Result};
+use serde_json::json;
+use std::path::Path;
+
+/// Post `message` to the Gotify server at `url` with an application `token`.
+/// Clients render Markdown themselves; HTML is sent as plain text. They show
+/// `image`, which must be an http(s) URL, as a big picture.
+/// `ca` (PEM) replaces the system roots for `https://` servers.
+#[allow(clippy::too_many_arguments)]
+pub fn send(
+    url: &str,
+    token: &str,
+    message: &str,
+    title: Option<&str>,
+    priority: Priority,
+    format: Format,
+    image: Option<&str>,
+    ca: Option<&Path>,
+) -> Result<()> {
+    let url = format!("{}/message", url.trim_end_matches('/'));
+    let message = match format {
+        Format::Html => format.to_plain(message),
+        _ => message.to_string(),
+    };
+    let mut body = json!({
+        "message": message,
+        "priority": gotify_priority(priority),
+    });
+    if let Some(title) = title {
+        body["title"] = json!(title);
+    }
+    if format == Format::Markdown {
+        body["extras"] = json!({"client::display": {"contentType": "text/markdown"}});
+    }
+    if let Some(image) = image {
+        if !image.starts_with("http://") && !image.starts_with("https://") {
+            return Err(Error::Config(
+                "Gotify shows --image from a URL; give an http(s) URL".into(),
+            ));
+        }
+        body["extras"]["client::notification"] = json!({"bigImageUrl": image});
+    }
+    let response = http::client(ca)?
+        .post(&url)
+        .header("X-Gotify-Key", token)
+        .content_type("application/json")
+        .send(body.to_string());
+    http::check(&url, response)
+}
+
+/// Gotify priorities run 0-10; clients show 8+ as high and 4+ with a sound
+fn gotify_priority(priority: Priority) -> u8 {
+    match priority {
+        Priority::Low => 2,
+        Priority::Normal => 5,
+        Priority::High => 8,
+        Priority::Urgent => 10,
+    }
+}
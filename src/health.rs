@@ -0,0 +1,153 @@
+//! `--health ADDR`: a small HTTP endpoint reporting how a listener is doing,
+//! for monitoring systems and container orchestrators to probe, with its
+//! [`metrics`](crate::metrics) at `/metrics`. The endpoint needs the `http`
+//! feature.
+// Still counted without it, but nothing serves them
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+
+#[cfg(feature = "http")]
+use crate::{exec, metrics, shutdown};
+use crate::{Error, Result};
+#[cfg(feature = "http")]
+use serde_json::json;
+#[cfg(feature = "http")]
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+#[cfg(feature = "http")]
+use std::thread;
+#[cfg(feature = "http")]
+use std::time::Instant;
+use std::time::SystemTime;
+#[cfg(feature = "http")]
+use tiny_http::{Header, Request, Response, Server};
+
+/// Messages received so far
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+/// When the last one came in
+static LAST_MESSAGE: Mutex<Option<SystemTime>> = Mutex::new(None);
+/// The MQTT broker connection: 0 without one, then [`CONNECTED`] or [`DISCONNECTED`]
+static BROKER: AtomicU8 = AtomicU8::new(0);
+const CONNECTED: u8 = 1;
+const DISCONNECTED: u8 = 2;
+/// Times the broker connection came up
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+/// Commands waiting in a `--command-queue` and messages collected for a `--digest`
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Note a message coming in
+pub fn received() {
+    RECEIVED.fetch_add(1, Ordering::Relaxed);
+    *LAST_MESSAGE.lock().unwrap_or_else(PoisonError::into_inner) = Some(SystemTime::now());
+}
+
+/// Note the broker connection coming up or going down
+pub fn broker_connected(connected: bool) {
+    if connected {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+    BROKER.store(
+        if connected { CONNECTED } else { DISCONNECTED },
+        Ordering::Relaxed,
+    );
+}
+
+/// Note `count` more commands or messages waiting
+pub fn queued(count: usize) {
+    WAITING.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Note `count` of them no longer waiting
+pub fn dequeued(count: usize) {
+    WAITING.fetch_sub(count, Ordering::Relaxed);
+}
+
+/// How the listener is doing right now
+pub(crate) struct Snapshot {
+    pub received: u64,
+    pub last_message: Option<SystemTime>,
+    /// Whether the broker is connected, for listeners with one
+    pub broker: Option<bool>,
+    /// Times the broker connection came back after the first
+    pub reconnects: u64,
+    pub queued: usize,
+}
+
+pub(crate) fn snapshot() -> Snapshot {
+    Snapshot {
+        received: RECEIVED.load(Ordering::Relaxed),
+        last_message: *LAST_MESSAGE.lock().unwrap_or_else(PoisonError::into_inner),
+        broker: match BROKER.load(Ordering::Relaxed) {
+            CONNECTED => Some(true),
+            DISCONNECTED => Some(false),
+            _ => None,
+        },
+        reconnects: CONNECTIONS.load(Ordering::Relaxed).saturating_sub(1),
+        queued: WAITING.load(Ordering::Relaxed),
+    }
+}
+
+/// Answer `GET /health` on `addr` from a thread of its own: 200 while the
+/// listener is up, 503 while its broker is unreachable or it is stopping.
+/// `GET /metrics` has the same in Prometheus' text format, and more.
+#[cfg(feature = "http")]
+pub fn serve(addr: &str) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| Error::Bind {
+        addr: addr.to_string(),
+        source: io::Error::other(e),
+    })?;
+    let started = Instant::now();
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            match request.url().split('?').next() {
+                Some("/" | "/health") => health(request, started),
+                Some("/metrics") => respond(request, 200, metrics::CONTENT_TYPE, metrics::render()),
+                _ => respond(request, 404, "text/plain", "Not found\n".into()),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+pub fn serve(_addr: &str) -> Result<()> {
+    Err(Error::Unsupported("--health without the http feature"))
+}
+
+#[cfg(feature = "http")]
+fn health(request: Request, started: Instant) {
+    let now = snapshot();
+    let status = if shutdown::stopping() {
+        "stopping"
+    } else if now.broker == Some(false) {
+        "disconnected"
+    } else {
+        "ok"
+    };
+    let mut body = json!({
+        "status": status,
+        "uptime": started.elapsed().as_secs(),
+        "received": now.received,
+        "last_message": now.last_message.map(exec::timestamp),
+        "queued": now.queued,
+    });
+    if let Some(connected) = now.broker {
+        body["broker"] = if connected {
+            "connected"
+        } else {
+            "disconnected"
+        }
+        .into();
+    }
+    let code = if status == "ok" { 200 } else { 503 };
+    respond(request, code, "application/json", format!("{}\n", body));
+}
+
+#[cfg(feature = "http")]
+fn respond(request: Request, status: u16, content_type: &str, body: String) {
+    let mut response = Response::from_string(body).with_status_code(status);
+    if let Ok(header) = Header::from_bytes("Content-Type", content_type) {
+        response.add_header(header);
+    }
+    let _ = request.respond(response);
+}
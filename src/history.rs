@@ -0,0 +1,183 @@
+//! A listener's record of every message it received and what it did about
+//! it (`--history-file`), one JSON object per line
+
+use crate::{duration, exec, relay, Error, Incoming, Priority, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::error;
+
+/// One received message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    /// When it arrived, in RFC 3339 (UTC)
+    pub time: String,
+    /// Where it came from: peer address, broker, ...
+    pub source: String,
+    /// Label of the token it was sent with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Topic, subject, channel or routing key (relay modes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub message: String,
+    /// Where the file sent with it was saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Where `forward` rules sent it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwarded: Vec<String>,
+    /// The command run (or queued) for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// The command's exit code, if the listener waited for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<i32>,
+    /// The command went to `--command-queue` or `--digest` to run later
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub queued: bool,
+    /// `--cooldown`/`--max-per-minute` kept it from running anything
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub throttled: bool,
+    /// Quiet hours kept it from running anything
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quiet: bool,
+}
+
+impl Entry {
+    /// `incoming`, received just now; nothing done about it yet
+    pub fn new(incoming: &Incoming) -> Entry {
+        Entry {
+            time: exec::timestamp(SystemTime::now()),
+            source: incoming.source.clone(),
+            token: incoming.token.clone(),
+            topic: incoming.topic.clone(),
+            priority: incoming.priority,
+            title: incoming.title.clone(),
+            message: incoming.message.clone(),
+            file: incoming.file.clone(),
+            ..Entry::default()
+        }
+    }
+}
+
+/// The file entries are appended to
+pub struct History {
+    path: PathBuf,
+    file: File,
+}
+
+impl History {
+    /// Append to `path`, creating it (and its directory) if need be
+    pub fn open(path: &Path) -> Result<History> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                Error::Config(format!(
+                    "Cannot open history file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        Ok(History {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Append `entry` as one line; a failure is logged, not fatal
+    pub fn record(&self, entry: &Entry) {
+        let written = serde_json::to_string(entry)
+            .map_err(std::io::Error::from)
+            .and_then(|json| (&self.file).write_all(format!("{}\n", json).as_bytes()));
+        if let Err(e) = written {
+            error!("Cannot write to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Every entry in `path`, oldest first; lines that aren't entries (say, one
+/// cut short by a crash) are skipped
+pub fn read(path: &Path) -> Result<impl Iterator<Item = Entry>> {
+    let file = File::open(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::Config(format!("No history in {} yet", path.display())),
+        _ => Error::Config(format!("Cannot read {}: {}", path.display(), e)),
+    })?;
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str(&line).ok()))
+}
+
+/// Which entries to show (`crier history`)
+#[derive(Debug, Default)]
+pub struct Query {
+    /// Only entries from this time on, in RFC 3339 (see [`since`])
+    pub since: Option<String>,
+    /// Only entries published to a topic matching this filter (wildcards allowed)
+    pub topic: Option<String>,
+    /// Only entries whose title or message matches
+    pub grep: Option<Regex>,
+}
+
+impl Query {
+    pub fn keeps(&self, entry: &Entry) -> bool {
+        // RFC 3339 times in UTC sort as text, and so does a prefix of one
+        if self
+            .since
+            .as_ref()
+            .is_some_and(|since| entry.time.as_str() < since.as_str())
+        {
+            return false;
+        }
+        if let Some(filter) = &self.topic {
+            if !entry
+                .topic
+                .as_deref()
+                .is_some_and(|topic| relay::matches(filter, topic))
+            {
+                return false;
+            }
+        }
+        if let Some(grep) = &self.grep {
+            let title = entry.title.as_deref().unwrap_or_default();
+            if !grep.is_match(&entry.message) && !grep.is_match(title) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// `--since`: how long ago (e.g. `12h`), or a UTC time in RFC 3339 or the
+/// start of one (`2024-05-01`, `2024-05-01T22:00`)
+pub fn since(text: &str) -> Result<String> {
+    let text = text.trim();
+    if let Ok(ago) = duration::parse(text) {
+        let time = SystemTime::now()
+            .checked_sub(ago)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        return Ok(exec::timestamp(time));
+    }
+    let date =
+        Regex::new(r"^\d{4}-\d{2}-\d{2}(T\d{2}(:\d{2}(:\d{2})?)?Z?)?$").expect("valid regex");
+    if !date.is_match(text) {
+        return Err(Error::Config(format!(
+            "'{}' is neither a duration (e.g. 12h) nor a time (e.g. 2024-05-01T22:00)",
+            text
+        )));
+    }
+    Ok(text.trim_end_matches('Z').to_string())
+}
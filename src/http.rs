@@ -0,0 +1,340 @@
+//! HTTP listener (`POST /notify` plus GitHub/GitLab/Alertmanager/Grafana webhooks)
+//! and the matching sender. The listener needs the `http` feature.
+
+#[cfg(feature = "http")]
+use crate::auth::{Token, Tokens};
+#[cfg(feature = "http")]
+use crate::webhook::{self, WebhookEvent};
+#[cfg(feature = "http")]
+use crate::{metrics, Format, Incoming, Priority};
+use crate::{tls, Error, Result};
+#[cfg(feature = "http")]
+use serde::Deserialize;
+#[cfg(feature = "http")]
+use serde_json::Value;
+#[cfg(feature = "http")]
+use std::collections::HashMap;
+#[cfg(feature = "http")]
+use std::io::{self, Read};
+use std::path::Path;
+#[cfg(feature = "http")]
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+#[cfg(feature = "http")]
+use tiny_http::{Request, Response, Server};
+#[cfg(feature = "http")]
+use tracing::warn;
+use ureq::tls::{Certificate, RootCerts, TlsConfig};
+
+/// Largest request body accepted (webhook payloads are well below this)
+#[cfg(feature = "http")]
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// Endpoints served by the listener
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Notify,
+    Github,
+    Gitlab,
+    Alertmanager,
+    Grafana,
+}
+
+#[cfg(feature = "http")]
+impl Route {
+    fn from_path(path: &str) -> Option<Route> {
+        match path.split('?').next().unwrap_or(path) {
+            "/notify" => Some(Route::Notify),
+            "/github" => Some(Route::Github),
+            "/gitlab" => Some(Route::Gitlab),
+            "/alertmanager" => Some(Route::Alertmanager),
+            "/grafana" => Some(Route::Grafana),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Route::Notify => "notify",
+            Route::Github => "github",
+            Route::Gitlab => "gitlab",
+            Route::Alertmanager => "alertmanager",
+            Route::Grafana => "grafana",
+        }
+    }
+
+    /// The accepted token the request was made with, checked the way each
+    /// sender presents it. GitHub signs the body with the secret, so its
+    /// tokens cannot be hashed; GitLab sends it verbatim in `X-Gitlab-Token`.
+    fn authorized<'a>(self, auth: &'a Tokens, request: &Request, body: &[u8]) -> Option<&'a Token> {
+        match self {
+            Route::Github => auth.find(|secret| {
+                webhook::verify_github(secret, header(request, "X-Hub-Signature-256"), body)
+            }),
+            Route::Gitlab => header(request, "X-Gitlab-Token").and_then(|token| auth.check(token)),
+            Route::Notify | Route::Alertmanager | Route::Grafana => {
+                webhook::bearer(header(request, "Authorization"))
+                    .and_then(|token| auth.check(token))
+            }
+        }
+    }
+
+    fn events(self, request: &Request, payload: &Value) -> Vec<WebhookEvent> {
+        match self {
+            Route::Github => header(request, "X-GitHub-Event")
+                .and_then(|e| webhook::github(e, payload))
+                .into_iter()
+                .collect(),
+            Route::Gitlab => header(request, "X-Gitlab-Event")
+                .and_then(|e| webhook::gitlab(e, payload))
+                .into_iter()
+                .collect(),
+            Route::Alertmanager => webhook::alertmanager(payload),
+            Route::Grafana => webhook::grafana(payload),
+            Route::Notify => Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+pub fn bind(addr: &str) -> Result<Server> {
+    Server::http(addr).map_err(|e| Error::Bind {
+        addr: addr.to_string(),
+        source: io::Error::other(e),
+    })
+}
+
+/// Serve requests forever, calling `handler` for every accepted message or event.
+///
+/// `auth` is the webhook secret; `webhooks` optionally maps event names
+/// (push, pipeline, issue, alert, grafana) to message templates. Requests are
+/// answered as soon as they are accepted and handled one at a time after
+/// that, so a slow command doesn't hold up the sender (GitHub gives up
+/// after 10 seconds).
+#[cfg(feature = "http")]
+pub fn serve(
+    server: Server,
+    auth: &Tokens,
+    webhooks: Option<&HashMap<String, String>>,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let (accepted, received) = mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(move || accept(server, auth, webhooks, accepted));
+        for incoming in received {
+            handler(&incoming);
+        }
+    });
+    Ok(())
+}
+
+/// Answer requests, passing on what they carry to the handler
+#[cfg(feature = "http")]
+fn accept(
+    server: Server,
+    auth: &Tokens,
+    webhooks: Option<&HashMap<String, String>>,
+    accepted: Sender<Incoming>,
+) {
+    for mut request in server.incoming_requests() {
+        let peer = request
+            .remote_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        let ip = request.remote_addr().map(|a| a.ip());
+
+        if request.method() != &tiny_http::Method::Post {
+            respond(request, 405, "Method Not Allowed");
+            continue;
+        }
+
+        let Some(route) = Route::from_path(request.url()) else {
+            respond(request, 404, "Not Found");
+            continue;
+        };
+
+        let mut body = Vec::new();
+        if let Err(e) = request
+            .as_reader()
+            .take(MAX_BODY + 1)
+            .read_to_end(&mut body)
+        {
+            warn!("[{}] Read error: {}", peer, e);
+            respond(request, 400, "Bad Request");
+            continue;
+        }
+        if body.len() as u64 > MAX_BODY {
+            warn!("[{}] Request body over {} bytes, refused", peer, MAX_BODY);
+            respond(request, 413, "Payload Too Large");
+            continue;
+        }
+
+        let mut token = None;
+        if !auth.is_empty() {
+            match route.authorized(auth, &request, &body) {
+                Some(accepted) => token = accepted.label.clone(),
+                None => {
+                    warn!("[{}] Auth failed", peer);
+                    metrics::auth_failed();
+                    respond(request, 401, "ERR:AUTH");
+                    continue;
+                }
+            }
+        }
+
+        if route == Route::Notify {
+            match notify(&request, &body, peer) {
+                Ok(mut incoming) => {
+                    incoming.token = token;
+                    incoming.peer = ip;
+                    respond(request, 200, "OK");
+                    let _ = accepted.send(incoming);
+                }
+                Err(reason) => respond(request, 400, reason),
+            }
+            continue;
+        }
+
+        let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+            respond(request, 400, "Invalid JSON");
+            continue;
+        };
+
+        let mut messages = Vec::new();
+        for event in route.events(&request, &payload) {
+            if let Some(message) = event.render(webhooks) {
+                let mut incoming = Incoming::new(message, route.name().to_string());
+                incoming.priority = event.priority;
+                incoming.title = event.title;
+                incoming.token = token.clone();
+                incoming.peer = ip;
+                messages.push(incoming);
+            }
+        }
+
+        if messages.is_empty() {
+            respond(request, 202, "Ignored");
+            continue;
+        }
+        respond(request, 200, "OK");
+        for incoming in messages {
+            let _ = accepted.send(incoming);
+        }
+    }
+}
+
+/// Body of `POST /notify`: plain text, or JSON with `message` and optional
+/// `title`, `priority` and `format` when sent as `application/json`
+#[cfg(feature = "http")]
+fn notify(
+    request: &Request,
+    body: &[u8],
+    peer: String,
+) -> std::result::Result<Incoming, &'static str> {
+    let is_json =
+        header(request, "Content-Type").is_some_and(|t| t.starts_with("application/json"));
+    let mut incoming = if is_json {
+        let payload = serde_json::from_slice::<Value>(body).map_err(|_| "Invalid JSON")?;
+        let message = payload["message"].as_str().ok_or("Missing message")?;
+        let mut incoming = Incoming::new(message.to_string(), peer);
+        incoming.title = payload["title"].as_str().map(str::to_string);
+        if let Some(priority) = payload.get("priority") {
+            incoming.priority = Priority::deserialize(priority).map_err(|_| "Invalid priority")?;
+        }
+        if let Some(format) = payload.get("format") {
+            incoming.format = Format::deserialize(format).map_err(|_| "Invalid format")?;
+        }
+        incoming
+    } else {
+        let message = std::str::from_utf8(body).map_err(|_| "Invalid UTF-8")?;
+        Incoming::new(message.to_string(), peer)
+    };
+    incoming.message = incoming.message.trim_end_matches(['\r', '\n']).to_string();
+    if incoming.message.is_empty() {
+        return Err("Empty message");
+    }
+    Ok(incoming)
+}
+
+#[cfg(feature = "http")]
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+#[cfg(feature = "http")]
+fn respond(request: Request, status: u16, body: &str) {
+    let _ = request.respond(Response::from_string(format!("{}\n", body)).with_status_code(status));
+}
+
+/// POST `message` to a crier HTTP listener. `url` may omit the scheme
+/// (`host:8080`) and the path (`/notify`); `https://` URLs are verified against
+/// `ca` (PEM) or the system roots.
+pub fn send(url: &str, message: &str, auth: Option<&str>, ca: Option<&Path>) -> Result<()> {
+    let url = notify_url(url);
+    let mut request = client(ca)?
+        .post(&url)
+        .content_type("text/plain; charset=utf-8");
+    if let Some(token) = auth {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    check(&url, request.send(message))
+}
+
+/// HTTP(S) client trusting `ca` (PEM) or the system roots; non-2xx statuses are
+/// returned as responses, not errors
+pub(crate) fn client(ca: Option<&Path>) -> Result<ureq::Agent> {
+    let roots: Vec<Certificate<'static>> = tls::root_certs(ca)?
+        .iter()
+        .map(|c| Certificate::from_der(c).to_owned())
+        .collect();
+    Ok(ureq::Agent::config_builder()
+        .tls_config(
+            TlsConfig::builder()
+                .root_certs(RootCerts::new_with_certs(&roots))
+                .unversioned_rustls_crypto_provider(tls::provider())
+                .build(),
+        )
+        .http_status_as_error(false)
+        .timeout_global(Some(Duration::from_secs(10)))
+        .build()
+        .new_agent())
+}
+
+/// Ok for a 2xx response, otherwise the response body (or status) as a rejection
+pub(crate) fn check(
+    url: &str,
+    response: std::result::Result<ureq::http::Response<ureq::Body>, ureq::Error>,
+) -> Result<()> {
+    let mut response = response.map_err(|e| Error::Connect {
+        addr: url.to_string(),
+        source: e.into_io(),
+    })?;
+    let body = response.body_mut().read_to_string().unwrap_or_default();
+    if response.status().is_success() {
+        Ok(())
+    } else if body.trim().is_empty() {
+        Err(Error::Rejected(response.status().to_string()))
+    } else {
+        Err(Error::Rejected(body.trim().to_string()))
+    }
+}
+
+/// `host:8080` -> `http://host:8080/notify`
+fn notify_url(url: &str) -> String {
+    let url = if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    };
+    let (_, rest) = url.split_once("://").unwrap_or_default();
+    if rest.contains('/') {
+        url
+    } else {
+        format!("{}/notify", url)
+    }
+}
@@ -0,0 +1,125 @@
+//! systemd journal follower (via `journalctl --follow --output=json`)
+
+use crate::{syslog, template, Error, Priority, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+/// Default message for a journal entry
+pub const DEFAULT_TEMPLATE: &str = "{unit}: {message}";
+
+/// Which entries to follow
+#[derive(Debug, Clone, Default)]
+pub struct Follow {
+    /// systemd units (any of them); empty means all
+    pub units: Vec<String>,
+    /// journalctl priority filter (e.g. `err`, `0..3`)
+    pub priority: Option<String>,
+    /// Only entries whose message matches
+    pub grep: Option<Regex>,
+}
+
+/// One journal entry
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub unit: String,
+    pub identifier: String,
+    pub message: String,
+    pub host: String,
+    pub pid: String,
+    /// Syslog severity (0 = emerg ... 7 = debug)
+    pub severity: u8,
+}
+
+impl Entry {
+    fn from_json(v: &Value) -> Entry {
+        let identifier = field(v, "SYSLOG_IDENTIFIER");
+        let unit = Some(field(v, "_SYSTEMD_UNIT"))
+            .filter(|u| !u.is_empty())
+            .unwrap_or_else(|| identifier.clone());
+        Entry {
+            unit,
+            identifier,
+            message: field(v, "MESSAGE"),
+            host: field(v, "_HOSTNAME"),
+            pid: field(v, "_PID"),
+            severity: field(v, "PRIORITY").parse().unwrap_or(6),
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        syslog::severity_priority(self.severity)
+    }
+
+    /// Fill `{unit}`, `{identifier}`, `{message}`, `{host}`, `{pid}` and `{priority}`
+    pub fn render(&self, template: &str) -> String {
+        template::render(template, |name| {
+            Some(match name {
+                "unit" => self.unit.clone(),
+                "identifier" => self.identifier.clone(),
+                "message" => self.message.clone(),
+                "host" => self.host.clone(),
+                "pid" => self.pid.clone(),
+                "priority" => self.priority().to_string(),
+                _ => return None,
+            })
+        })
+    }
+}
+
+/// Follow the journal from now on, calling `on_entry` for every matching entry.
+/// Returns when journalctl exits.
+pub fn follow(opts: &Follow, mut on_entry: impl FnMut(&Entry)) -> Result<()> {
+    let mut cmd = Command::new("journalctl");
+    cmd.args(["--follow", "--output=json", "--lines=0"]);
+    for unit in &opts.units {
+        cmd.arg("--unit").arg(unit);
+    }
+    if let Some(priority) = &opts.priority {
+        cmd.arg("--priority").arg(priority);
+    }
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Config(format!("Failed to run journalctl: {}", e)))?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    for line in BufReader::new(stdout).lines() {
+        let Ok(json) = serde_json::from_str::<Value>(&line?) else {
+            continue;
+        };
+        let entry = Entry::from_json(&json);
+        if opts
+            .grep
+            .as_ref()
+            .is_some_and(|re| !re.is_match(&entry.message))
+        {
+            continue;
+        }
+        on_entry(&entry);
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Config(format!("journalctl exited with {}", status)))
+    }
+}
+
+/// Journal fields are strings, or byte arrays when not valid UTF-8
+fn field(v: &Value, name: &str) -> String {
+    match v.get(name) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(bytes)) => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .filter_map(|b| b.as_u64().map(|b| b as u8))
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => String::new(),
+    }
+}
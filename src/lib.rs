@@ -0,0 +1,1100 @@
+//! Crier - Simple push notification tool
+//!
+//! The `crier` binary is a thin CLI over this library. Other programs can use
+//! the same transports directly, through the C API behind the `ffi` feature,
+//! or from Python with the `python` feature.
+
+pub mod acl;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+mod args;
+pub mod auth;
+pub mod bridge;
+pub mod ci;
+pub mod config;
+pub mod cron;
+pub mod crypto;
+pub mod daemon;
+pub mod desktop;
+pub mod digest;
+pub mod direct;
+pub mod duration;
+pub mod email;
+pub mod envelope;
+mod error;
+pub mod exec;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod files;
+pub mod filter;
+pub mod forward;
+pub mod gotify;
+pub mod health;
+pub mod history;
+pub mod http;
+pub mod journal;
+pub mod logfile;
+pub mod logging;
+mod markup;
+pub mod metrics;
+pub mod nagios;
+pub mod nats;
+pub mod output;
+mod priority;
+pub mod proxy;
+#[cfg(feature = "python")]
+mod python;
+pub mod quiet;
+pub mod ratelimit;
+pub mod redis;
+pub mod relay;
+pub mod route;
+pub mod sandbox;
+pub mod schedule;
+pub mod script;
+pub mod secret;
+pub mod shutdown;
+pub mod signing;
+pub mod spool;
+pub mod syslog;
+pub mod systemd;
+pub mod tail;
+pub mod template;
+pub mod tls;
+pub mod user;
+pub mod watch;
+pub mod webhook;
+#[cfg(feature = "zmq")]
+pub mod zmq;
+
+pub use config::{Config, Preset, Transport};
+pub use error::{Error, Result};
+pub use markup::Format;
+pub use priority::Priority;
+
+#[cfg(any(feature = "amqp", feature = "zmq"))]
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use tracing::warn;
+
+/// A message received by a listener
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    /// Message text, with any auth prefix already stripped
+    pub message: String,
+    /// Peer address (direct mode), topic, subject, channel or routing key (relay modes),
+    /// webhook source (HTTP)
+    /// or originating host (syslog)
+    pub source: String,
+    pub priority: Priority,
+    pub title: Option<String>,
+    /// How the message text is formatted
+    pub format: Format,
+    /// Name/value metadata from the transport (MQTT v5 user properties)
+    pub properties: Vec<(String, String)>,
+    /// Label of the named auth token the message came with
+    pub token: Option<String>,
+    /// The peer's IP (direct, HTTP and syslog modes)
+    pub peer: Option<IpAddr>,
+    /// Topic, subject, channel or routing key it was published to (relay modes)
+    pub topic: Option<String>,
+    /// Where the file sent with it was saved (`--save-dir`)
+    pub file: Option<PathBuf>,
+    /// The same file, when the sender marked it as an image to show
+    pub image: Option<PathBuf>,
+    /// Set when the sender waits for the command's result (`--reply`), for
+    /// the handler to leave it in
+    pub reply: Option<ReplySlot>,
+    /// Set by relay listeners, for opening the message to leave the id of
+    /// the receipt its sender waits for (`--receipt`) in
+    pub receipt: Option<ReceiptSlot>,
+}
+
+impl Incoming {
+    pub fn new(message: String, source: String) -> Self {
+        Incoming {
+            message,
+            source,
+            priority: Priority::default(),
+            title: None,
+            format: Format::default(),
+            properties: Vec::new(),
+            token: None,
+            peer: None,
+            topic: None,
+            file: None,
+            image: None,
+            reply: None,
+            receipt: None,
+        }
+    }
+
+    /// The message without its formatting
+    pub fn text(&self) -> String {
+        self.format.to_plain(&self.message)
+    }
+
+    /// Value of the first property called `name`
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Who sent it: the label of its auth token, the hostname an MQTT v5
+    /// sender (or one `--with-meta`) reports, the peer's IP (without the
+    /// port, which changes with every connection) or the source
+    pub fn sender(&self) -> String {
+        if let Some(label) = &self.token {
+            return label.clone();
+        }
+        if let Some(hostname) = self.property("hostname").or(self.property("host")) {
+            return hostname.to_string();
+        }
+        match self.source.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => self.source.clone(),
+        }
+    }
+
+    /// Who sent it, as far as the listener can tell for itself: the label of
+    /// its auth token, or else the peer's IP. Brokers don't say who published,
+    /// so there it is the topic (subject, channel or routing key) it came on,
+    /// shared by everyone sending to it.
+    pub fn identity(&self) -> String {
+        if let Some(label) = &self.token {
+            return label.clone();
+        }
+        match self.peer {
+            Some(ip) => ip.to_string(),
+            None => self.source.clone(),
+        }
+    }
+}
+
+/// Where a listener's handler leaves the [`exec::Reply`] for a sender waiting
+/// on it; copies of the message share it
+#[derive(Debug, Clone, Default)]
+pub struct ReplySlot(Arc<Mutex<Option<exec::Reply>>>);
+
+impl ReplySlot {
+    pub fn set(&self, reply: exec::Reply) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(reply);
+    }
+
+    pub fn take(&self) -> Option<exec::Reply> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+/// Where opening a message leaves the id of the receipt its sender waits
+/// for, for the relay listener to publish; copies of the message share it
+#[derive(Debug, Clone, Default)]
+pub struct ReceiptSlot(Arc<Mutex<Option<String>>>);
+
+impl ReceiptSlot {
+    pub fn set(&self, id: String) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(id);
+    }
+
+    pub fn take(&self) -> Option<String> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+/// Send `message` to the target described by `preset`
+pub fn send(preset: &Preset, message: &str) -> Result<()> {
+    let transport = preset.transport()?;
+    check_sender(preset, &transport)?;
+    // Crier listeners get an image the way they get files
+    if let Some(image) = preset.image.as_deref() {
+        if !transport.is_foreign() && !matches!(transport, Transport::Fanout(_)) {
+            if image.starts_with("http://") || image.starts_with("https://") {
+                return Err(Error::Config(
+                    "crier listeners need --image as a file, not a URL".into(),
+                ));
+            }
+            return send_attachment(
+                preset,
+                Path::new(image),
+                Some(message).filter(|m| !m.is_empty()),
+                true,
+            );
+        }
+    }
+    if preset.receipt.unwrap_or(false) {
+        return send_with_receipt(preset, &transport, message);
+    }
+    let sealed = seal(preset, &transport, message, None)?;
+    retrying(preset, || deliver(preset, &transport, &sealed))
+}
+
+/// [`send`] over MQTT, waiting up to `receipt_timeout` for a listener to
+/// acknowledge the message (`--receipt`). A message without one isn't sent
+/// again: the listener may have got it and not been heard from.
+fn send_with_receipt(preset: &Preset, transport: &Transport, message: &str) -> Result<()> {
+    let Transport::Relay {
+        broker,
+        port,
+        topic,
+    } = transport
+    else {
+        return Err(Error::Unsupported("--receipt outside relay mode"));
+    };
+    let id = relay::random_id()?;
+    let timeout = preset
+        .receipt_timeout
+        .unwrap_or(config::DEFAULT_RECEIPT_TIMEOUT);
+    let auth = preset.auth.as_deref();
+    let options = preset.mqtt_options()?;
+    if preset.mqtt5.unwrap_or(false) {
+        let sealed = seal(preset, transport, message, None)?;
+        let mut properties = preset.mqtt5_properties();
+        properties
+            .user
+            .push((envelope::RECEIPT_PROPERTY.to_string(), id.clone()));
+        retrying(preset, || {
+            let (broker, port, _tunnel) = preset.mqtt_broker(broker, *port)?;
+            relay::send_with_receipt_v5(
+                &broker,
+                port,
+                topic,
+                &sealed,
+                auth,
+                &properties,
+                &options,
+                &id,
+                timeout,
+            )
+        })
+    } else {
+        let sealed = seal(preset, transport, message, Some(&id))?;
+        retrying(preset, || {
+            let (broker, port, _tunnel) = preset.mqtt_broker(broker, *port)?;
+            relay::send_with_receipt(&broker, port, topic, &sealed, auth, &options, &id, timeout)
+        })
+    }
+}
+
+/// The settings a sender can't use with `transport`
+fn check_sender(preset: &Preset, transport: &Transport) -> Result<()> {
+    if preset.proxy.is_some()
+        && !matches!(
+            transport,
+            Transport::Direct { .. } | Transport::Relay { .. }
+        )
+    {
+        return Err(Error::Unsupported("--proxy outside direct and relay mode"));
+    }
+    if preset.status_topic.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--status-topic outside relay mode"));
+    }
+    if preset.qos.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--qos outside relay mode"));
+    }
+    if preset.retain.unwrap_or(false) && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--retain outside relay mode"));
+    }
+    if preset.client_id.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--client-id outside relay mode"));
+    }
+    if (preset.keep_alive.is_some()
+        || preset.connect_timeout.is_some()
+        || preset.max_packet_size.is_some())
+        && !matches!(transport, Transport::Relay { .. })
+    {
+        return Err(Error::Unsupported(
+            "--keep-alive, --connect-timeout or --max-packet-size outside relay mode",
+        ));
+    }
+    if preset.challenge.unwrap_or(false) && !matches!(transport, Transport::Direct { .. }) {
+        return Err(Error::Unsupported("--challenge outside direct mode"));
+    }
+    if preset.timeout.is_some() && !matches!(transport, Transport::Direct { .. }) {
+        return Err(Error::Unsupported("--timeout outside direct mode"));
+    }
+    if preset.receipt.unwrap_or(false) && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--receipt outside relay mode"));
+    }
+    if preset.receipt.unwrap_or(false) && preset.retain.unwrap_or(false) {
+        return Err(Error::Unsupported("--receipt with --retain"));
+    }
+    if preset.expiry.is_some()
+        && matches!(transport, Transport::Relay { .. })
+        && !preset.mqtt5.unwrap_or(false)
+    {
+        return Err(Error::Config("--expiry requires --mqtt5".into()));
+    }
+    Ok(())
+}
+
+/// Call `attempt` until it succeeds, fails for good or the preset's
+/// `retries` are used up, waiting `retry_delay` before the first retry and
+/// twice as long before each one after that
+fn retrying(preset: &Preset, mut attempt: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut delay = preset.retry_delay.unwrap_or(config::DEFAULT_RETRY_DELAY);
+    for _ in 0..preset.retries.unwrap_or(0) {
+        match attempt() {
+            Err(e) if e.is_transient() => {
+                warn!(
+                    "Sending failed ({}), retrying in {}",
+                    e,
+                    cron::format_duration(delay)
+                );
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    attempt()
+}
+
+/// Hand the `sealed` message to `transport`, once
+fn deliver(preset: &Preset, transport: &Transport, message: &str) -> Result<()> {
+    let auth = preset.auth.as_deref();
+    match transport {
+        Transport::Relay {
+            broker,
+            port,
+            topic,
+        } => {
+            let options = preset.mqtt_options()?;
+            let (broker, port, _tunnel) = preset.mqtt_broker(broker, *port)?;
+            if preset.mqtt5.unwrap_or(false) {
+                relay::send_v5(
+                    &broker,
+                    port,
+                    topic,
+                    message,
+                    auth,
+                    &preset.mqtt5_properties(),
+                    &options,
+                )
+            } else {
+                relay::send(&broker, port, topic, message, auth, &options)
+            }
+        }
+        Transport::Nats { url, subject } => nats::send(url, subject, message, auth),
+        Transport::Redis { url, channel } => redis::send(url, channel, message, auth),
+        #[cfg(feature = "amqp")]
+        Transport::Amqp(amqp) => amqp::send(amqp, message, auth),
+        #[cfg(feature = "zmq")]
+        Transport::Zmq { endpoints, topic } => zmq::send(endpoints, topic, message, auth),
+        Transport::Direct { addr } => direct::send(
+            addr,
+            message,
+            auth,
+            preset.auth_mode()?,
+            preset.client_tls()?,
+            preset.proxy()?.as_ref(),
+            preset.timeout,
+        ),
+        Transport::Http { addr } => http::send(addr, message, auth, preset.tls_ca.as_deref()),
+        Transport::Syslog { .. } => Err(Error::Unsupported("Sending to a syslog listener")),
+        Transport::Gotify { url, token } => gotify::send(
+            url,
+            token,
+            message,
+            preset.title.as_deref(),
+            preset.priority.unwrap_or_default(),
+            preset.format.unwrap_or_default(),
+            preset.image.as_deref(),
+            preset.tls_ca.as_deref(),
+        ),
+        #[cfg(feature = "email")]
+        Transport::Email(smtp) => {
+            if preset
+                .image
+                .as_deref()
+                .is_some_and(|image| image.starts_with("http://") || image.starts_with("https://"))
+            {
+                return Err(Error::Config(
+                    "Email attaches --image from a file, not a URL".into(),
+                ));
+            }
+            email::send(
+                smtp,
+                message,
+                preset.title.as_deref(),
+                preset.priority.unwrap_or_default(),
+                preset.format.unwrap_or_default(),
+                preset.image.as_deref().map(Path::new),
+            )
+        }
+        Transport::Fanout(destinations) => {
+            let results = send_each(destinations, message);
+            let failed: Vec<String> = results
+                .iter()
+                .filter_map(|(name, result)| {
+                    result.as_ref().err().map(|e| format!("{}: {}", name, e))
+                })
+                .collect();
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::Rejected(format!(
+                    "{} of {} destinations failed ({})",
+                    failed.len(),
+                    results.len(),
+                    failed.join("; ")
+                )))
+            }
+        }
+    }
+}
+
+/// `message` as it goes out over `transport`. Gotify, email and MQTT v5
+/// carry the title, priority and format themselves; elsewhere they go in an
+/// envelope, along with the `receipt` id if any, which is then signed and
+/// encrypted with the message.
+fn seal(
+    preset: &Preset,
+    transport: &Transport,
+    message: &str,
+    receipt: Option<&str>,
+) -> Result<String> {
+    let mut message = match transport {
+        Transport::Fanout(_) => message.to_string(),
+        _ if transport.is_foreign() => message.to_string(),
+        Transport::Relay { .. } if preset.mqtt5.unwrap_or(false) => message.to_string(),
+        _ => envelope::seal(
+            message,
+            preset.title.as_deref(),
+            preset.priority,
+            preset.format,
+            preset.with_meta.unwrap_or(false).then(envelope::Meta::now),
+            receipt,
+        ),
+    };
+    // Sign the plaintext, then encrypt, so the signature is hidden too
+    if preset.sign.is_some() || preset.encrypt.is_some() {
+        if transport.is_foreign() || matches!(transport, Transport::Fanout(_)) {
+            return Err(Error::Unsupported(
+                "--sign or --encrypt with Gotify, email or a fanout preset",
+            ));
+        }
+        if let Some(key) = preset.signing_key()? {
+            message = key.sign(&message, transport.topic())?;
+        }
+        if let Some(key) = preset.encryption_key()? {
+            message = key.encrypt(&message)?;
+        }
+    }
+    Ok(message)
+}
+
+/// What [`send`] would do with `message`, without connecting anywhere
+/// (`--dry-run`): a name and value for where it goes, how it is secured and
+/// what goes out. Settings that are wrong fail as they would when sending.
+pub fn dry_run(preset: &Preset, message: &str) -> Result<Vec<(String, String)>> {
+    let transport = preset.transport()?;
+    check_sender(preset, &transport)?;
+    let mut lines = Vec::new();
+    let mut line = |name: &str, value: String| lines.push((name.to_string(), value));
+    let version = if preset.mqtt5.unwrap_or(false) {
+        " (MQTT v5)"
+    } else {
+        ""
+    };
+    match &transport {
+        Transport::Direct { addr } => line("To", format!("{} (direct)", addr)),
+        Transport::Relay {
+            broker,
+            port,
+            topic,
+        } => line(
+            "To",
+            format!("{}:{}, topic {}{}", broker, port, topic, version),
+        ),
+        Transport::Nats { url, subject } => {
+            line("To", format!("{}, subject {}", nats::server(url), subject))
+        }
+        Transport::Redis { url, channel } => {
+            line("To", format!("{}, channel {}", redis::server(url), channel))
+        }
+        #[cfg(feature = "amqp")]
+        Transport::Amqp(amqp) => line(
+            "To",
+            format!(
+                "{}, exchange '{}', routing key {}",
+                amqp.server(),
+                amqp.exchange,
+                amqp.routing_key
+            ),
+        ),
+        #[cfg(feature = "zmq")]
+        Transport::Zmq { endpoints, topic } => {
+            line("To", format!("{}, topic '{}'", endpoints.join(", "), topic))
+        }
+        Transport::Http { addr } => line("To", format!("{} (HTTP)", addr)),
+        Transport::Syslog { .. } => return Err(Error::Unsupported("Sending to a syslog listener")),
+        Transport::Gotify { url, .. } => line("To", format!("{} (Gotify, app token)", url)),
+        #[cfg(feature = "email")]
+        Transport::Email(smtp) => line(
+            "To",
+            format!(
+                "{} (email from {} via {})",
+                smtp.to.join(", "),
+                smtp.from,
+                smtp.host
+            ),
+        ),
+        Transport::Fanout(destinations) => {
+            for (name, destination) in destinations {
+                line("Destination", name.clone());
+                for (name, value) in dry_run(destination, message)? {
+                    line(&format!("  {}", name), value);
+                }
+            }
+            return Ok(lines);
+        }
+    }
+    if let Some(proxy) = preset.proxy()? {
+        line("Proxy", proxy.server());
+    }
+    if let Some(timeout) = preset.timeout {
+        line("Timeout", cron::format_duration(timeout));
+    }
+    if matches!(
+        transport,
+        Transport::Direct { .. } | Transport::Relay { .. }
+    ) && preset.tls_enabled()
+    {
+        preset.client_tls()?;
+        let certificate = if preset.tls_cert.is_some() {
+            " (client certificate)"
+        } else {
+            ""
+        };
+        line("TLS", format!("enabled{}", certificate));
+    }
+    if preset.auth.is_some() && !transport.is_foreign() {
+        let challenge = match preset.auth_mode()? {
+            direct::AuthMode::Challenge if matches!(transport, Transport::Direct { .. }) => {
+                " (challenge-response)"
+            }
+            _ => "",
+        };
+        line("Auth", format!("token{}", challenge));
+    }
+    if preset.signing_key()?.is_some() {
+        line("Signed", "yes (Ed25519)".into());
+    }
+    if preset.encryption_key()?.is_some() {
+        line("Encrypted", "yes (XChaCha20-Poly1305)".into());
+    }
+    if let Some(title) = &preset.title {
+        line("Title", title.clone());
+    }
+    if let Some(priority) = preset.priority {
+        line("Priority", priority.to_string());
+    }
+    if let Some(image) = &preset.image {
+        line("Image", image.clone());
+    }
+    if preset.with_meta.unwrap_or(false) && !transport.is_foreign() {
+        line("Meta", "this host's name, the user and the time".into());
+    }
+    if preset.retain.unwrap_or(false) {
+        line(
+            "Retained",
+            "yes (listeners that subscribe later get it too)".into(),
+        );
+    }
+    if let Some(retries) = preset.retries.filter(|retries| *retries > 0) {
+        let delay = preset.retry_delay.unwrap_or(config::DEFAULT_RETRY_DELAY);
+        line(
+            "Retries",
+            format!(
+                "{}, the first after {}",
+                retries,
+                cron::format_duration(delay)
+            ),
+        );
+    }
+    line("Message", message.to_string());
+    let sealed = seal(preset, &transport, message, None)?;
+    if sealed != message {
+        line("Payload", sealed);
+    }
+    Ok(lines)
+}
+
+/// Send `message` to a listener running with `--reply` and wait up to
+/// `reply_timeout` for the result of the command it runs for it (direct
+/// mode and MQTT v5)
+pub fn request(preset: &Preset, message: &str) -> Result<exec::Reply> {
+    let auth = preset.auth.as_deref();
+    let transport = preset.transport()?;
+    check_sender(preset, &transport)?;
+    if preset.image.is_some() {
+        return Err(Error::Unsupported("--expect-reply with --image"));
+    }
+    // Listeners subscribing later would run it again
+    if preset.retain.unwrap_or(false) {
+        return Err(Error::Unsupported("--expect-reply with --retain"));
+    }
+    let timeout = preset
+        .reply_timeout
+        .unwrap_or(config::DEFAULT_REPLY_TIMEOUT);
+    let message = seal(preset, &transport, message, None)?;
+    match transport {
+        Transport::Direct { addr } => direct::request(
+            &addr,
+            &message,
+            auth,
+            preset.auth_mode()?,
+            preset.client_tls()?,
+            preset.proxy()?.as_ref(),
+            preset.timeout,
+            timeout,
+        ),
+        Transport::Relay {
+            broker,
+            port,
+            topic,
+        } if preset.mqtt5.unwrap_or(false) => {
+            let options = preset.mqtt_options()?;
+            let (broker, port, _tunnel) = preset.mqtt_broker(&broker, port)?;
+            let properties = preset.mqtt5_properties();
+            relay::request_v5(
+                &broker,
+                port,
+                &topic,
+                &message,
+                auth,
+                &properties,
+                &options,
+                timeout,
+            )
+        }
+        _ => Err(Error::Unsupported(
+            "--expect-reply outside direct mode and MQTT v5",
+        )),
+    }
+}
+
+/// Send the file at `path`, and `message` with it, as a series of messages
+pub fn send_file(preset: &Preset, path: &Path, message: Option<&str>) -> Result<()> {
+    send_attachment(preset, path, message, false)
+}
+
+/// [`send_file`], optionally marking the file as an image to show
+fn send_attachment(preset: &Preset, path: &Path, message: Option<&str>, image: bool) -> Result<()> {
+    let transport = preset.transport()?;
+    if transport.is_foreign() || matches!(transport, Transport::Fanout(_)) {
+        return Err(Error::Unsupported(
+            "--file with Gotify, email or a fanout preset",
+        ));
+    }
+    // Only the last chunk would be kept
+    if preset.retain.unwrap_or(false) {
+        return Err(Error::Unsupported("--file or --image with --retain"));
+    }
+    let chunks = files::chunks(path, message, preset.max_file_size(), image)?;
+    let mut preset = Preset {
+        image: None,
+        ..preset.clone()
+    };
+    for chunk in chunks {
+        send(&preset, &chunk)?;
+        // The listener was online for the first chunk
+        preset.status_topic = None;
+    }
+    Ok(())
+}
+
+/// Send every message `messages` yields, in order, until it is closed or one
+/// fails, calling `sent` for each; in direct mode they share one connection
+/// (a [`direct::Session`]), kept alive while there is nothing to send
+pub fn send_stream(
+    preset: &Preset,
+    messages: &mpsc::Receiver<String>,
+    mut sent: impl FnMut(&str),
+) -> Result<()> {
+    let transport = preset.transport()?;
+    let Transport::Direct { addr } = &transport else {
+        for message in messages {
+            send(preset, &message)?;
+            sent(&message);
+        }
+        return Ok(());
+    };
+    check_sender(preset, &transport)?;
+    if preset.image.is_some() {
+        return Err(Error::Unsupported("--image with a stream of messages"));
+    }
+    let mut session = direct::Session::new(
+        addr,
+        preset.auth.as_deref(),
+        preset.auth_mode()?,
+        preset.client_tls()?,
+        preset.proxy()?,
+        preset.timeout,
+    );
+    loop {
+        match messages.recv_timeout(direct::KEEPALIVE_INTERVAL) {
+            Ok(message) => {
+                let sealed = seal(preset, &transport, &message, None)?;
+                retrying(preset, || session.send(&sealed))?;
+                sent(&message);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => session.keep_alive(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Send each of `messages` in order, going on past failures, and call `done`
+/// with each one and the error it failed with, if any. In direct mode they
+/// share one connection (a [`direct::Session`]), over MQTT one broker
+/// connection, whose loss fails the rest; elsewhere, and when waiting for
+/// receipts, each goes on its own.
+pub fn send_batch(
+    preset: &Preset,
+    messages: &[String],
+    mut done: impl FnMut(&str, Option<&Error>),
+) -> Result<()> {
+    let transport = preset.transport()?;
+    check_sender(preset, &transport)?;
+    if preset.image.is_some() {
+        return Err(Error::Unsupported("--image with a batch of messages"));
+    }
+    match &transport {
+        Transport::Direct { addr } => {
+            let mut session = direct::Session::new(
+                addr,
+                preset.auth.as_deref(),
+                preset.auth_mode()?,
+                preset.client_tls()?,
+                preset.proxy()?,
+                preset.timeout,
+            );
+            for message in messages {
+                let sealed = seal(preset, &transport, message, None)?;
+                done(
+                    message,
+                    retrying(preset, || session.send(&sealed)).err().as_ref(),
+                );
+            }
+        }
+        // Each waits for its own receipt
+        Transport::Relay { .. } if preset.receipt.unwrap_or(false) => {
+            for message in messages {
+                done(message, send(preset, message).err().as_ref());
+            }
+        }
+        Transport::Relay {
+            broker,
+            port,
+            topic,
+        } => {
+            let sealed = messages
+                .iter()
+                .map(|message| seal(preset, &transport, message, None))
+                .collect::<Result<Vec<_>>>()?;
+            let payloads: Vec<&str> = sealed.iter().map(String::as_str).collect();
+            let options = preset.mqtt_options()?;
+            let (broker, port, _tunnel) = preset.mqtt_broker(broker, *port)?;
+            let auth = preset.auth.as_deref();
+            let mut sent = 0;
+            let mut published = |_| {
+                done(&messages[sent], None);
+                sent += 1;
+            };
+            let result = if preset.mqtt5.unwrap_or(false) {
+                let properties = preset.mqtt5_properties();
+                relay::send_all_v5(
+                    &broker,
+                    port,
+                    topic,
+                    &payloads,
+                    auth,
+                    &properties,
+                    &options,
+                    &mut published,
+                )
+            } else {
+                relay::send_all(
+                    &broker,
+                    port,
+                    topic,
+                    &payloads,
+                    auth,
+                    &options,
+                    &mut published,
+                )
+            };
+            if let Err(e) = result {
+                for message in &messages[sent..] {
+                    done(message, Some(&e));
+                }
+            }
+        }
+        _ => {
+            for message in messages {
+                done(message, send(preset, message).err().as_ref());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A target kept open for messages that come one at a time, as typed
+/// (`crier send --interactive`): over one [`direct::Session`] in direct
+/// mode, and one [`relay::Publisher`] in relay mode. Elsewhere, and when
+/// waiting for receipts, each message goes on its own, as with [`send`].
+pub struct Connection {
+    preset: Preset,
+    transport: Transport,
+    open: Open,
+}
+
+enum Open {
+    Direct(direct::Session),
+    Relay {
+        publisher: relay::Publisher,
+        /// Kept open for the publisher, through `proxy`
+        _tunnel: Option<proxy::Tunnel>,
+    },
+    Each,
+}
+
+impl Connection {
+    /// Get ready to send with `preset`; only relay mode connects right away
+    pub fn open(preset: &Preset) -> Result<Connection> {
+        let transport = preset.transport()?;
+        check_sender(preset, &transport)?;
+        if preset.image.is_some() {
+            return Err(Error::Unsupported("--image with an open connection"));
+        }
+        let auth = preset.auth.as_deref();
+        let open = match &transport {
+            Transport::Direct { addr } => Open::Direct(direct::Session::new(
+                addr,
+                auth,
+                preset.auth_mode()?,
+                preset.client_tls()?,
+                preset.proxy()?,
+                preset.timeout,
+            )),
+            Transport::Relay { .. } if preset.receipt.unwrap_or(false) => Open::Each,
+            Transport::Relay {
+                broker,
+                port,
+                topic,
+            } => {
+                let options = preset.mqtt_options()?;
+                let (broker, port, tunnel) = preset.mqtt_broker(broker, *port)?;
+                let publisher = if preset.mqtt5.unwrap_or(false) {
+                    relay::Publisher::new_v5(
+                        &broker,
+                        port,
+                        topic,
+                        auth,
+                        &preset.mqtt5_properties(),
+                        &options,
+                    )?
+                } else {
+                    relay::Publisher::new(&broker, port, topic, auth, &options)?
+                };
+                Open::Relay {
+                    publisher,
+                    _tunnel: tunnel,
+                }
+            }
+            _ => Open::Each,
+        };
+        Ok(Connection {
+            preset: preset.clone(),
+            transport,
+            open,
+        })
+    }
+
+    /// Send `message`, with the preset's retries
+    pub fn send(&mut self, message: &str) -> Result<()> {
+        let preset = &self.preset;
+        match &mut self.open {
+            Open::Direct(session) => {
+                let sealed = seal(preset, &self.transport, message, None)?;
+                retrying(preset, || session.send(&sealed))
+            }
+            Open::Relay { publisher, .. } => {
+                let sealed = seal(preset, &self.transport, message, None)?;
+                retrying(preset, || publisher.publish(&sealed))
+            }
+            Open::Each => send(preset, message),
+        }
+    }
+}
+
+/// Send `message` to every destination at once; results are in the same order
+pub fn send_each(destinations: &[(String, Preset)], message: &str) -> Vec<(String, Result<()>)> {
+    std::thread::scope(|scope| {
+        let sends: Vec<_> = destinations
+            .iter()
+            .map(|(name, preset)| (name, scope.spawn(move || send(preset, message))))
+            .collect();
+        sends
+            .into_iter()
+            .map(|(name, send)| {
+                let result = send
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::Rejected("send panicked".into())));
+                (name.clone(), result)
+            })
+            .collect()
+    })
+}
+
+/// Check the settings a listener can't use with `transport`, and wrap
+/// `handler` in what every listener puts in front of it: the rate limit,
+/// decryption, signatures, the envelope and files sent along
+pub fn listening(
+    preset: &Preset,
+    transport: &Transport,
+    handler: impl FnMut(&Incoming),
+) -> Result<impl FnMut(&Incoming)> {
+    if preset.proxy.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--proxy outside relay mode"));
+    }
+    if preset
+        .topics
+        .as_ref()
+        .is_some_and(|topics| !topics.is_empty())
+        && !matches!(transport, Transport::Relay { .. })
+    {
+        return Err(Error::Unsupported("Several topics outside relay mode"));
+    }
+    if preset.status_topic.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--status-topic outside relay mode"));
+    }
+    if preset.ignore_retained.unwrap_or(false) && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--ignore-retained outside relay mode"));
+    }
+    if preset.qos.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--qos outside relay mode"));
+    }
+    if preset.persistent_session.unwrap_or(false) && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported(
+            "--persistent-session outside relay mode",
+        ));
+    }
+    if preset.client_id.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("--client-id outside relay mode"));
+    }
+    if (preset.keep_alive.is_some()
+        || preset.connect_timeout.is_some()
+        || preset.max_packet_size.is_some())
+        && !matches!(transport, Transport::Relay { .. })
+    {
+        return Err(Error::Unsupported(
+            "--keep-alive, --connect-timeout or --max-packet-size outside relay mode",
+        ));
+    }
+    if preset.on_topic.is_some() && !matches!(transport, Transport::Relay { .. }) {
+        return Err(Error::Unsupported("on_topic outside relay mode"));
+    }
+    if preset.challenge.unwrap_or(false) && !matches!(transport, Transport::Direct { .. }) {
+        return Err(Error::Unsupported("--challenge outside direct mode"));
+    }
+    if (preset.allow.is_some() || preset.deny.is_some())
+        && !matches!(transport, Transport::Direct { .. })
+    {
+        return Err(Error::Unsupported("--allow/--deny outside direct mode"));
+    }
+    if (preset.encrypt.is_some() || preset.verify.is_some())
+        && matches!(transport, Transport::Syslog { .. })
+    {
+        return Err(Error::Unsupported("--encrypt or --verify with syslog"));
+    }
+    if (preset.tls_cert.is_some() || preset.tls_key.is_some() || preset.tls_client_ca.is_some())
+        && matches!(transport, Transport::Http { .. })
+    {
+        return Err(Error::Unsupported(
+            "--tls-cert, --tls-key or --tls-client-ca with --http",
+        ));
+    }
+    Ok(ratelimit::limiting(
+        preset.rate_limit()?,
+        crypto::decrypting(
+            preset.encryption_key()?,
+            signing::verifying(
+                preset.verifying_keys()?,
+                envelope::opening(files::receiving(preset.save_files()?, handler)),
+            ),
+        ),
+    ))
+}
+
+/// Listen on the target described by `preset`, calling `handler` for every
+/// message. With `reply`, `handler` leaves the result of its command in
+/// `Incoming::reply` for senders that wait for one. It logs like `crier
+/// listen` (see [`logging`]) unless the program has set up tracing itself.
+pub fn listen(preset: &Preset, handler: impl FnMut(&Incoming)) -> Result<()> {
+    logging::init(
+        preset.log_format.unwrap_or_default(),
+        preset.log_level.unwrap_or_default(),
+    );
+    let auth = &preset.accepted_tokens();
+    let transport = preset.transport()?;
+    let reply = preset.replies(&transport)?;
+    let handler = listening(preset, &transport, handler)?;
+    match transport {
+        Transport::Relay { broker, port, .. } => {
+            let options = preset.mqtt_options()?;
+            let (broker, port, _tunnel) = preset.mqtt_broker(&broker, port)?;
+            let topics = preset.relay_topics();
+            if preset.mqtt5.unwrap_or(false) {
+                relay::listen_v5(&broker, port, &topics, auth, &options, reply, handler)
+            } else {
+                relay::listen(&broker, port, &topics, auth, &options, handler)
+            }
+        }
+        Transport::Nats { url, subject } => nats::listen(&url, &subject, auth, handler),
+        Transport::Redis { url, channel } => redis::listen(&url, &channel, auth, handler),
+        #[cfg(feature = "amqp")]
+        Transport::Amqp(amqp) => amqp::listen(&amqp, auth, handler),
+        #[cfg(feature = "zmq")]
+        Transport::Zmq { endpoints, topic } => zmq::listen(&endpoints, &topic, auth, handler),
+        Transport::Direct { addr } => direct::serve(
+            preset.direct_listeners(&addr)?.0,
+            auth,
+            preset.auth_mode()?,
+            preset.server_tls()?,
+            &preset.acl()?,
+            reply,
+            preset.direct_limits()?,
+            handler,
+        ),
+        #[cfg(feature = "http")]
+        Transport::Http { addr } => {
+            http::serve(http::bind(&addr)?, auth, preset.webhooks.as_ref(), handler)
+        }
+        #[cfg(not(feature = "http"))]
+        Transport::Http { .. } => Err(Error::Unsupported(
+            "Listening over HTTP without the http feature",
+        )),
+        Transport::Syslog { addr } => {
+            syslog::serve(syslog::bind(&addr)?, &preset.syslog_filter()?, handler)
+        }
+        Transport::Gotify { .. } => Err(Error::Unsupported("Listening on Gotify")),
+        #[cfg(feature = "email")]
+        Transport::Email(_) => Err(Error::Unsupported("Listening for email")),
+        Transport::Fanout(_) => Err(Error::Unsupported("Listening on a fanout preset")),
+    }
+}
+
+/// Run an async client (AMQP, ZeroMQ) on a private single-threaded runtime
+#[cfg(any(feature = "amqp", feature = "zmq"))]
+pub(crate) fn block_on<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future)
+}
@@ -0,0 +1,144 @@
+//! `--log-file`: a listener's output (its log and what its commands print)
+//! appended to a file, which is set aside once it grows too big or too old
+//! (`--log-max-size`, `--log-max-age`), keeping the last few
+//! (`crier.log.1` being the newest)
+
+use crate::{Error, Result};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Set-aside files kept by default
+pub const DEFAULT_KEEP: usize = 5;
+
+/// When to start a new log file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Once the file is this many bytes or more
+    pub max_size: Option<u64>,
+    /// Once the file was started this long ago
+    pub max_age: Option<Duration>,
+    /// How many set-aside files to keep
+    pub keep: usize,
+}
+
+impl Rotation {
+    /// Whether it rotates at all
+    pub fn enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_age.is_some()
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    started: SystemTime,
+}
+
+/// The file stdout and stderr go to, once [`open`]
+static LOG: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Send stdout and stderr to `path` from now on, appending to what is there,
+/// and rotate it as [`check`] finds it due
+#[cfg(unix)]
+pub fn open(path: &Path, rotation: Rotation) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = append(path)
+        .map_err(|e| Error::Config(format!("Cannot open log file {}: {}", path.display(), e)))?;
+    let meta = file.metadata()?;
+    let started = meta
+        .created()
+        .or_else(|_| meta.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    redirect(&file);
+    *LOG.lock().unwrap_or_else(PoisonError::into_inner) = Some(LogFile {
+        path: path.to_path_buf(),
+        rotation,
+        file,
+        started,
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn open(_path: &Path, _rotation: Rotation) -> Result<()> {
+    Err(Error::Unsupported("--log-file on Windows"))
+}
+
+/// Start a new file if the current one is due; the log calls this before
+/// every event it writes
+pub fn check() {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(log) = log.as_mut().filter(|log| log.rotation.enabled()) else {
+        return;
+    };
+    let too_big = log
+        .rotation
+        .max_size
+        .is_some_and(|max| log.file.metadata().is_ok_and(|meta| meta.len() >= max));
+    let too_old = log
+        .rotation
+        .max_age
+        .is_some_and(|max| log.started.elapsed().is_ok_and(|age| age >= max));
+    if !(too_big || too_old) {
+        return;
+    }
+    match log.rotate() {
+        Ok(()) => {}
+        // Still on the old file, and not trying again until the next window;
+        // straight to stderr, as this runs inside the log
+        Err(e) => {
+            log.started = SystemTime::now();
+            eprintln!("Cannot rotate {}: {}", log.path.display(), e);
+        }
+    }
+}
+
+impl LogFile {
+    /// `crier.log.N` becomes `crier.log.N+1` (the oldest going once there are
+    /// `keep`), the file `crier.log.1`, and a new one takes its place
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.rotation.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = fs::remove_file(numbered(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+            self.file = append(&self.path)?;
+        }
+        redirect(&self.file);
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Point stdout and stderr at `file`; commands started from now on write
+/// there too
+fn redirect(file: &File) {
+    #[cfg(unix)]
+    unsafe {
+        use std::os::fd::AsRawFd;
+        libc::dup2(file.as_raw_fd(), 1);
+        libc::dup2(file.as_raw_fd(), 2);
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+}
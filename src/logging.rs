@@ -0,0 +1,129 @@
+//! What a listener logs and how (`--log-format`, `--log-level`): plain lines
+//! as ever, or one JSON object per event for journald, Loki or ELK to take in.
+//! Warnings and errors go to stderr, the rest to stdout.
+
+use crate::logfile;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How each event is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The message alone, a line each
+    #[default]
+    Text,
+    /// One JSON object per line: timestamp, level, message and any fields
+    Json,
+}
+
+/// The least severe events logged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Level {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+        }
+    }
+}
+
+/// Set once JSON logging is on
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Log from now on in `format`, leaving out events below `level`. Only the
+/// first call (or a program's own tracing subscriber) counts.
+pub fn init(format: LogFormat, level: LogLevel) {
+    let stderr = || {
+        logfile::check();
+        std::io::stderr()
+    };
+    let stdout = || {
+        logfile::check();
+        std::io::stdout()
+    };
+    let writer = stderr.with_max_level(Level::WARN).or_else(stdout);
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_max_level(Level::from(level));
+    let installed = match format {
+        LogFormat::Text => builder.event_format(Plain).try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_target(false)
+            .with_current_span(false)
+            .try_init(),
+    };
+    if installed.is_ok() && format == LogFormat::Json {
+        JSON.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether events are logged as JSON, so anything else written to stdout
+/// (like a command's output) has to be logged as events too
+pub fn json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// An empty line after the listener's banner, in text logs only
+pub fn separator() {
+    if !json() && tracing::enabled!(Level::INFO) {
+        println!();
+    }
+}
+
+/// The message alone, as crier has always printed it
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}", message.0)
+    }
+}
+
+/// Picks the message out of an event's fields
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Message formats (`--format`): Markdown and HTML are rendered by the
+//! backends that can (Gotify, email) and turned into plain text everywhere else
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a message is formatted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    /// `text` without its formatting, for backends that can only show plain text
+    pub fn to_plain(self, text: &str) -> String {
+        match self {
+            Format::Plain => text.to_string(),
+            Format::Markdown => markdown_to_plain(text),
+            Format::Html => html_to_plain(text),
+        }
+    }
+
+    /// `text` as HTML, for backends that render it; None for plain text
+    pub fn to_html(self, text: &str) -> Option<String> {
+        match self {
+            Format::Plain => None,
+            Format::Markdown => {
+                let mut html = String::new();
+                pulldown_cmark::html::push_html(
+                    &mut html,
+                    Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH),
+                );
+                Some(html)
+            }
+            Format::Html => Some(text.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Plain => "plain",
+            Format::Markdown => "markdown",
+            Format::Html => "html",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The text of a Markdown document: list items keep a `- `, links their URL
+fn markdown_to_plain(text: &str) -> String {
+    let mut plain = String::new();
+    let mut links = Vec::new();
+    for event in Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Text(text) | Event::Code(text) | Event::InlineHtml(text) => {
+                plain.push_str(&text)
+            }
+            Event::SoftBreak | Event::HardBreak | Event::Rule => plain.push('\n'),
+            Event::Start(Tag::Item) => plain.push_str("- "),
+            Event::Start(Tag::Link { dest_url, .. }) => links.push((plain.len(), dest_url)),
+            Event::End(TagEnd::Link) => {
+                if let Some((start, url)) = links.pop() {
+                    if plain[start..] != *url {
+                        plain.push_str(&format!(" ({})", url));
+                    }
+                }
+            }
+            Event::End(
+                TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::CodeBlock,
+            ) if !plain.ends_with('\n') => plain.push('\n'),
+            _ => {}
+        }
+    }
+    plain.trim_end().to_string()
+}
+
+/// The text of an HTML fragment: tags dropped (block-level ones ending a
+/// line), scripts and styles skipped, common entities decoded
+fn html_to_plain(html: &str) -> String {
+    let mut plain = String::new();
+    let mut rest = html;
+    let mut skipping: Option<String> = None;
+    while let Some(start) = rest.find('<') {
+        if skipping.is_none() {
+            plain.push_str(&decode_entities(&rest[..start]));
+        }
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim().to_ascii_lowercase();
+        rest = &rest[start + end + 1..];
+        let closing = tag.strip_prefix('/');
+        let name = closing
+            .unwrap_or(&tag)
+            .split([' ', '/'])
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        match (&skipping, closing.is_some()) {
+            (Some(skipped), true) if *skipped == name => skipping = None,
+            (Some(_), _) => {}
+            (None, false) if name == "script" || name == "style" => skipping = Some(name),
+            (None, _) => {
+                let block = matches!(
+                    name.as_str(),
+                    "br" | "p"
+                        | "div"
+                        | "li"
+                        | "tr"
+                        | "h1"
+                        | "h2"
+                        | "h3"
+                        | "h4"
+                        | "h5"
+                        | "h6"
+                        | "pre"
+                        | "blockquote"
+                );
+                if block && !plain.is_empty() && !plain.ends_with('\n') {
+                    plain.push('\n');
+                }
+                if name == "li" && closing.is_none() {
+                    plain.push_str("- ");
+                }
+            }
+        }
+    }
+    if skipping.is_none() {
+        plain.push_str(&decode_entities(rest));
+    }
+    plain.trim().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 8)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
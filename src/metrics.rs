@@ -0,0 +1,139 @@
+//! A listener's Prometheus metrics, served at `/metrics` on the `--health`
+//! address
+// Still counted without the http feature, but nothing serves them
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+
+use crate::health;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// What `/metrics` is served as (Prometheus' text format)
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the command duration buckets, in seconds
+const BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Messages refused for a wrong or missing token
+static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+/// Commands run, and how long they took
+static COMMANDS: Mutex<Commands> = Mutex::new(Commands {
+    succeeded: 0,
+    failed: 0,
+    buckets: [0; BUCKETS.len()],
+    seconds: 0.0,
+});
+
+struct Commands {
+    succeeded: u64,
+    failed: u64,
+    /// Commands that took at most each of [`BUCKETS`]
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+}
+
+/// Note a message refused for its token
+pub fn auth_failed() {
+    AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note a command run for a message ending with `code` after `elapsed`
+pub fn command_ran(elapsed: Duration, code: i32) {
+    let mut commands = COMMANDS.lock().unwrap_or_else(PoisonError::into_inner);
+    if code == 0 {
+        commands.succeeded += 1;
+    } else {
+        commands.failed += 1;
+    }
+    let seconds = elapsed.as_secs_f64();
+    for (bound, count) in BUCKETS.iter().zip(&mut commands.buckets) {
+        if seconds <= *bound {
+            *count += 1;
+        }
+    }
+    commands.seconds += seconds;
+}
+
+/// Everything, in Prometheus' text format
+pub(crate) fn render() -> String {
+    let now = health::snapshot();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+        let _ = writeln!(
+            out,
+            "# HELP {} {}\n# TYPE {} {}\n{} {}",
+            name, help, name, kind, name, value
+        );
+    };
+    metric(
+        "crier_messages_received_total",
+        "counter",
+        "Messages received",
+        &now.received,
+    );
+    metric(
+        "crier_auth_failures_total",
+        "counter",
+        "Messages refused for a wrong or missing token",
+        &AUTH_FAILURES.load(Ordering::Relaxed),
+    );
+    if let Some(last) = now.last_message {
+        let seconds = last
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        metric(
+            "crier_last_message_timestamp_seconds",
+            "gauge",
+            "When the last message came in",
+            &seconds,
+        );
+    }
+    metric(
+        "crier_queued_commands",
+        "gauge",
+        "Commands waiting in the command queue, or messages collected for a digest",
+        &now.queued,
+    );
+    if let Some(connected) = now.broker {
+        metric(
+            "crier_broker_connected",
+            "gauge",
+            "Whether the MQTT broker is connected",
+            &u8::from(connected),
+        );
+        metric(
+            "crier_broker_reconnects_total",
+            "counter",
+            "Times the broker connection came back after a drop",
+            &now.reconnects,
+        );
+    }
+    let commands = COMMANDS.lock().unwrap_or_else(PoisonError::into_inner);
+    let name = "crier_commands_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Commands run for messages, by how they ended\n# TYPE {} counter",
+        name, name
+    );
+    let _ = writeln!(out, "{}{{result=\"success\"}} {}", name, commands.succeeded);
+    let _ = writeln!(out, "{}{{result=\"failure\"}} {}", name, commands.failed);
+    let name = "crier_command_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} How long commands took\n# TYPE {} histogram",
+        name, name
+    );
+    for (bound, within) in BUCKETS.iter().zip(commands.buckets) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, within);
+    }
+    let count = commands.succeeded + commands.failed;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(
+        out,
+        "{}_sum {:.3}\n{}_count {}",
+        name, commands.seconds, name, count
+    );
+    out
+}
@@ -0,0 +1,69 @@
+//! Nagios/Icinga/Checkmk notification-command support.
+//!
+//! Monitoring systems export their notification macros as environment
+//! variables (`NAGIOS_HOSTNAME`, `ICINGA_SERVICESTATE`, `NOTIFY_HOSTOUTPUT`, ...);
+//! this turns them into a host or service alert message.
+
+use crate::{template, Error, Result};
+use std::env;
+
+/// Prefixes used by Nagios, Icinga 1.x and Checkmk, in lookup order
+const PREFIXES: [&str; 3] = ["NAGIOS_", "ICINGA_", "NOTIFY_"];
+
+const HOST_TEMPLATE: &str = "{type}: host {host} is {state} - {output}";
+const SERVICE_TEMPLATE: &str = "{type}: {service} on {host} is {state} - {output}";
+
+/// Template field -> macro name (without prefix); state/output depend on host vs service
+const FIELDS: [(&str, &str); 7] = [
+    ("type", "NOTIFICATIONTYPE"),
+    ("host", "HOSTNAME"),
+    ("address", "HOSTADDRESS"),
+    ("service", "SERVICEDESC"),
+    ("datetime", "LONGDATETIME"),
+    ("author", "NOTIFICATIONAUTHOR"),
+    ("comment", "NOTIFICATIONCOMMENT"),
+];
+
+/// Build the alert message from the environment. `template` (the `-m` value)
+/// may use `{type}`, `{host}`, `{address}`, `{service}`, `{state}`,
+/// `{output}`, `{datetime}`, `{author}` and `{comment}`.
+pub fn message(template: Option<&str>) -> Result<String> {
+    if macro_value("HOSTNAME").is_none() {
+        return Err(Error::Config(
+            "--nagios: no NAGIOS_*, ICINGA_* or NOTIFY_* notification variables in the environment"
+                .into(),
+        ));
+    }
+
+    let is_service = match macro_value("WHAT") {
+        Some(what) => what == "SERVICE",
+        None => macro_value("SERVICEDESC").is_some(),
+    };
+    let (state, output) = if is_service {
+        ("SERVICESTATE", "SERVICEOUTPUT")
+    } else {
+        ("HOSTSTATE", "HOSTOUTPUT")
+    };
+
+    let default = if is_service {
+        SERVICE_TEMPLATE
+    } else {
+        HOST_TEMPLATE
+    };
+    let rendered = template::render(template.unwrap_or(default), |name| {
+        let macro_name = match name {
+            "state" => state,
+            "output" => output,
+            _ => FIELDS.iter().find(|(field, _)| *field == name)?.1,
+        };
+        Some(macro_value(macro_name).unwrap_or_default())
+    });
+    Ok(rendered)
+}
+
+fn macro_value(name: &str) -> Option<String> {
+    PREFIXES
+        .iter()
+        .find_map(|prefix| env::var(format!("{}{}", prefix, name)).ok())
+        .filter(|v| !v.is_empty())
+}
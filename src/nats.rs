@@ -0,0 +1,173 @@
+//! NATS relay: core NATS text protocol (PUB/SUB), no JetStream
+
+use crate::auth::Tokens;
+use crate::{relay, Error, Incoming, Result};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::warn;
+
+/// Default NATS server port
+pub const DEFAULT_PORT: u16 = 4222;
+
+/// Subscribe to `subject` and call `handler` for every authenticated message
+pub fn listen(
+    url: &str,
+    subject: &str,
+    auth: &Tokens,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let mut conn = Connection::open(url, "crier-listener")?;
+    conn.write(&format!("SUB {} 1\r\n", subject))?;
+    conn.ping()?;
+
+    loop {
+        let line = conn.read_line()?;
+        if line == "PING" {
+            conn.write("PONG\r\n")?;
+        } else if let Some(err) = line.strip_prefix("-ERR ") {
+            return Err(Error::Rejected(err.trim_matches('\'').to_string()));
+        } else if let Some(header) = line.strip_prefix("MSG ") {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let fields: Vec<&str> = header.split(' ').collect();
+            let len = fields
+                .last()
+                .and_then(|n| n.parse::<usize>().ok())
+                .unwrap_or(0);
+            let payload = conn.read_payload(len)?;
+            let payload = String::from_utf8_lossy(&payload);
+            match relay::open(&payload, auth, fields[0].to_string()) {
+                Some(incoming) => handler(&incoming),
+                None => warn!("Auth failed, ignoring message"),
+            }
+        }
+    }
+}
+
+/// Publish one message and wait for the server to confirm it was processed
+pub fn send(url: &str, subject: &str, message: &str, auth: Option<&str>) -> Result<()> {
+    let mut conn = Connection::open(url, "crier-sender")?;
+    let payload = relay::seal(message, auth);
+    conn.write(&format!(
+        "PUB {} {}\r\n{}\r\n",
+        subject,
+        payload.len(),
+        payload
+    ))?;
+    conn.ping()
+}
+
+/// `url` without credentials, for display
+pub fn server(url: &str) -> &str {
+    let rest = url.strip_prefix("nats://").unwrap_or(url);
+    rest.rsplit_once('@').map_or(rest, |(_, host)| host)
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Connect to `nats://[user:password@|token@]host[:port]` and log in
+    fn open(url: &str, name: &str) -> Result<Connection> {
+        let rest = url.strip_prefix("nats://").unwrap_or(url);
+        let credentials = rest.rsplit_once('@').map(|(credentials, _)| credentials);
+        let host = server(url).trim_end_matches('/');
+        let addr = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+        {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        };
+
+        let stream = TcpStream::connect(&addr).map_err(|source| Error::Connect {
+            addr: addr.clone(),
+            source,
+        })?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let info = conn.read_line()?;
+        let Some(info) = info.strip_prefix("INFO ") else {
+            return Err(Error::Rejected(format!("{} is not a NATS server", addr)));
+        };
+        let info: Value = serde_json::from_str(info).unwrap_or_default();
+        if info["tls_required"].as_bool() == Some(true) {
+            return Err(Error::Unsupported("NATS over TLS"));
+        }
+
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": name,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+        });
+        match credentials.map(|c| c.split_once(':')) {
+            Some(Some((user, pass))) => {
+                options["user"] = json!(user);
+                options["pass"] = json!(pass);
+            }
+            Some(None) => options["auth_token"] = json!(credentials),
+            None => {}
+        }
+        conn.write(&format!("CONNECT {}\r\n", options))?;
+        conn.ping()?;
+        conn.writer.set_read_timeout(None)?;
+        Ok(conn)
+    }
+
+    /// Round-trip a PING; any error the server reported first is returned
+    fn ping(&mut self) -> Result<()> {
+        self.write("PING\r\n")?;
+        loop {
+            let line = self.read_line()?;
+            if line == "PONG" {
+                return Ok(());
+            }
+            if let Some(err) = line.strip_prefix("-ERR ") {
+                return Err(Error::Rejected(err.trim_matches('\'').to_string()));
+            }
+        }
+    }
+
+    fn write(&mut self, data: &str) -> Result<()> {
+        self.writer.write_all(data.as_bytes())?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(closed()),
+            Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(Error::Timeout)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Payload of `len` bytes plus its trailing CRLF
+    fn read_payload(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut payload = vec![0; len + 2];
+        self.reader.read_exact(&mut payload)?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+}
+
+fn closed() -> Error {
+    Error::Io(io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "NATS server closed the connection",
+    ))
+}
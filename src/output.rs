@@ -0,0 +1,124 @@
+//! Writing received messages to a file or named pipe (`--output`) for other
+//! programs to read
+
+use crate::{history, Error, Incoming, Result};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+/// How each message is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The message text and a newline
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of a history entry
+    Json,
+}
+
+/// Where a listener writes messages; a named pipe is (re)opened whenever a
+/// reader is there, and messages nobody is reading are dropped
+pub struct Output {
+    path: PathBuf,
+    format: OutputFormat,
+    file: Option<File>,
+}
+
+impl Output {
+    /// Open `path` for appending (creating a regular file if need be). A
+    /// named pipe without a reader is fine: it is tried again per message.
+    pub fn open(path: &Path, format: OutputFormat) -> Result<Output> {
+        let mut output = Output {
+            path: path.to_path_buf(),
+            format,
+            file: None,
+        };
+        match output.connect() {
+            Ok(()) => Ok(output),
+            Err(_) if output.is_pipe() => Ok(output),
+            Err(e) => Err(Error::Config(format!(
+                "Cannot open output {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Whether `path` is a named pipe
+    fn is_pipe(&self) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            std::fs::metadata(&self.path).is_ok_and(|meta| meta.file_type().is_fifo())
+        }
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// Write `incoming`; failures are logged and the message dropped
+    pub fn write(&mut self, incoming: &Incoming) {
+        let line = match self.format {
+            OutputFormat::Text => format!("{}\n", incoming.text()),
+            OutputFormat::Json => match serde_json::to_string(&history::Entry::new(incoming)) {
+                Ok(json) => format!("{}\n", json),
+                Err(e) => {
+                    error!("Cannot write to {}: {}", self.path.display(), e);
+                    return;
+                }
+            },
+        };
+        if self.file.is_none() {
+            if let Err(e) = self.connect() {
+                match e.raw_os_error() {
+                    #[cfg(unix)]
+                    Some(libc::ENXIO) => error!(
+                        "Nobody is reading {}, dropping the message",
+                        self.path.display()
+                    ),
+                    _ => error!("Cannot open output {}: {}", self.path.display(), e),
+                }
+                return;
+            }
+        }
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                if e.kind() == std::io::ErrorKind::BrokenPipe {
+                    warn!(
+                        "Nobody is reading {} any more, dropping the message",
+                        self.path.display()
+                    );
+                } else {
+                    error!("Cannot write to {}: {}", self.path.display(), e);
+                }
+                // Open it again next time, say for a pipe's next reader
+                self.file = None;
+            }
+        }
+    }
+
+    /// Open the file, or a named pipe's writing end if it has a reader
+    /// (without waiting for one)
+    fn connect(&mut self) -> std::io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.append(true);
+        #[cfg(unix)]
+        if self.is_pipe() {
+            use std::os::fd::AsRawFd;
+            use std::os::unix::fs::OpenOptionsExt;
+
+            let file = options.custom_flags(libc::O_NONBLOCK).open(&self.path)?;
+            // Blocking writes from here on, so a slow reader loses nothing
+            unsafe {
+                let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+                libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK);
+            }
+            self.file = Some(file);
+            return Ok(());
+        }
+        self.file = Some(options.create(true).open(&self.path)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How urgent a message is
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    /// Map a monitoring severity label (critical, warning, info, ...) to a priority
+    pub fn from_severity(severity: &str) -> Priority {
+        match severity.to_ascii_lowercase().as_str() {
+            "critical" | "emergency" | "alert" | "page" | "urgent" => Priority::Urgent,
+            "error" | "high" | "major" => Priority::High,
+            "info" | "informational" | "low" | "minor" | "none" => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        };
+        f.write_str(name)
+    }
+}
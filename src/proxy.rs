@@ -0,0 +1,335 @@
+//! Outbound proxies (SOCKS5, HTTP CONNECT) for direct sends and MQTT relay mode
+
+use crate::{Error, Result};
+use base64::Engine;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// A proxy given as `socks5://`, `socks5h://` or `http://[user:password@]host:port`
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    kind: Kind,
+    /// host:port of the proxy itself
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// `remote_dns`: let the proxy resolve the target (socks5h)
+    Socks5 {
+        remote_dns: bool,
+    },
+    Http,
+}
+
+impl Proxy {
+    pub fn parse(url: &str) -> Result<Proxy> {
+        let invalid = |why: &str| Error::Config(format!("Invalid proxy '{}': {}", url, why));
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        let kind = match scheme {
+            "socks5" => Kind::Socks5 { remote_dns: false },
+            "socks5h" => Kind::Socks5 { remote_dns: true },
+            "http" => Kind::Http,
+            _ => return Err(invalid("use socks5://, socks5h:// or http://")),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((credentials, addr)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((user.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        if addr
+            .rsplit_once(':')
+            .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+        {
+            return Err(invalid("missing port"));
+        }
+        Ok(Proxy {
+            kind,
+            addr: addr.to_string(),
+            credentials,
+        })
+    }
+
+    /// The proxy URL without credentials, for display
+    pub fn server(&self) -> String {
+        let scheme = match self.kind {
+            Kind::Socks5 { remote_dns: false } => "socks5",
+            Kind::Socks5 { remote_dns: true } => "socks5h",
+            Kind::Http => "http",
+        };
+        format!("{}://{}", scheme, self.addr)
+    }
+
+    /// Open a TCP connection to `target` (host:port) through the proxy,
+    /// giving connecting to the proxy, and its answer, up to `timeout` each
+    /// (default: the system's, then 10s)
+    pub fn connect(&self, target: &str, timeout: Option<Duration>) -> Result<TcpStream> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| {
+                Some((host.trim_matches(['[', ']']), port.parse::<u16>().ok()?))
+            })
+            .ok_or_else(|| Error::Config(format!("Invalid address '{}'", target)))?;
+
+        let mut stream =
+            crate::direct::dial(&self.addr, timeout).map_err(|source| Error::Connect {
+                addr: self.addr.clone(),
+                source,
+            })?;
+        stream.set_read_timeout(Some(timeout.unwrap_or(Duration::from_secs(10))))?;
+        let result = match self.kind {
+            Kind::Socks5 { remote_dns } => self.socks5(&mut stream, host, port, remote_dns),
+            Kind::Http => self.http_connect(&mut stream, host, port),
+        };
+        result.map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Connect {
+                addr: format!("{} via {}", target, self.server()),
+                source: e,
+            },
+        })?;
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Forward a local port to `host:port` through the proxy, for clients that
+    /// open their own connections (MQTT)
+    pub fn tunnel(&self, host: &str, port: u16) -> Result<Tunnel> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let local = listener.local_addr()?;
+        let uploads = Arc::new((Mutex::new(0), Condvar::new()));
+        let tunnel = Tunnel {
+            local,
+            uploads: uploads.clone(),
+        };
+        let proxy = self.clone();
+        let target = format!("{}:{}", host, port);
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                match proxy.connect(&target, None) {
+                    Ok(upstream) => {
+                        if let Err(e) = pipe(client, upstream, uploads.clone()) {
+                            warn!("Proxy tunnel error: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Proxy: {}", e),
+                }
+            }
+        });
+        Ok(tunnel)
+    }
+
+    fn socks5(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+        remote_dns: bool,
+    ) -> io::Result<()> {
+        // Greeting: offer username/password only when we have credentials
+        let methods: &[u8] = if self.credentials.is_some() {
+            &[0x00, 0x02]
+        } else {
+            &[0x00]
+        };
+        stream.write_all(&[&[0x05, methods.len() as u8], methods].concat())?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        match (reply, &self.credentials) {
+            ([0x05, 0x00], _) => {}
+            ([0x05, 0x02], Some((user, password))) => {
+                let mut auth = vec![0x01, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth)?;
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0x00 {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "SOCKS5 proxy rejected the credentials",
+                    ));
+                }
+            }
+            ([0x05, 0xff], None) => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy requires a username and password",
+                ))
+            }
+            _ => {
+                return Err(io::Error::other(
+                    "SOCKS5 proxy requires an unsupported authentication method",
+                ))
+            }
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        let ip = match host.parse::<IpAddr>() {
+            Ok(ip) => Some(ip),
+            Err(_) if remote_dns => None,
+            Err(_) => Some(
+                (host, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| {
+                        io::Error::new(ErrorKind::NotFound, format!("Cannot resolve {}", host))
+                    })?
+                    .ip(),
+            ),
+        };
+        match ip {
+            Some(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Some(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            None => {
+                request.extend_from_slice(&[0x03, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        // VER REP RSV ATYP, then the bound address and port
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        if header[1] != 0x00 {
+            return Err(socks5_error(header[1]));
+        }
+        let len = match header[3] {
+            0x01 => 4,
+            0x04 => 16,
+            _ => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+        };
+        stream.read_exact(&mut vec![0; len + 2])
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((user, password)) = &self.credentials {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read the response head a byte at a time so no tunnelled data is consumed
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "HTTP proxy closed the connection",
+                ));
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "HTTP proxy answered '{}'",
+                status
+            ))),
+        }
+    }
+}
+
+fn socks5_error(code: u8) -> io::Error {
+    let (kind, message) = match code {
+        0x02 => (
+            ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        0x03 => (ErrorKind::Other, "network unreachable"),
+        0x04 => (ErrorKind::Other, "host unreachable"),
+        0x05 => (ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (ErrorKind::Other, "TTL expired"),
+        0x07 => (ErrorKind::Unsupported, "command not supported"),
+        0x08 => (ErrorKind::Unsupported, "address type not supported"),
+        _ => (ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {}", message))
+}
+
+/// A local port forwarded through a proxy. Dropping it waits (briefly) until
+/// everything the local client sent has been passed on, so a sender that
+/// exits right after publishing does not lose its message.
+pub struct Tunnel {
+    local: SocketAddr,
+    /// Connections still copying client -> upstream
+    uploads: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Tunnel {
+    pub fn host(&self) -> String {
+        self.local.ip().to_string()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.local.port()
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let (count, done) = &*self.uploads;
+        if let Ok(count) = count.lock() {
+            let _ = done.wait_timeout_while(count, Duration::from_secs(5), |n| *n > 0);
+        }
+    }
+}
+
+/// Copy both ways until either side closes
+fn pipe(
+    client: TcpStream,
+    upstream: TcpStream,
+    uploads: Arc<(Mutex<usize>, Condvar)>,
+) -> io::Result<()> {
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    if let Ok(mut n) = uploads.0.lock() {
+        *n += 1;
+    }
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Both);
+        let (count, done) = &*uploads;
+        if let Ok(mut n) = count.lock() {
+            *n -= 1;
+        }
+        done.notify_all();
+    });
+    thread::spawn(move || {
+        let _ = io::copy(&mut upstream_read, &mut client_write);
+        let _ = client_write.shutdown(Shutdown::Both);
+    });
+    Ok(())
+}
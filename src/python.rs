@@ -0,0 +1,107 @@
+//! Python module, built with `maturin build --features python`.
+//!
+//! ```python
+//! import crier
+//! crier.send("Build complete!", preset="mybuilds")
+//! crier.listen(lambda message, source: print(source, message), "0.0.0.0:5555")
+//! ```
+
+use crate::{config, Incoming, Preset};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+create_exception!(
+    crier,
+    CrierError,
+    PyException,
+    "Raised when sending or listening fails"
+);
+
+/// Send `message` to `addr` (direct mode) or the target described by the
+/// preset/relay arguments. Keyword arguments override the preset.
+#[pyfunction]
+#[pyo3(signature = (
+    message, addr=None, *, preset=None, config=None, relay=None, port=None, topic=None, auth=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn send(
+    py: Python<'_>,
+    message: String,
+    addr: Option<String>,
+    preset: Option<String>,
+    config: Option<PathBuf>,
+    relay: Option<String>,
+    port: Option<u16>,
+    topic: Option<String>,
+    auth: Option<String>,
+) -> PyResult<()> {
+    let given = Preset {
+        addr,
+        relay,
+        port,
+        topic,
+        auth,
+        ..Preset::default()
+    };
+    let p = resolve(given, preset, config)?;
+    py.detach(|| crate::send(&p, &message)).map_err(to_py)
+}
+
+/// Listen on `addr` (direct mode) or the target described by the
+/// preset/relay arguments, calling `callback(message, source)` for every
+/// message. Blocks until the listener fails.
+#[pyfunction]
+#[pyo3(signature = (
+    callback, addr=None, *, preset=None, config=None, relay=None, port=None, topic=None, auth=None
+))]
+#[allow(clippy::too_many_arguments)]
+fn listen(
+    py: Python<'_>,
+    callback: Py<PyAny>,
+    addr: Option<String>,
+    preset: Option<String>,
+    config: Option<PathBuf>,
+    relay: Option<String>,
+    port: Option<u16>,
+    topic: Option<String>,
+    auth: Option<String>,
+) -> PyResult<()> {
+    let given = Preset {
+        addr,
+        relay,
+        port,
+        topic,
+        auth,
+        ..Preset::default()
+    };
+    let p = resolve(given, preset, config)?;
+    py.detach(|| {
+        crate::listen(&p, |incoming: &Incoming| {
+            Python::attach(|py| {
+                // A failing callback must not take the listener down
+                if let Err(e) = callback.call1(py, (&incoming.message, &incoming.source)) {
+                    e.print(py);
+                }
+            })
+        })
+    })
+    .map_err(to_py)
+}
+
+fn resolve(given: Preset, preset: Option<String>, config: Option<PathBuf>) -> PyResult<Preset> {
+    config::resolve(given, preset.as_deref(), config.as_ref()).map_err(to_py)
+}
+
+fn to_py(e: crate::Error) -> PyErr {
+    CrierError::new_err(e.to_string())
+}
+
+#[pymodule]
+fn crier(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("CrierError", m.py().get_type::<CrierError>())?;
+    m.add_function(wrap_pyfunction!(send, m)?)?;
+    m.add_function(wrap_pyfunction!(listen, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Quiet hours (`quiet_hours: "22:00-07:00"`): a time of day during which a
+//! listener only logs messages instead of acting on them
+
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// A daily window in local time, from `start` up to (not including) `end`,
+/// in minutes since midnight; it runs past midnight when `end` <= `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    /// Whether it is quiet at `minute` (since midnight)
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Whether it is quiet now
+    pub fn now(&self) -> bool {
+        self.contains(local_seconds() / 60)
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Parse `HH:MM-HH:MM` (for clap's `value_parser`)
+pub fn parse(text: &str) -> Result<QuietHours, String> {
+    let invalid = || format!("'{}' is not a time range (e.g. 22:00-07:00)", text.trim());
+    let (start, end) = text.trim().split_once('-').ok_or_else(invalid)?;
+    let minute = |time: &str| -> Option<u32> {
+        let (hours, minutes) = time.trim().split_once(':')?;
+        let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    };
+    let (start, end) = (
+        minute(start).ok_or_else(invalid)?,
+        minute(end).ok_or_else(invalid)?,
+    );
+    if start == end {
+        return Err(format!(
+            "'{}' starts and ends at the same time",
+            text.trim()
+        ));
+    }
+    Ok(QuietHours { start, end })
+}
+
+/// Deserialize optional quiet hours from `HH:MM-HH:MM`
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<QuietHours>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(text) => parse(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Seconds since midnight, local time
+#[cfg(unix)]
+pub(crate) fn local_seconds() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+    }
+}
+
+/// Seconds since midnight, local time
+#[cfg(windows)]
+pub(crate) fn local_seconds() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_s(&mut tm, &now);
+        (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+    }
+}
@@ -0,0 +1,143 @@
+//! Per-sender rate limiting for listeners, with temporary bans for senders
+//! that go over the limit. Senders are told apart by [`Incoming::identity`],
+//! which only the listener vouches for.
+
+use crate::Incoming;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// Forget idle senders once this many are tracked
+const MAX_TRACKED: usize = 1024;
+
+/// At most `per_minute` messages from one sender in any 60 seconds; a sender
+/// that sends more is ignored for `ban`
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub ban: Duration,
+}
+
+#[derive(Default)]
+struct Sender {
+    recent: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Sender {
+    fn active(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+            || self
+                .recent
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < WINDOW)
+    }
+}
+
+/// Wrap `handler` so that, with a limit, messages from senders over it (or
+/// banned for going over it) are dropped
+pub fn limiting(limit: Option<Limit>, mut handler: impl FnMut(&Incoming)) -> impl FnMut(&Incoming) {
+    let mut senders: HashMap<String, Sender> = HashMap::new();
+    move |incoming: &Incoming| {
+        let Some(limit) = limit else {
+            return handler(incoming);
+        };
+        let now = Instant::now();
+        if senders.len() >= MAX_TRACKED {
+            senders.retain(|_, sender| sender.active(now));
+        }
+        let name = incoming.identity();
+        let sender = senders.entry(name.clone()).or_default();
+        if sender.banned_until.is_some_and(|until| until > now) {
+            return;
+        }
+        while sender
+            .recent
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= WINDOW)
+        {
+            sender.recent.pop_front();
+        }
+        if sender.recent.len() >= limit.per_minute as usize {
+            sender.recent.clear();
+            sender.banned_until = Some(now + limit.ban);
+            warn!(
+                "[{}] Over {} messages per minute, ignoring {} for {}s",
+                incoming.source,
+                limit.per_minute,
+                name,
+                limit.ban.as_secs()
+            );
+            return;
+        }
+        sender.banned_until = None;
+        sender.recent.push_back(now);
+        handler(incoming);
+    }
+}
+
+/// Limits on how often the listener acts (runs its command, shows a
+/// notification) whoever sends: at most once per `cooldown` and at most
+/// `per_minute` times in any 60 seconds. Messages over them are only logged.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    cooldown: Option<Duration>,
+    per_minute: Option<u32>,
+    recent: VecDeque<Instant>,
+    /// Messages not acted on since the last one that was
+    skipped: u32,
+}
+
+impl Throttle {
+    pub fn new(cooldown: Option<Duration>, per_minute: Option<u32>) -> Self {
+        Throttle {
+            cooldown,
+            per_minute,
+            ..Throttle::default()
+        }
+    }
+
+    /// Whether to act on a message now; if so, it counts against the limits
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        let last = self.recent.back().map(|last| now.duration_since(*last));
+        if let Some((cooldown, last)) = self
+            .cooldown
+            .zip(last)
+            .filter(|(cooldown, last)| last < cooldown)
+        {
+            self.skipped += 1;
+            warn!(
+                "Cooling down, not acting on it ({}s left)",
+                (cooldown - last).as_secs_f32().ceil()
+            );
+            return false;
+        }
+        if let Some(per_minute) = self
+            .per_minute
+            .filter(|per_minute| self.recent.len() >= *per_minute as usize)
+        {
+            self.skipped += 1;
+            warn!("Over {} runs per minute, not acting on it", per_minute);
+            return false;
+        }
+        if self.skipped > 0 {
+            warn!(
+                "Acting again after skipping {} message{}",
+                self.skipped,
+                if self.skipped == 1 { "" } else { "s" }
+            );
+            self.skipped = 0;
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
@@ -0,0 +1,192 @@
+//! Redis relay: PUBLISH / (P)SUBSCRIBE over RESP
+
+use crate::auth::Tokens;
+use crate::{relay, Error, Incoming, Result};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::warn;
+
+/// Default Redis port
+pub const DEFAULT_PORT: u16 = 6379;
+
+/// Subscribe to `channel` (a pattern if it contains `*`, `?` or `[`) and call
+/// `handler` for every authenticated message
+pub fn listen(
+    url: &str,
+    channel: &str,
+    auth: &Tokens,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let mut conn = Connection::open(url)?;
+    let pattern = channel.contains(['*', '?', '[']);
+    conn.command(&[if pattern { "PSUBSCRIBE" } else { "SUBSCRIBE" }, channel])?;
+    conn.set_timeout(None)?;
+
+    loop {
+        let Reply::Array(Some(items)) = conn.read()? else {
+            continue;
+        };
+        // ["message", channel, payload] or ["pmessage", pattern, channel, payload]
+        let (channel, payload) = match (items.first().and_then(Reply::text).as_deref(), items.len())
+        {
+            (Some("message"), 3) => (&items[1], &items[2]),
+            (Some("pmessage"), 4) => (&items[2], &items[3]),
+            _ => continue,
+        };
+        let (Some(channel), Some(payload)) = (channel.text(), payload.text()) else {
+            continue;
+        };
+        match relay::open(&payload, auth, channel) {
+            Some(incoming) => handler(&incoming),
+            None => warn!("Auth failed, ignoring message"),
+        }
+    }
+}
+
+/// Publish one message to `channel`
+pub fn send(url: &str, channel: &str, message: &str, auth: Option<&str>) -> Result<()> {
+    let mut conn = Connection::open(url)?;
+    conn.command(&["PUBLISH", channel, &relay::seal(message, auth)])?;
+    Ok(())
+}
+
+/// `url` without credentials, for display
+pub fn server(url: &str) -> &str {
+    let rest = url.strip_prefix("redis://").unwrap_or(url);
+    rest.rsplit_once('@').map_or(rest, |(_, host)| host)
+}
+
+/// A RESP value
+#[derive(Debug)]
+enum Reply {
+    Status(String),
+    Error(String),
+    /// Integer reply (e.g. PUBLISH's receiver count); the value is not needed
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn text(&self) -> Option<String> {
+        match self {
+            Reply::Status(s) => Some(s.clone()),
+            Reply::Bulk(Some(b)) => Some(String::from_utf8_lossy(b).into_owned()),
+            _ => None,
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Connect to `redis://[[user]:password@]host[:port]` and log in
+    fn open(url: &str) -> Result<Connection> {
+        if url.starts_with("rediss://") {
+            return Err(Error::Unsupported("Redis over TLS"));
+        }
+        let rest = url.strip_prefix("redis://").unwrap_or(url);
+        let credentials = rest.rsplit_once('@').map(|(credentials, _)| credentials);
+        // A trailing /<db> does not matter for pub/sub
+        let host = server(url).split('/').next().unwrap_or_default();
+        let addr = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+        {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        };
+
+        let stream = TcpStream::connect(&addr).map_err(|source| Error::Connect {
+            addr: addr.clone(),
+            source,
+        })?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        conn.set_timeout(Some(Duration::from_secs(5)))?;
+
+        if let Some(credentials) = credentials {
+            let auth = match credentials.split_once(':') {
+                Some(("", password)) => vec!["AUTH", password],
+                Some((user, password)) => vec!["AUTH", user, password],
+                None => vec!["AUTH", credentials],
+            };
+            conn.command(&auth)?;
+        }
+        Ok(conn)
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Send a command and read its reply; error replies become `Rejected`
+    fn command(&mut self, args: &[&str]) -> Result<Reply> {
+        let mut out = format!("*{}\r\n", args.len());
+        for arg in args {
+            out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(out.as_bytes())?;
+        match self.read()? {
+            Reply::Error(e) => Err(Error::Rejected(e)),
+            reply => Ok(reply),
+        }
+    }
+
+    fn read(&mut self) -> Result<Reply> {
+        let line = self.read_line()?;
+        let (kind, rest) = line.split_at(line.len().min(1));
+        let number = || rest.parse::<i64>().map_err(|_| protocol_error(&line));
+        Ok(match kind {
+            "+" => Reply::Status(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => {
+                number()?;
+                Reply::Integer
+            }
+            "$" => match usize::try_from(number()?) {
+                Ok(len) => {
+                    let mut data = vec![0; len + 2];
+                    self.reader.read_exact(&mut data)?;
+                    data.truncate(len);
+                    Reply::Bulk(Some(data))
+                }
+                Err(_) => Reply::Bulk(None),
+            },
+            "*" => match usize::try_from(number()?) {
+                Ok(len) => {
+                    Reply::Array(Some((0..len).map(|_| self.read()).collect::<Result<_>>()?))
+                }
+                Err(_) => Reply::Array(None),
+            },
+            _ => return Err(protocol_error(&line)),
+        })
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err(Error::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Redis server closed the connection",
+            ))),
+            Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(Error::Timeout)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn protocol_error(line: &str) -> Error {
+    Error::Rejected(format!("Unexpected reply from Redis: {}", line))
+}
@@ -0,0 +1,1249 @@
+use crate::auth::Tokens;
+use crate::exec::Reply;
+use crate::{
+    cron, health, metrics, shutdown, tls, Error, Format, Incoming, Priority, ReceiptSlot,
+    ReplySlot, Result,
+};
+use rumqttc::v5::mqttbytes::v5::{
+    Filter, LastWill as LastWill5, Packet as Packet5, PublishProperties,
+};
+use rumqttc::v5::{
+    Client as Client5, Event as Event5, MqttOptions as MqttOptions5, RecvTimeoutError,
+};
+use rumqttc::{
+    Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, SubscribeFilter,
+    TlsConfiguration, Transport,
+};
+use rustls::ClientConfig;
+use std::fmt::Display;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Largest MQTT packet sent or accepted by default; room for a file chunk
+/// (see `files`) rather than rumqttc's default 10 KiB
+pub(crate) const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// How often listeners and senders ping an otherwise quiet broker by default
+pub(crate) const LISTENER_KEEP_ALIVE: Duration = Duration::from_secs(60);
+const SENDER_KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// How long connecting to the broker may take by default
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// User property with the command's exit code on a reply (see [`request_v5`]);
+/// a reply without one means the listener ran nothing for the message
+const EXIT_PROPERTY: &str = "exit";
+
+/// What a listener's status topic says while it is connected, and once it isn't
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// What a listener acknowledges a message with on its receipt topic (see
+/// [`receipt_topic`])
+const RECEIVED: &str = "received";
+
+/// How long a sender waits for the listener's status once subscribed to it
+const STATUS_WAIT: Duration = Duration::from_secs(1);
+
+/// MQTT v5 session expiry interval meaning the session never expires
+const NEVER_EXPIRES: u32 = u32::MAX;
+
+/// How long a listener waits before trying the broker again, doubling after
+/// every failed attempt up to [`MAX_BACKOFF`]
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How to talk to the broker, beyond where it is
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// TLS, with a client certificate if the config has one
+    pub tls: Option<Arc<ClientConfig>>,
+    /// Topic a listener keeps its presence on: a retained "online" while
+    /// connected, and "offline" once it stops or (as its Last Will, published
+    /// by the broker) drops off. Senders check it says "online" first.
+    pub status: Option<String>,
+    /// Listeners skip messages the broker kept from before they subscribed
+    pub ignore_retained: bool,
+    /// Senders have the broker keep their message as the topic's latest, to
+    /// hand to whoever subscribes later
+    pub retain: bool,
+    /// Quality of service to subscribe or publish with, over each side's
+    /// default: at least once for listeners, at most once for senders
+    pub qos: Option<QoS>,
+    /// Listeners ask the broker to keep their session (subscriptions and
+    /// the QoS 1 and 2 messages published meanwhile) while they are away
+    pub persistent: bool,
+    /// Client id to connect as, instead of one made up (see [`client_id`])
+    pub client_id: Option<String>,
+    /// How often to ping the broker when there is no other traffic (zero:
+    /// never), over each side's default
+    pub keep_alive: Option<Duration>,
+    /// How long connecting may take, and a sender's wait for the broker to
+    /// take its message (default: 5s)
+    pub connect_timeout: Option<Duration>,
+    /// Largest packet sent or accepted (default: [`MAX_PACKET_SIZE`])
+    pub max_packet_size: Option<usize>,
+}
+
+impl Options {
+    fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(CONNECT_TIMEOUT)
+    }
+}
+
+/// MQTT v5 extras sent along with a message
+#[derive(Debug, Clone, Default)]
+pub struct Properties {
+    /// User properties (e.g. hostname, priority); the listener's command
+    /// template can use them as `{name}`
+    pub user: Vec<(String, String)>,
+    /// Seconds the broker may hold the message for subscribers before discarding it
+    pub expiry: Option<u32>,
+}
+
+/// Subscribe to `topics` (MQTT wildcards allowed) and call `handler` for
+/// every authenticated message; `Incoming::topic` is the one it was published to.
+/// Each message has an `Incoming::receipt` slot, and a receipt id left there
+/// is acknowledged on its [`receipt_topic`] once `handler` returns.
+pub fn listen(
+    broker: &str,
+    port: u16,
+    topics: &[String],
+    auth: &Tokens,
+    options: &Options,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let qos = options.qos.unwrap_or(QoS::AtLeastOnce);
+    let id = client_id(options.client_id.as_deref(), "listener", options.persistent)?;
+    debug!("Client id: {}", id);
+    let mut opts = client_options(id, broker, port, options, LISTENER_KEEP_ALIVE);
+    opts.set_clean_session(!options.persistent);
+    if let Some(status) = &options.status {
+        opts.set_last_will(LastWill::new(status, OFFLINE, QoS::AtLeastOnce, true));
+    }
+
+    let (client, mut connection) = connect(opts, options);
+    let (disconnected, gone) = mpsc::channel();
+    let stopping = client.clone();
+    let status = options.status.clone();
+    shutdown::on_stop(move || {
+        disconnect(
+            || {
+                // A clean disconnect drops the Last Will, so say it ourselves
+                if let Some(status) = status {
+                    let _ = stopping.publish(status, QoS::AtLeastOnce, true, OFFLINE);
+                }
+                stopping.disconnect().is_ok()
+            },
+            gone,
+        )
+    });
+
+    health::broker_connected(false);
+    let mut reconnect = Reconnect::default();
+    for event in connection.iter() {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                reconnect.failed(e);
+                continue;
+            }
+        };
+        if let Event::Incoming(Packet::ConnAck(ack)) = &event {
+            reconnect.connected(ack.session_present);
+            // Unless the broker kept them, subscriptions go with the connection
+            if !ack.session_present {
+                let filters = topics
+                    .iter()
+                    .map(|topic| SubscribeFilter::new(topic.clone(), qos));
+                if let Err(e) = client.try_subscribe_many(filters) {
+                    warn!("Cannot subscribe: {}", e);
+                }
+            }
+            if let Some(status) = &options.status {
+                if let Err(e) = client.try_publish(status, QoS::AtLeastOnce, true, ONLINE) {
+                    warn!("Cannot publish the status: {}", e);
+                }
+            }
+        }
+        if let Event::Outgoing(Outgoing::Disconnect) = event {
+            let _ = disconnected.send(());
+            break;
+        }
+        if let Event::Incoming(Packet::Publish(msg)) = event {
+            if msg.retain && options.ignore_retained {
+                debug!("[{}] Ignoring a retained message", msg.topic);
+                continue;
+            }
+            if is_receipt(&msg.topic) {
+                debug!("[{}] Ignoring a receipt", msg.topic);
+                continue;
+            }
+            let payload = String::from_utf8_lossy(&msg.payload);
+            let Some(mut incoming) = open(&payload, auth, msg.topic.clone()) else {
+                warn!("Auth failed, ignoring message");
+                continue;
+            };
+            let receipt = ReceiptSlot::default();
+            incoming.receipt = Some(receipt.clone());
+            handler(&incoming);
+            if let Some(id) = receipt.take() {
+                // Not waiting for room in the queue: only iterating the connection makes some
+                if let Err(e) = client.try_publish(
+                    receipt_topic(&msg.topic, &id),
+                    QoS::AtLeastOnce,
+                    false,
+                    RECEIVED,
+                ) {
+                    warn!("Cannot send the receipt: {}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn send(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    message: &str,
+    auth: Option<&str>,
+    options: &Options,
+) -> Result<()> {
+    send_all(broker, port, topic, &[message], auth, options, |_| {})
+}
+
+/// Like [`send`], for several messages over one connection, one after the
+/// other, calling `sent` with the index of each once the broker has it
+pub fn send_all(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    messages: &[&str],
+    auth: Option<&str>,
+    options: &Options,
+    mut sent: impl FnMut(usize),
+) -> Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    if let Some(status) = &options.status {
+        check_online(broker, port, status, options)?;
+    }
+    let id = client_id(options.client_id.as_deref(), "sender", false)?;
+    let opts = client_options(id, broker, port, options, SENDER_KEEP_ALIVE);
+    let (client, mut connection) = connect(opts, options);
+
+    let qos = options.qos.unwrap_or(QoS::AtMostOnce);
+    let publish = |message: &str| {
+        client
+            .publish(topic, qos, options.retain, seal(message, auth).as_bytes())
+            .map_err(|e| Error::Mqtt(e.to_string()))
+    };
+    publish(messages[0])?;
+
+    // Poll connection briefly to actually send each message
+    let mut next = 0;
+    let mut start = Instant::now();
+    let timeout = options.connect_timeout();
+
+    for event in connection.iter() {
+        if start.elapsed() > timeout {
+            return Err(Error::Timeout);
+        }
+        let done = match event {
+            Ok(Event::Outgoing(rumqttc::Outgoing::Publish(_))) => qos == QoS::AtMostOnce,
+            // Past QoS 0, done once the broker has taken the message
+            Ok(Event::Incoming(Packet::PubAck(_))) => qos == QoS::AtLeastOnce,
+            Ok(Event::Incoming(Packet::PubComp(_))) => true,
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => false,
+        };
+        if done {
+            sent(next);
+            next += 1;
+            if next == messages.len() {
+                return Ok(());
+            }
+            publish(messages[next])?;
+            start = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+/// Like [`listen`], over MQTT v5: user properties end up in
+/// `Incoming::properties`, and `title`/`priority`/`format` ones also set those fields.
+/// With `reply`, messages with a response topic get an `Incoming::reply` slot,
+/// and what `handler` leaves there is published to it (see [`request_v5`]).
+/// Receipts go as with [`listen`].
+pub fn listen_v5(
+    broker: &str,
+    port: u16,
+    topics: &[String],
+    auth: &Tokens,
+    options: &Options,
+    reply: bool,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    let qos = qos5(options.qos.unwrap_or(QoS::AtLeastOnce));
+    let id = client_id(options.client_id.as_deref(), "listener", options.persistent)?;
+    debug!("Client id: {}", id);
+    let mut opts = client_options5(id, broker, port, options, LISTENER_KEEP_ALIVE);
+    if options.persistent {
+        opts.set_clean_start(false);
+        opts.set_session_expiry_interval(Some(NEVER_EXPIRES));
+    }
+    if let Some(status) = &options.status {
+        opts.set_last_will(LastWill5::new(
+            status,
+            OFFLINE,
+            rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+            true,
+            None,
+        ));
+    }
+
+    let (client, mut connection) = Client5::new(opts, 10);
+    let (disconnected, gone) = mpsc::channel();
+    let stopping = client.clone();
+    let status = options.status.clone();
+    shutdown::on_stop(move || {
+        disconnect(
+            || {
+                // A clean disconnect drops the Last Will, so say it ourselves
+                if let Some(status) = status {
+                    let _ = stopping.publish(
+                        status,
+                        rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                        true,
+                        OFFLINE,
+                    );
+                }
+                stopping.disconnect().is_ok()
+            },
+            gone,
+        )
+    });
+
+    health::broker_connected(false);
+    let mut reconnect = Reconnect::default();
+    for event in connection.iter() {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                reconnect.failed(e);
+                continue;
+            }
+        };
+        if let Event5::Incoming(Packet5::ConnAck(ack)) = &event {
+            reconnect.connected(ack.session_present);
+            if !ack.session_present {
+                let filters = topics.iter().map(|topic| Filter::new(topic.clone(), qos));
+                if let Err(e) = client.try_subscribe_many(filters) {
+                    warn!("Cannot subscribe: {}", e);
+                }
+            }
+            if let Some(status) = &options.status {
+                if let Err(e) = client.try_publish(
+                    status,
+                    rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                    true,
+                    ONLINE,
+                ) {
+                    warn!("Cannot publish the status: {}", e);
+                }
+            }
+        }
+        if let Event5::Outgoing(Outgoing::Disconnect) = event {
+            let _ = disconnected.send(());
+            break;
+        }
+        if let Event5::Incoming(Packet5::Publish(msg)) = event {
+            let source = String::from_utf8_lossy(&msg.topic).into_owned();
+            if msg.retain && options.ignore_retained {
+                debug!("[{}] Ignoring a retained message", source);
+                continue;
+            }
+            if is_receipt(&source) {
+                debug!("[{}] Ignoring a receipt", source);
+                continue;
+            }
+            let payload = String::from_utf8_lossy(&msg.payload);
+            let Some(mut incoming) = open(&payload, auth, source.clone()) else {
+                warn!("Auth failed, ignoring message");
+                continue;
+            };
+            let PublishProperties {
+                user_properties,
+                response_topic,
+                correlation_data,
+                ..
+            } = msg.properties.unwrap_or_default();
+            incoming.properties = user_properties;
+            if let Some(title) = incoming.property("title") {
+                incoming.title = Some(title.to_string());
+            }
+            if let Some(priority) = incoming.property("priority") {
+                incoming.priority =
+                    clap::ValueEnum::from_str(priority, true).unwrap_or(Priority::Normal);
+            }
+            if let Some(format) = incoming.property("format") {
+                incoming.format = clap::ValueEnum::from_str(format, true).unwrap_or(Format::Plain);
+            }
+            let respond_to = response_topic.filter(|_| reply);
+            incoming.reply = respond_to.as_ref().map(|_| ReplySlot::default());
+            let receipt = ReceiptSlot::default();
+            incoming.receipt = Some(receipt.clone());
+            handler(&incoming);
+            if let Some(id) = receipt.take() {
+                let published = client.try_publish(
+                    receipt_topic(&source, &id),
+                    rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                    false,
+                    RECEIVED,
+                );
+                if let Err(e) = published {
+                    warn!("Cannot send the receipt: {}", e);
+                }
+            }
+            if let (Some(topic), Some(slot)) = (respond_to, &incoming.reply) {
+                let mut properties = PublishProperties {
+                    correlation_data,
+                    ..PublishProperties::default()
+                };
+                let output = match slot.take() {
+                    Some(result) => {
+                        properties
+                            .user_properties
+                            .push((EXIT_PROPERTY.into(), result.code.to_string()));
+                        result.output
+                    }
+                    None => String::new(),
+                };
+                // Not waiting for room in the queue: only iterating the connection makes some
+                let published = client.try_publish_with_properties(
+                    topic,
+                    rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                    false,
+                    output.into_bytes(),
+                    properties,
+                );
+                if let Err(e) = published {
+                    warn!("Cannot send the reply: {}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Like [`send`], over MQTT v5 with user properties and message expiry
+pub fn send_v5(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    message: &str,
+    auth: Option<&str>,
+    properties: &Properties,
+    options: &Options,
+) -> Result<()> {
+    send_all_v5(
+        broker,
+        port,
+        topic,
+        &[message],
+        auth,
+        properties,
+        options,
+        |_| {},
+    )
+}
+
+/// Like [`send_all`], over MQTT v5
+#[allow(clippy::too_many_arguments)]
+pub fn send_all_v5(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    messages: &[&str],
+    auth: Option<&str>,
+    properties: &Properties,
+    options: &Options,
+    mut sent: impl FnMut(usize),
+) -> Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    if let Some(status) = &options.status {
+        check_online(broker, port, status, options)?;
+    }
+    let id = client_id(options.client_id.as_deref(), "sender", false)?;
+    let opts = client_options5(id, broker, port, options, SENDER_KEEP_ALIVE);
+    let (client, mut connection) = Client5::new(opts, 10);
+
+    let publish = PublishProperties {
+        user_properties: properties.user.clone(),
+        message_expiry_interval: properties.expiry,
+        content_type: Some("text/plain".into()),
+        ..PublishProperties::default()
+    };
+    let qos = options.qos.unwrap_or(QoS::AtMostOnce);
+    let publish_one = |message: &str| {
+        client
+            .publish_with_properties(
+                topic,
+                qos5(qos),
+                options.retain,
+                seal(message, auth).into_bytes(),
+                publish.clone(),
+            )
+            .map_err(|e| Error::Mqtt(e.to_string()))
+    };
+    publish_one(messages[0])?;
+
+    let mut next = 0;
+    let mut start = Instant::now();
+    let timeout = options.connect_timeout();
+
+    for event in connection.iter() {
+        if start.elapsed() > timeout {
+            return Err(Error::Timeout);
+        }
+        let done = match event {
+            Ok(Event5::Outgoing(rumqttc::Outgoing::Publish(_))) => qos == QoS::AtMostOnce,
+            Ok(Event5::Incoming(Packet5::PubAck(_))) => qos == QoS::AtLeastOnce,
+            Ok(Event5::Incoming(Packet5::PubComp(_))) => true,
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => false,
+        };
+        if done {
+            sent(next);
+            next += 1;
+            if next == messages.len() {
+                return Ok(());
+            }
+            publish_one(messages[next])?;
+            start = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+/// A sender's connection to the broker, kept open between messages that
+/// come one at a time (`crier send --interactive`). Its event loop runs on a
+/// thread of its own, so the broker keeps hearing from it while there is
+/// nothing to send, and connects again by itself after a drop.
+pub struct Publisher {
+    client: Publishing,
+    topic: String,
+    auth: Option<String>,
+    qos: QoS,
+    retain: bool,
+    timeout: Duration,
+    /// Each message the broker has taken, or each connection error, from the
+    /// event loop
+    progress: mpsc::Receiver<std::result::Result<(), String>>,
+}
+
+enum Publishing {
+    V4(Client),
+    V5(Client5, PublishProperties),
+}
+
+impl Publisher {
+    /// Connect to publish to `topic`, once the listener on the status topic,
+    /// if any, is online
+    pub fn new(
+        broker: &str,
+        port: u16,
+        topic: &str,
+        auth: Option<&str>,
+        options: &Options,
+    ) -> Result<Publisher> {
+        if let Some(status) = &options.status {
+            check_online(broker, port, status, options)?;
+        }
+        let id = client_id(options.client_id.as_deref(), "sender", false)?;
+        let (client, mut connection) = connect(
+            client_options(id, broker, port, options, SENDER_KEEP_ALIVE),
+            options,
+        );
+        let qos = options.qos.unwrap_or(QoS::AtMostOnce);
+        let (progressed, progress) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                let progress = match event {
+                    Ok(Event::Outgoing(Outgoing::Publish(_))) if qos == QoS::AtMostOnce => Ok(()),
+                    Ok(Event::Incoming(Packet::PubAck(_))) if qos == QoS::AtLeastOnce => Ok(()),
+                    Ok(Event::Incoming(Packet::PubComp(_))) => Ok(()),
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        thread::sleep(MIN_BACKOFF);
+                        Err(format!("{:?}", e))
+                    }
+                };
+                if progressed.send(progress).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Publisher::with(
+            Publishing::V4(client),
+            topic,
+            auth,
+            options,
+            progress,
+        ))
+    }
+
+    /// Like [`Publisher::new`], over MQTT v5
+    pub fn new_v5(
+        broker: &str,
+        port: u16,
+        topic: &str,
+        auth: Option<&str>,
+        properties: &Properties,
+        options: &Options,
+    ) -> Result<Publisher> {
+        if let Some(status) = &options.status {
+            check_online(broker, port, status, options)?;
+        }
+        let id = client_id(options.client_id.as_deref(), "sender", false)?;
+        let (client, mut connection) = Client5::new(
+            client_options5(id, broker, port, options, SENDER_KEEP_ALIVE),
+            10,
+        );
+        let qos = options.qos.unwrap_or(QoS::AtMostOnce);
+        let (progressed, progress) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                let progress = match event {
+                    Ok(Event5::Outgoing(Outgoing::Publish(_))) if qos == QoS::AtMostOnce => Ok(()),
+                    Ok(Event5::Incoming(Packet5::PubAck(_))) if qos == QoS::AtLeastOnce => Ok(()),
+                    Ok(Event5::Incoming(Packet5::PubComp(_))) => Ok(()),
+                    Ok(Event5::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        thread::sleep(MIN_BACKOFF);
+                        Err(format!("{:?}", e))
+                    }
+                };
+                if progressed.send(progress).is_err() {
+                    break;
+                }
+            }
+        });
+        let publish = PublishProperties {
+            user_properties: properties.user.clone(),
+            message_expiry_interval: properties.expiry,
+            content_type: Some("text/plain".into()),
+            ..PublishProperties::default()
+        };
+        Ok(Publisher::with(
+            Publishing::V5(client, publish),
+            topic,
+            auth,
+            options,
+            progress,
+        ))
+    }
+
+    fn with(
+        client: Publishing,
+        topic: &str,
+        auth: Option<&str>,
+        options: &Options,
+        progress: mpsc::Receiver<std::result::Result<(), String>>,
+    ) -> Publisher {
+        Publisher {
+            client,
+            topic: topic.to_string(),
+            auth: auth.map(str::to_string),
+            qos: options.qos.unwrap_or(QoS::AtMostOnce),
+            retain: options.retain,
+            timeout: options.connect_timeout(),
+            progress,
+        }
+    }
+
+    /// Publish `message` and wait (up to the connect timeout) for the broker
+    /// to take it, through any reconnecting that takes
+    pub fn publish(&mut self, message: &str) -> Result<()> {
+        // What came before is about earlier messages, or none
+        while self.progress.try_recv().is_ok() {}
+        let payload = seal(message, self.auth.as_deref());
+        match &self.client {
+            Publishing::V4(client) => client
+                .publish(&self.topic, self.qos, self.retain, payload.as_bytes())
+                .map_err(|e| Error::Mqtt(e.to_string()))?,
+            Publishing::V5(client, properties) => client
+                .publish_with_properties(
+                    &self.topic,
+                    qos5(self.qos),
+                    self.retain,
+                    payload.into_bytes(),
+                    properties.clone(),
+                )
+                .map_err(|e| Error::Mqtt(e.to_string()))?,
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut failed = None;
+        loop {
+            match self
+                .progress
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => failed = Some(e),
+                Err(_) => return Err(failed.map_or(Error::Timeout, Error::Mqtt)),
+            }
+        }
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        // Its event loop sends it, as far as it gets before we exit
+        match &self.client {
+            Publishing::V4(client) => drop(client.try_disconnect()),
+            Publishing::V5(client, _) => drop(client.try_disconnect()),
+        }
+    }
+}
+
+/// Like [`send_v5`], to a listener running with `--reply`: subscribe to a
+/// response topic of our own, publish the message naming it, and wait up to
+/// `timeout` for the result of the command the listener runs
+#[allow(clippy::too_many_arguments)]
+pub fn request_v5(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    message: &str,
+    auth: Option<&str>,
+    properties: &Properties,
+    options: &Options,
+    timeout: Duration,
+) -> Result<Reply> {
+    if let Some(status) = &options.status {
+        check_online(broker, port, status, options)?;
+    }
+    let id = random_id()?;
+    let response_topic = format!("crier/reply/{}", id);
+
+    let opts = client_options5(
+        client_id(options.client_id.as_deref(), "sender", false)?,
+        broker,
+        port,
+        options,
+        SENDER_KEEP_ALIVE,
+    );
+    let (client, mut connection) = Client5::new(opts, 10);
+    client
+        .subscribe(&response_topic, rumqttc::v5::mqttbytes::QoS::AtLeastOnce)
+        .map_err(|e| Error::Mqtt(e.to_string()))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let event = match connection
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::Rejected(format!(
+                    "No reply within {}; is the listener using --reply?",
+                    cron::format_duration(timeout)
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Mqtt("Disconnected".into())),
+        };
+        match event {
+            // Only publish once replies can't be missed
+            Ok(Event5::Incoming(Packet5::SubAck(_))) => {
+                let publish = PublishProperties {
+                    user_properties: properties.user.clone(),
+                    message_expiry_interval: properties.expiry,
+                    content_type: Some("text/plain".into()),
+                    response_topic: Some(response_topic.clone()),
+                    correlation_data: Some(id.clone().into_bytes().into()),
+                    ..PublishProperties::default()
+                };
+                client
+                    .publish_with_properties(
+                        topic,
+                        qos5(options.qos.unwrap_or(QoS::AtLeastOnce)),
+                        false,
+                        seal(message, auth).into_bytes(),
+                        publish,
+                    )
+                    .map_err(|e| Error::Mqtt(e.to_string()))?;
+            }
+            Ok(Event5::Incoming(Packet5::Publish(msg))) => {
+                let properties = msg.properties.unwrap_or_default();
+                if properties.correlation_data.as_deref() != Some(id.as_bytes()) {
+                    continue;
+                }
+                let _ = client.disconnect();
+                let code = properties
+                    .user_properties
+                    .iter()
+                    .find(|(name, _)| name == EXIT_PROPERTY)
+                    .and_then(|(_, code)| code.parse().ok());
+                let Some(code) = code else {
+                    return Err(Error::Rejected(
+                        "The listener ran no command for the message".into(),
+                    ));
+                };
+                return Ok(Reply {
+                    code,
+                    output: String::from_utf8_lossy(&msg.payload).into_owned(),
+                });
+            }
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => {}
+        }
+    }
+}
+
+/// Like [`send`], waiting up to `timeout` for a listener to acknowledge the
+/// message on its [`receipt_topic`]; `message` carries the receipt `id` in its
+/// envelope (see `envelope`)
+#[allow(clippy::too_many_arguments)]
+pub fn send_with_receipt(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    message: &str,
+    auth: Option<&str>,
+    options: &Options,
+    id: &str,
+    timeout: Duration,
+) -> Result<()> {
+    if let Some(status) = &options.status {
+        check_online(broker, port, status, options)?;
+    }
+    let ack = receipt_topic(topic, id);
+    let opts = client_options(
+        client_id(options.client_id.as_deref(), "sender", false)?,
+        broker,
+        port,
+        options,
+        SENDER_KEEP_ALIVE,
+    );
+    let (client, mut connection) = connect(opts, options);
+    client
+        .subscribe(&ack, QoS::AtLeastOnce)
+        .map_err(|e| Error::Mqtt(e.to_string()))?;
+
+    let mut deadline = Instant::now() + options.connect_timeout();
+    let mut published = false;
+    loop {
+        let event =
+            match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => event,
+                Err(rumqttc::RecvTimeoutError::Timeout) if published => {
+                    return Err(no_receipt(topic, timeout))
+                }
+                Err(rumqttc::RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                Err(rumqttc::RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Mqtt("Disconnected".into()))
+                }
+            };
+        match event {
+            // Only publish once the receipt can't be missed
+            Ok(Event::Incoming(Packet::SubAck(_))) if !published => {
+                let qos = options.qos.unwrap_or(QoS::AtLeastOnce);
+                client
+                    .publish(topic, qos, options.retain, seal(message, auth).as_bytes())
+                    .map_err(|e| Error::Mqtt(e.to_string()))?;
+                published = true;
+                deadline = Instant::now() + timeout;
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) if msg.topic == ack => {
+                let _ = client.disconnect();
+                return Ok(());
+            }
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => {}
+        }
+    }
+}
+
+/// Like [`send_with_receipt`], over MQTT v5; the receipt id goes in
+/// `properties`
+#[allow(clippy::too_many_arguments)]
+pub fn send_with_receipt_v5(
+    broker: &str,
+    port: u16,
+    topic: &str,
+    message: &str,
+    auth: Option<&str>,
+    properties: &Properties,
+    options: &Options,
+    id: &str,
+    timeout: Duration,
+) -> Result<()> {
+    if let Some(status) = &options.status {
+        check_online(broker, port, status, options)?;
+    }
+    let ack = receipt_topic(topic, id);
+    let opts = client_options5(
+        client_id(options.client_id.as_deref(), "sender", false)?,
+        broker,
+        port,
+        options,
+        SENDER_KEEP_ALIVE,
+    );
+    let (client, mut connection) = Client5::new(opts, 10);
+    client
+        .subscribe(&ack, rumqttc::v5::mqttbytes::QoS::AtLeastOnce)
+        .map_err(|e| Error::Mqtt(e.to_string()))?;
+
+    let mut deadline = Instant::now() + options.connect_timeout();
+    let mut published = false;
+    loop {
+        let event = match connection
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if published => return Err(no_receipt(topic, timeout)),
+            Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Mqtt("Disconnected".into())),
+        };
+        match event {
+            Ok(Event5::Incoming(Packet5::SubAck(_))) if !published => {
+                let publish = PublishProperties {
+                    user_properties: properties.user.clone(),
+                    message_expiry_interval: properties.expiry,
+                    content_type: Some("text/plain".into()),
+                    ..PublishProperties::default()
+                };
+                client
+                    .publish_with_properties(
+                        topic,
+                        qos5(options.qos.unwrap_or(QoS::AtLeastOnce)),
+                        options.retain,
+                        seal(message, auth).into_bytes(),
+                        publish,
+                    )
+                    .map_err(|e| Error::Mqtt(e.to_string()))?;
+                published = true;
+                deadline = Instant::now() + timeout;
+            }
+            Ok(Event5::Incoming(Packet5::Publish(msg))) if msg.topic == ack.as_bytes() => {
+                let _ = client.disconnect();
+                return Ok(());
+            }
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => {}
+        }
+    }
+}
+
+/// Where a listener acknowledges the message with receipt `id` it got on
+/// `topic`: `<topic>/ack/<id>`
+pub fn receipt_topic(topic: &str, id: &str) -> String {
+    format!("{}/ack/{}", topic, id)
+}
+
+/// Whether `topic` is a [`receipt_topic`], which listeners whose wildcards
+/// cover it don't take for a message
+fn is_receipt(topic: &str) -> bool {
+    let mut levels = topic.rsplit('/');
+    let id = levels.next().unwrap_or_default();
+    levels.next() == Some("ack") && is_receipt_id(id)
+}
+
+/// Whether `id` is one a sender made for a receipt (32 hex digits), and so
+/// safe to put in a [`receipt_topic`]
+pub(crate) fn is_receipt_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn no_receipt(topic: &str, timeout: Duration) -> Error {
+    Error::Rejected(format!(
+        "No receipt within {}; is a listener subscribed to {}?",
+        cron::format_duration(timeout),
+        topic
+    ))
+}
+
+/// 16 random bytes in hex, for a receipt or a response topic
+pub(crate) fn random_id() -> Result<String> {
+    let mut id = [0; 16];
+    tls::provider()
+        .secure_random
+        .fill(&mut id)
+        .map_err(|e| Error::Tls(rustls::Error::from(e).to_string()))?;
+    Ok(hex::encode(id))
+}
+
+/// Make sure the listener keeping its presence on `status` is online: that
+/// topic's retained message, if any, has to say so
+fn check_online(broker: &str, port: u16, status: &str, options: &Options) -> Result<()> {
+    let id = client_id(options.client_id.as_deref(), "sender", false)?;
+    let (client, mut connection) = connect(
+        client_options(id, broker, port, options, SENDER_KEEP_ALIVE),
+        options,
+    );
+    client
+        .subscribe(status, QoS::AtMostOnce)
+        .map_err(|e| Error::Mqtt(e.to_string()))?;
+    let mut deadline = Instant::now() + options.connect_timeout();
+    let mut subscribed = false;
+    let state = loop {
+        let event =
+            match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => event,
+                Err(rumqttc::RecvTimeoutError::Timeout) if subscribed => break None,
+                Err(rumqttc::RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                Err(rumqttc::RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Mqtt("Disconnected".into()))
+                }
+            };
+        match event {
+            // The broker sends retained messages along with the subscription
+            Ok(Event::Incoming(Packet::SubAck(_))) => {
+                subscribed = true;
+                deadline = Instant::now() + STATUS_WAIT;
+            }
+            Ok(Event::Incoming(Packet::Publish(msg))) if msg.topic == status => {
+                break Some(String::from_utf8_lossy(&msg.payload).into_owned());
+            }
+            Err(e) => return Err(Error::Mqtt(format!("{:?}", e))),
+            _ => {}
+        }
+    };
+    let _ = client.disconnect();
+    match state.as_deref() {
+        Some(ONLINE) => Ok(()),
+        Some(state) => Err(Error::Rejected(format!(
+            "The listener is {} (status on {})",
+            state, status
+        ))),
+        None => Err(Error::Rejected(format!(
+            "No status on {}; is the listener using --status-topic?",
+            status
+        ))),
+    }
+}
+
+/// Whether `topic` matches the subscription `filter`, where `+` stands for
+/// one level and a final `#` for any number. Wildcards at the start don't
+/// match `$` topics (e.g. `$SYS/...`), as with brokers.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// A listener's broker connection dropping and coming back, logged as it
+/// happens and reported to [`health`]
+pub(crate) struct Reconnect {
+    /// Whether the connection has been up yet
+    was_up: bool,
+    /// Whether it is down after being up, or after failing to come up
+    down: bool,
+    backoff: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Reconnect {
+            was_up: false,
+            down: false,
+            backoff: MIN_BACKOFF,
+        }
+    }
+}
+
+impl Reconnect {
+    /// The connection is up (with the session the broker kept, if `resumed`)
+    pub(crate) fn connected(&mut self, resumed: bool) {
+        health::broker_connected(true);
+        let session = if resumed {
+            ", resuming the session"
+        } else {
+            ""
+        };
+        match (self.down, self.was_up) {
+            (true, true) => info!("Reconnected to the broker{}", session),
+            (true, false) => info!("Connected to the broker{}", session),
+            (false, _) => debug!("Connected to the broker{}", session),
+        }
+        self.was_up = true;
+        self.down = false;
+        self.backoff = MIN_BACKOFF;
+    }
+
+    /// Connecting failed, or the connection dropped, with `error`: wait
+    /// before the next attempt, a little longer every time
+    pub(crate) fn failed(&mut self, error: impl Display) {
+        health::broker_connected(false);
+        let wait = cron::format_duration(self.backoff);
+        if self.was_up && !self.down {
+            warn!(
+                "Lost the broker connection ({}), reconnecting in {}",
+                error, wait
+            );
+        } else {
+            warn!(
+                "Cannot connect to the broker ({}), retrying in {}",
+                error, wait
+            );
+        }
+        self.down = true;
+        thread::sleep(self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Say goodbye to the broker when stopping, so it drops the session at once
+/// instead of waiting for the keep-alive to run out, and wait (briefly) for
+/// the listener's loop to send it
+pub(crate) fn disconnect(request: impl FnOnce() -> bool, gone: mpsc::Receiver<()>) {
+    if request() {
+        let _ = gone.recv_timeout(Duration::from_secs(2));
+    }
+}
+
+/// MQTT 3.1.1 client settings from `options`, pinging the broker every
+/// `keep_alive` unless they say otherwise
+pub(crate) fn client_options(
+    id: String,
+    broker: &str,
+    port: u16,
+    options: &Options,
+    keep_alive: Duration,
+) -> MqttOptions {
+    let mut opts = MqttOptions::new(id, broker, port);
+    let max = options.max_packet_size.unwrap_or(MAX_PACKET_SIZE);
+    opts.set_max_packet_size(max, max);
+    opts.set_keep_alive(options.keep_alive.unwrap_or(keep_alive));
+    opts.set_transport(transport(options.tls.clone()));
+    opts
+}
+
+/// Like [`client_options`], for MQTT v5
+fn client_options5(
+    id: String,
+    broker: &str,
+    port: u16,
+    options: &Options,
+    keep_alive: Duration,
+) -> MqttOptions5 {
+    let mut opts = MqttOptions5::new(id, broker, port);
+    opts.set_max_packet_size(Some(
+        options.max_packet_size.unwrap_or(MAX_PACKET_SIZE) as u32
+    ));
+    opts.set_keep_alive(options.keep_alive.unwrap_or(keep_alive));
+    opts.set_transport(transport(options.tls.clone()));
+    opts.set_connection_timeout(options.connect_timeout().as_secs());
+    opts
+}
+
+/// An MQTT 3.1.1 client with `opts`, whose connection timeout (unlike v5's)
+/// is set on the connection itself
+pub(crate) fn connect(opts: MqttOptions, options: &Options) -> (Client, Connection) {
+    let (client, mut connection) = Client::new(opts, 10);
+    let mut network = connection.eventloop.network_options();
+    network.set_connection_timeout(options.connect_timeout().as_secs());
+    connection.eventloop.set_network_options(network);
+    (client, connection)
+}
+
+/// `given`, or `crier-<role>-<host>` followed by a random suffix, so that
+/// clients on different machines or in different processes don't take each
+/// other's place on the broker. A `stable` id leaves the suffix out, for the
+/// broker to know the client again after a restart.
+pub(crate) fn client_id(given: Option<&str>, role: &str, stable: bool) -> Result<String> {
+    if let Some(id) = given {
+        return Ok(id.to_string());
+    }
+    let mut id = format!("crier-{}", role);
+    if let Ok(host) = hostname::get() {
+        let host = host.to_string_lossy();
+        let host: String = host
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect();
+        if !host.is_empty() {
+            id = format!("{}-{}", id, host);
+        }
+    }
+    if !stable {
+        let mut suffix = [0; 4];
+        tls::provider()
+            .secure_random
+            .fill(&mut suffix)
+            .map_err(|_| Error::Config("No randomness available for a client id".into()))?;
+        id = format!("{}-{}", id, hex::encode(suffix));
+    }
+    Ok(id)
+}
+
+/// `qos` as the MQTT v5 client has it
+fn qos5(qos: QoS) -> rumqttc::v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// Plain TCP, or TLS (with a client certificate if `tls` has one)
+pub(crate) fn transport(tls: Option<Arc<ClientConfig>>) -> Transport {
+    match tls {
+        Some(config) => Transport::tls_with_config(TlsConfiguration::Rustls(config)),
+        None => Transport::tcp(),
+    }
+}
+
+/// Payload for a broker: the message, prefixed with `AUTH:<token>:` if given
+pub(crate) fn seal(message: &str, auth: Option<&str>) -> String {
+    match auth {
+        Some(a) => format!("AUTH:{}:{}", a, message),
+        None => message.to_string(),
+    }
+}
+
+/// The message in a broker payload from `source`, or None if no accepted
+/// token's auth prefix matches
+pub(crate) fn open(payload: &str, auth: &Tokens, source: String) -> Option<Incoming> {
+    let topic = Some(source.clone());
+    if auth.is_empty() {
+        return Some(Incoming {
+            topic,
+            ..Incoming::new(payload.to_string(), source)
+        });
+    }
+    let Some((token, message)) = payload
+        .strip_prefix("AUTH:")
+        .and_then(|sealed| auth.split(sealed))
+    else {
+        metrics::auth_failed();
+        return None;
+    };
+    let mut incoming = Incoming::new(message.to_string(), source);
+    incoming.token = token.label.clone();
+    incoming.topic = topic;
+    Some(incoming)
+}
@@ -0,0 +1,75 @@
+//! A listener's `routes`: rules tried in order, the first one matching a
+//! message deciding what to do with it (run a command, notify, forward)
+
+use crate::{exec, forward, relay, Incoming, Priority};
+use regex::Regex;
+
+/// A `routes` entry, ready to use
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// Only messages whose text starts with this
+    pub prefix: Option<String>,
+    /// Only messages whose text matches
+    pub regex: Option<Regex>,
+    /// Only messages published to a matching topic (wildcards allowed)
+    pub topic: Option<String>,
+    /// Only messages at this priority or above
+    pub min_priority: Priority,
+    /// Command to run; none means log only, unless it notifies or forwards
+    pub command: Option<exec::Action>,
+    /// Show a desktop notification; None leaves it to `--notify`
+    pub notify: Option<bool>,
+    /// Send the message on to this destination
+    pub forward: Option<forward::Rule>,
+}
+
+impl Rule {
+    /// Whether the rule applies to `incoming`
+    pub fn matches(&self, incoming: &Incoming) -> bool {
+        if incoming.priority < self.min_priority {
+            return false;
+        }
+        if let Some(filter) = &self.topic {
+            if !incoming
+                .topic
+                .as_deref()
+                .is_some_and(|topic| relay::matches(filter, topic))
+            {
+                return false;
+            }
+        }
+        if self.prefix.is_none() && self.regex.is_none() {
+            return true;
+        }
+        let text = incoming.text();
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| text.starts_with(prefix.as_str()))
+            && self
+                .regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(&text))
+    }
+
+    /// What it matches, for the listener's banner
+    pub fn describe(&self) -> String {
+        let mut only = Vec::new();
+        if let Some(prefix) = &self.prefix {
+            only.push(format!("starting with {:?}", prefix));
+        }
+        if let Some(regex) = &self.regex {
+            only.push(format!("matching {}", regex));
+        }
+        if let Some(topic) = &self.topic {
+            only.push(format!("topic {}", topic));
+        }
+        if self.min_priority > Priority::Low {
+            only.push(format!("{} and above", self.min_priority));
+        }
+        if only.is_empty() {
+            "everything else".to_string()
+        } else {
+            only.join(", ")
+        }
+    }
+}
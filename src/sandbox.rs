@@ -0,0 +1,221 @@
+//! Optional confinement for listener commands: a private working directory,
+//! a cleared environment, resource limits (Unix) and, on Linux 5.13+ with the
+//! `landlock` feature, Landlock rules that leave the command unable to write
+//! anywhere else
+
+use crate::user::User;
+use crate::{Error, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Seconds of CPU time a command may use
+#[cfg(unix)]
+const CPU_SECONDS: u64 = 60;
+/// Largest file a command may write
+#[cfg(unix)]
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// Open files a command may have
+#[cfg(unix)]
+const OPEN_FILES: u64 = 256;
+
+/// Environment variables a sandboxed command keeps, besides `keep_env`
+const KEPT_ENV: &[&str] = &["PATH", "LANG", "LC_ALL", "TZ"];
+
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    /// Working directory, and the only place the command may write
+    pub dir: PathBuf,
+    /// More environment variables to pass through (e.g. DISPLAY)
+    pub keep_env: Vec<String>,
+}
+
+impl Sandbox {
+    /// Sandbox in `dir` (default: [`default_dir`]), for commands run as
+    /// `run_as` (default: crier's own user). The directory is created private
+    /// to that user; one already there has to be that already (a real
+    /// directory, not a link, of theirs, mode 0700), since the command may
+    /// write anywhere in it.
+    pub fn new(
+        dir: Option<PathBuf>,
+        keep_env: Vec<String>,
+        run_as: Option<&User>,
+    ) -> Result<Sandbox> {
+        let dir = dir.unwrap_or_else(default_dir);
+        let failed = |e: std::io::Error| {
+            Error::Config(format!(
+                "Cannot create sandbox directory {}: {}",
+                dir.display(),
+                e
+            ))
+        };
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent).map_err(failed)?;
+        }
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        match builder.create(&dir) {
+            Ok(()) => hand_over(&dir, run_as).map_err(failed)?,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => check_private(&dir, run_as)?,
+            Err(e) => return Err(failed(e)),
+        }
+        Ok(Sandbox {
+            dir: dir.canonicalize()?,
+            keep_env,
+        })
+    }
+
+    /// Whether the kernel can keep commands from writing outside `dir`
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    pub fn confines_filesystem() -> bool {
+        use landlock::{Access, AccessFs, CompatLevel, Compatible, Ruleset, RulesetAttr, ABI};
+        Ruleset::default()
+            .set_compatibility(CompatLevel::HardRequirement)
+            .handle_access(AccessFs::from_all(ABI::V1))
+            .and_then(|ruleset| ruleset.create())
+            .is_ok()
+    }
+
+    /// Whether the kernel can keep commands from writing outside `dir`
+    #[cfg(not(all(target_os = "linux", feature = "landlock")))]
+    pub fn confines_filesystem() -> bool {
+        false
+    }
+
+    /// Set `command` up to run inside the sandbox. Call before adding the
+    /// command's own environment variables, which clearing would remove.
+    pub fn apply(&self, command: &mut Command) -> Result<()> {
+        command.env_clear().current_dir(&self.dir);
+        let kept = KEPT_ENV
+            .iter()
+            .copied()
+            .chain(self.keep_env.iter().map(String::as_str));
+        for name in kept {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+        command.env("HOME", &self.dir).env("TMPDIR", &self.dir);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            #[cfg(all(target_os = "linux", feature = "landlock"))]
+            let mut ruleset = Some(self.landlock()?);
+            // Runs in the child between fork and exec, so only async-signal-safe calls
+            unsafe {
+                command.pre_exec(move || {
+                    set_limits()?;
+                    #[cfg(all(target_os = "linux", feature = "landlock"))]
+                    if let Some(ruleset) = ruleset.take() {
+                        ruleset.restrict_self().map_err(std::io::Error::other)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and execute anywhere, write only in `dir` (and /dev/null).
+    /// Kernels without Landlock ignore it.
+    #[cfg(all(target_os = "linux", feature = "landlock"))]
+    fn landlock(&self) -> Result<landlock::RulesetCreated> {
+        use landlock::{
+            path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, ABI,
+        };
+        let abi = ABI::V3;
+        let rules = || -> std::result::Result<_, landlock::RulesetError> {
+            Ruleset::default()
+                .handle_access(AccessFs::from_all(abi))?
+                .create()?
+                .add_rules(path_beneath_rules(["/"], AccessFs::from_read(abi)))?
+                .add_rules(path_beneath_rules(["/dev/null"], AccessFs::from_file(abi)))?
+                .add_rules(path_beneath_rules([&self.dir], AccessFs::from_all(abi)))
+        };
+        rules().map_err(|e| Error::Config(format!("Cannot set up the sandbox: {}", e)))
+    }
+}
+
+/// `~/.local/state/crier/sandbox` (or the platform's local data dir); not
+/// the shared temp directory, where anyone could have put a link first
+pub fn default_dir() -> PathBuf {
+    let base = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("crier").join("sandbox")
+}
+
+/// Give the sandbox directory just created to `run_as`, if set
+#[cfg(unix)]
+fn hand_over(dir: &Path, run_as: Option<&User>) -> std::io::Result<()> {
+    match run_as {
+        Some(user) => std::os::unix::fs::chown(dir, Some(user.uid), Some(user.gid)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn hand_over(_dir: &Path, _run_as: Option<&User>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Fail unless the existing `dir` is a real directory, owned by `run_as`
+/// (or us) and closed to everyone else
+fn check_private(dir: &Path, run_as: Option<&User>) -> Result<()> {
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.file_type().is_dir() {
+        return Err(Error::Config(format!(
+            "Sandbox directory {} is a link or not a directory",
+            dir.display()
+        )));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let owner = run_as.map_or_else(|| unsafe { libc::geteuid() }, |user| user.uid);
+        if meta.uid() != owner || meta.mode() & 0o777 != 0o700 {
+            return Err(Error::Config(format!(
+                "Sandbox directory {} must belong to uid {} with mode 0700 (it is uid {}, mode {:o})",
+                dir.display(),
+                owner,
+                meta.uid(),
+                meta.mode() & 0o777
+            )));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = run_as;
+    Ok(())
+}
+
+/// Lower the CPU, file size and open file limits (never raising a hard limit)
+#[cfg(unix)]
+fn set_limits() -> std::io::Result<()> {
+    let limits = [
+        (libc::RLIMIT_CPU, CPU_SECONDS),
+        (libc::RLIMIT_FSIZE, FILE_SIZE),
+        (libc::RLIMIT_NOFILE, OPEN_FILES),
+        (libc::RLIMIT_CORE, 0),
+    ];
+    for (resource, value) in limits {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let value = (value as libc::rlim_t).min(limit.rlim_max);
+        let limit = libc::rlimit {
+            rlim_cur: value,
+            rlim_max: value,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
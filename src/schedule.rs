@@ -0,0 +1,326 @@
+//! `--delay` and `--at`: sends put off until later. Each is kept on disk (in
+//! `~/.local/state/crier/pending/`, with the command line to send it with,
+//! less any secrets) so it survives restarts, and sent when due by `crier
+//! pending --run`, which `crier send` and `crier pending` start in the
+//! background whenever sends are waiting and no runner is at it.
+
+use crate::{args, daemon, exec, quiet, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest `run` sleeps before looking for new sends again; sends deferred
+/// meanwhile are left to the runner already going
+const POLL: Duration = Duration::from_secs(5);
+
+/// Held by the runner while it runs, so only one does
+const LOCK: &str = ".runner.lock";
+
+/// Send options the deferred command line goes without: the schedule itself,
+/// and what made the message, which is kept as it was
+const DROPPED: [&str; 5] = ["--delay", "--at", "--message", "--message-file", "--var"];
+
+/// A send waiting for its time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pending {
+    /// `<due>-<pid>`, which sorts by when it is due
+    #[serde(skip)]
+    pub id: String,
+    /// When it is due, in RFC 3339 (UTC)
+    pub due: String,
+    /// The same in seconds since the epoch
+    pub due_secs: u64,
+    /// Where `crier send` ran, for relative paths
+    pub dir: PathBuf,
+    /// `crier send`'s arguments, without the schedule
+    pub args: Vec<String>,
+    /// The message, if any, kept in `<id>.txt` and sent with `--message-file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// `--at HH:MM` (for clap's `value_parser`): minutes since midnight
+pub fn parse_time(text: &str) -> std::result::Result<u32, String> {
+    let invalid = || format!("'{}' is not a time of day (e.g. 18:00)", text.trim());
+    let (hours, minutes) = text.trim().split_once(':').ok_or_else(invalid)?;
+    let (hours, minutes): (u32, u32) = (
+        hours.parse().map_err(|_| invalid())?,
+        minutes.parse().map_err(|_| invalid())?,
+    );
+    if hours >= 24 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// How long until `minute` (since midnight, local time) next comes round
+pub fn until(minute: u32) -> Duration {
+    let now = i64::from(quiet::local_seconds());
+    Duration::from_secs((i64::from(minute) * 60 - now).rem_euclid(86400) as u64)
+}
+
+/// `~/.local/state/crier/pending` (or the platform's local data dir)
+fn dir() -> PathBuf {
+    let base = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("crier").join("pending")
+}
+
+/// Keep this `crier send` to run again in `wait`, with `message` as it is
+/// now, and start `crier pending --run` in the background to send it
+pub fn defer(wait: Duration, message: Option<&str>) -> Result<Pending> {
+    let due = SystemTime::now() + wait;
+    let due_secs = due
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let id = format!("{}-{}", due_secs, std::process::id());
+    let dir = dir();
+    daemon::create_private_dir(&dir)?;
+    let mut args = deferred_args()?;
+    if let Some(message) = message {
+        let path = dir.join(format!("{}.txt", id));
+        daemon::create_private(&path)?.write_all(message.as_bytes())?;
+        args.push("--message-file".into());
+        args.push(path.to_string_lossy().into_owned());
+    }
+    let pending = Pending {
+        id,
+        due: exec::timestamp(due),
+        due_secs,
+        dir: std::env::current_dir()?,
+        args,
+        message: message.map(str::to_string),
+    };
+    let json = serde_json::to_string(&pending).map_err(std::io::Error::from)?;
+    daemon::create_private(&dir.join(format!("{}.json", pending.id)))?
+        .write_all(json.as_bytes())?;
+    start_runner()?;
+    Ok(pending)
+}
+
+/// This process's arguments without the ones in [`DROPPED`] (and
+/// `--nagios`, whose message is already built). A secret given on the
+/// command line fails it instead: it isn't kept on disk.
+fn deferred_args() -> Result<Vec<String>> {
+    let args = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned());
+    let (mut kept, secrets) = args::without_secrets(args, &DROPPED);
+    if let Some(name) = secrets.first() {
+        return Err(Error::Config(format!(
+            "A later send can't keep {} from the command line; set it in a preset or the keyring (keyring:<name>)",
+            name
+        )));
+    }
+    kept.retain(|arg| arg != "--nagios");
+    Ok(kept)
+}
+
+/// Start a runner if sends are waiting (say, from before a restart) and
+/// none is running
+pub fn resume() -> Result<()> {
+    if list()?.is_empty() || runner_active()? {
+        return Ok(());
+    }
+    start_runner()
+}
+
+/// Whether a runner holds the lock
+fn runner_active() -> Result<bool> {
+    match lock()? {
+        Some(lock) => {
+            drop(lock);
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
+
+/// The runner's lock, or None if another runner has it
+fn lock() -> Result<Option<fs::File>> {
+    let dir = dir();
+    daemon::create_private_dir(&dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// `crier pending --run`, detached, its output going to `pending.log` next
+/// to the pending sends
+fn start_runner() -> Result<()> {
+    let log = daemon::default_path(Some("pending"), "log");
+    let output = OpenOptions::new().create(true).append(true).open(&log)?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(["pending", "--run"])
+        .stdin(Stdio::null())
+        .stdout(output.try_clone()?)
+        .stderr(output);
+    #[cfg(unix)]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        // Its own session, so closing the terminal doesn't take it along
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    command.spawn()?;
+    Ok(())
+}
+
+/// Every send waiting, soonest first
+pub fn list() -> Result<Vec<Pending>> {
+    let entries = match fs::read_dir(dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut pending: Vec<Pending> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let id = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".json")?
+                .to_string();
+            let json = fs::read_to_string(&path).ok()?;
+            let pending: Pending = serde_json::from_str(&json).ok()?;
+            Some(Pending { id, ..pending })
+        })
+        .collect();
+    pending.sort_by(|a, b| a.due_secs.cmp(&b.due_secs).then_with(|| a.id.cmp(&b.id)));
+    Ok(pending)
+}
+
+/// Drop the send `id` before it goes
+pub fn cancel(id: &str) -> Result<()> {
+    let dir = dir();
+    match fs::remove_file(dir.join(format!("{}.json", id))) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::Config(format!(
+            "No pending send {} (see crier pending)",
+            id
+        ))),
+        Err(e) => Err(e.into()),
+        Ok(()) => {
+            let _ = fs::remove_file(dir.join(format!("{}.txt", id)));
+            Ok(())
+        }
+    }
+}
+
+/// Send each pending send once it is due, until there are none left. Only
+/// one runner runs at a time; another returns at once.
+pub fn run() -> Result<()> {
+    let Some(_lock) = lock()? else {
+        return Ok(());
+    };
+    loop {
+        let pending = list()?;
+        let Some(next) = pending.first() else {
+            return Ok(());
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if next.due_secs > now {
+            thread::sleep(Duration::from_secs(next.due_secs - now).min(POLL));
+            continue;
+        }
+        for due in pending.iter().filter(|pending| pending.due_secs <= now) {
+            send(due);
+        }
+    }
+}
+
+/// Claim `pending`, run its `crier send` and forget it, whatever the outcome
+fn send(pending: &Pending) {
+    let dir = dir();
+    let json = dir.join(format!("{}.json", pending.id));
+    let claimed = dir.join(format!("{}.sending", pending.id));
+    if fs::rename(&json, &claimed).is_err() {
+        return;
+    }
+    println!("[{}] Sending (due {})", pending.id, pending.due);
+    let ran = std::env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .args(&pending.args)
+            .current_dir(&pending.dir)
+            .stdin(Stdio::null())
+            .status()
+    });
+    match ran {
+        Ok(status) if status.success() => {}
+        Ok(status) => println!("[{}] crier send failed ({})", pending.id, status),
+        Err(e) => println!("[{}] Cannot run crier send: {}", pending.id, e),
+    }
+    let _ = fs::remove_file(claimed);
+    let _ = fs::remove_file(dir.join(format!("{}.txt", pending.id)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(parse_time("00:00"), Ok(0));
+        assert_eq!(parse_time(" 18:05 "), Ok(18 * 60 + 5));
+        assert_eq!(parse_time("23:59"), Ok(23 * 60 + 59));
+        assert_eq!(parse_time("7:30"), Ok(7 * 60 + 30));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_time() {
+        for text in [
+            "24:00",
+            "12:60",
+            "12",
+            "12:",
+            ":30",
+            "-1:00",
+            "12:30:00",
+            "noon",
+            "１２:００",
+            "",
+        ] {
+            assert!(parse_time(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn waits_less_than_a_day() {
+        // Retry if the minute turns over in between
+        let (minute, next, later) = loop {
+            let minute = quiet::local_seconds() / 60;
+            let (next, later) = (until((minute + 1) % 1440), until(minute));
+            if quiet::local_seconds() / 60 == minute {
+                break (minute, next, later);
+            }
+        };
+        assert!(next <= Duration::from_secs(60), "{}: {:?}", minute, next);
+        // The current minute has begun, so it next comes round tomorrow
+        assert!(
+            later > Duration::from_secs(86400 - 60) || later.is_zero(),
+            "{}: {:?}",
+            minute,
+            later
+        );
+        assert!(later < Duration::from_secs(86400));
+    }
+}
@@ -0,0 +1,213 @@
+//! A listener's `script`: a small [Rhai](https://rhai.rs) program whose
+//! `on_message(msg)` sees every message the listener would act on and can
+//! change it, drop it or pick what to run for it. Scripts need the `script`
+//! feature.
+
+use crate::exec::Action;
+use crate::{Error, Incoming, Result};
+#[cfg(feature = "script")]
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+#[cfg(feature = "script")]
+use std::path::PathBuf;
+#[cfg(feature = "script")]
+use tracing::warn;
+
+/// Most operations a call may take, so a runaway loop can't wedge the listener
+#[cfg(feature = "script")]
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// The function a script must define
+#[cfg(feature = "script")]
+const ENTRY_POINT: &str = "on_message";
+
+/// A compiled script
+#[cfg(feature = "script")]
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+}
+
+/// What the script made of a message it kept
+pub struct Outcome {
+    /// The message, with any changes
+    pub incoming: Incoming,
+    /// The command it picked, over the listener's own (empty: run nothing)
+    pub command: Option<Action>,
+    /// Whether to show a desktop notification, over `--notify`
+    pub notify: Option<bool>,
+}
+
+#[cfg(feature = "script")]
+impl Script {
+    /// Compile `path`, which must define `on_message(msg)`
+    pub fn load(path: &Path) -> Result<Script> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| Error::Config(format!("Script {}: {}", path.display(), e)))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == ENTRY_POINT && f.params.len() == 1)
+        {
+            return Err(Error::Config(format!(
+                "Script {} has no fn {}(msg)",
+                path.display(),
+                ENTRY_POINT
+            )));
+        }
+        Ok(Script {
+            path: path.to_path_buf(),
+            engine,
+            ast,
+        })
+    }
+
+    /// Call `on_message` for `incoming`. It gets a map with `message`,
+    /// `text`, `title`, `priority`, `format`, `source`, `sender`, `topic`,
+    /// `token` and `properties` and returns:
+    /// - nothing or `true` to act on the message as it is
+    /// - `false` to only log it
+    /// - a string to act on that as the message text
+    /// - a map of what to change: `message`, `title`, `priority`,
+    ///   `properties`, and `command` (a shell string or an argument list)
+    ///   or `notify` to decide what happens
+    ///
+    /// None means only log it; a script that fails is logged and keeps the
+    /// listener from acting too.
+    pub fn run(&self, incoming: &Incoming) -> Option<Outcome> {
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &self.ast,
+                ENTRY_POINT,
+                (to_map(incoming),),
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|verdict| outcome(incoming, verdict));
+        match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                warn!(
+                    "[{}] Script {} failed: {}",
+                    incoming.source,
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Without the `script` feature there is no script to load
+#[cfg(not(feature = "script"))]
+pub enum Script {}
+
+#[cfg(not(feature = "script"))]
+impl Script {
+    pub fn load(_path: &Path) -> Result<Script> {
+        Err(Error::Unsupported("--script without the script feature"))
+    }
+
+    pub fn run(&self, _incoming: &Incoming) -> Option<Outcome> {
+        match *self {}
+    }
+}
+
+/// `incoming` as the script sees it
+#[cfg(feature = "script")]
+fn to_map(incoming: &Incoming) -> Map {
+    let text = |value: Option<&String>| value.map_or(Dynamic::UNIT, |value| value.clone().into());
+    let properties: Map = incoming
+        .properties
+        .iter()
+        .map(|(name, value)| (name.into(), value.clone().into()))
+        .collect();
+    Map::from_iter([
+        ("message".into(), incoming.message.clone().into()),
+        ("text".into(), incoming.text().into()),
+        ("title".into(), text(incoming.title.as_ref())),
+        ("priority".into(), incoming.priority.to_string().into()),
+        ("format".into(), incoming.format.to_string().into()),
+        ("source".into(), incoming.source.clone().into()),
+        ("sender".into(), incoming.sender().into()),
+        ("topic".into(), text(incoming.topic.as_ref())),
+        ("token".into(), text(incoming.token.as_ref())),
+        ("properties".into(), properties.into()),
+    ])
+}
+
+/// What `on_message` returned, applied to `incoming`
+#[cfg(feature = "script")]
+fn outcome(incoming: &Incoming, verdict: Dynamic) -> std::result::Result<Option<Outcome>, String> {
+    let mut outcome = Outcome {
+        incoming: incoming.clone(),
+        command: None,
+        notify: None,
+    };
+    if verdict.is_unit() {
+        return Ok(Some(outcome));
+    }
+    if let Ok(act) = verdict.as_bool() {
+        return Ok(act.then_some(outcome));
+    }
+    if verdict.is_string() {
+        outcome.incoming.message = verdict.into_string()?;
+        return Ok(Some(outcome));
+    }
+    let Some(changes) = verdict.try_cast::<Map>() else {
+        return Err("on_message must return nothing, true, false, a string or a map".into());
+    };
+    for (key, value) in changes {
+        let changed = &mut outcome.incoming;
+        match key.as_str() {
+            "message" => changed.message = string(&key, value)?,
+            "title" if value.is_unit() => changed.title = None,
+            "title" => changed.title = Some(string(&key, value)?),
+            "priority" => {
+                let name = string(&key, value)?;
+                changed.priority = clap::ValueEnum::from_str(&name, true).map_err(|_| {
+                    format!("unknown priority '{}' (low, normal, high or urgent)", name)
+                })?;
+            }
+            "properties" => {
+                let Some(properties) = value.try_cast::<Map>() else {
+                    return Err("properties must be a map".into());
+                };
+                changed.properties = properties
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+            }
+            "command" if value.is_array() => {
+                let argv = value
+                    .cast::<Array>()
+                    .into_iter()
+                    .map(|arg| arg.to_string())
+                    .collect();
+                outcome.command = Some(Action::Argv(argv));
+            }
+            "command" => outcome.command = Some(Action::Shell(string(&key, value)?)),
+            "notify" => {
+                outcome.notify = Some(
+                    value
+                        .as_bool()
+                        .map_err(|_| "notify must be true or false")?,
+                )
+            }
+            _ => return Err(format!("unknown key '{}' in the returned map", key)),
+        }
+    }
+    Ok(Some(outcome))
+}
+
+#[cfg(feature = "script")]
+fn string(key: &str, value: Dynamic) -> std::result::Result<String, String> {
+    value
+        .into_string()
+        .map_err(|_| format!("{} must be a string", key))
+}
@@ -0,0 +1,75 @@
+//! Secrets in the platform keyring (macOS Keychain, Windows Credential
+//! Manager, Secret Service on Linux/BSD), referenced from presets as
+//! `keyring:<name>`, with the `keyring` feature
+
+use crate::{Error, Result};
+#[cfg(feature = "keyring")]
+use keyring::Entry;
+
+/// Keyring service every crier secret is stored under
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "crier";
+/// Marks a preset value that should be looked up in the keyring
+pub const PREFIX: &str = "keyring:";
+#[cfg(not(feature = "keyring"))]
+const UNSUPPORTED: Error = Error::Unsupported("Keyring secrets without the keyring feature");
+
+#[cfg(feature = "keyring")]
+pub fn set(name: &str, value: &str) -> Result<()> {
+    entry(name)?
+        .set_password(value)
+        .map_err(|e| keyring_err(name, e))
+}
+
+#[cfg(feature = "keyring")]
+pub fn get(name: &str) -> Result<String> {
+    entry(name)?
+        .get_password()
+        .map_err(|e| keyring_err(name, e))
+}
+
+#[cfg(feature = "keyring")]
+pub fn delete(name: &str) -> Result<()> {
+    entry(name)?
+        .delete_credential()
+        .map_err(|e| keyring_err(name, e))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn set(_name: &str, _value: &str) -> Result<()> {
+    Err(UNSUPPORTED)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn get(_name: &str) -> Result<String> {
+    Err(UNSUPPORTED)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn delete(_name: &str) -> Result<()> {
+    Err(UNSUPPORTED)
+}
+
+/// Replace a `keyring:<name>` reference in `value` with the stored secret
+pub fn resolve(value: &mut String) -> Result<()> {
+    if let Some(name) = value.strip_prefix(PREFIX) {
+        *value = get(name)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "keyring")]
+fn entry(name: &str) -> Result<Entry> {
+    Entry::new(SERVICE, name).map_err(|e| keyring_err(name, e))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_err(name: &str, e: keyring::Error) -> Error {
+    match e {
+        keyring::Error::NoEntry => Error::Config(format!(
+            "No secret '{}' in the keyring (add it with `crier secret set {}`)",
+            name, name
+        )),
+        e => Error::Config(format!("Keyring ({}): {}", name, e)),
+    }
+}
@@ -0,0 +1,151 @@
+//! Stopping a listener cleanly on SIGINT/SIGTERM: the running command gets to
+//! finish, broker connections are closed and the log is flushed before crier
+//! exits. A second signal stops it at once.
+
+use crate::Result;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use tracing::info;
+
+/// Set by the first signal; no command starts after that
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// Commands running right now
+static BUSY: Mutex<usize> = Mutex::new(0);
+static IDLE: Condvar = Condvar::new();
+/// What to do before exiting, e.g. disconnect from the broker
+#[allow(clippy::type_complexity)]
+static HOOKS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+/// Write end of the pipe the signal handler wakes the stopping thread with
+#[cfg(unix)]
+static WAKE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+/// Handle SIGINT and SIGTERM from now on: wait for the running command, run
+/// the [`on_stop`] hooks, flush the output and exit with status 0
+#[cfg(unix)]
+pub fn install() -> Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    WAKE.store(fds[1], Ordering::SeqCst);
+    let wakeup = fds[0];
+    std::thread::spawn(move || {
+        let mut signal = 0u8;
+        if unsafe { libc::read(wakeup, &mut signal as *mut u8 as *mut libc::c_void, 1) } == 1 {
+            stop(signal.into())
+        }
+    });
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install() -> Result<()> {
+    Ok(())
+}
+
+/// Only async-signal-safe calls in here
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(128 + signal) };
+    }
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            WAKE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        )
+    };
+}
+
+#[cfg(unix)]
+fn stop(signal: libc::c_int) -> ! {
+    let name = if signal == libc::SIGINT {
+        "SIGINT"
+    } else {
+        "SIGTERM"
+    };
+    crate::systemd::notify("STOPPING=1");
+    let mut busy = BUSY.lock().unwrap_or_else(PoisonError::into_inner);
+    if *busy > 0 {
+        info!(
+            "{}: stopping once the running command finishes (again to stop now)",
+            name
+        );
+    } else {
+        info!("{}: stopping", name);
+    }
+    while *busy > 0 {
+        busy = IDLE.wait(busy).unwrap_or_else(PoisonError::into_inner);
+    }
+    drop(busy);
+    run_hooks();
+    crate::daemon::stopped();
+    info!("Stopped");
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    std::process::exit(0)
+}
+
+/// Run `hook` when stopping, after the last command has finished
+pub fn on_stop(hook: impl FnOnce() + Send + 'static) {
+    HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Box::new(hook));
+}
+
+/// The listener gave up with an error: run the [`on_stop`] hooks anyway
+pub fn failed() {
+    run_hooks();
+}
+
+fn run_hooks() {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap_or_else(PoisonError::into_inner));
+    for hook in hooks {
+        hook();
+    }
+}
+
+/// Held while a command runs, so stopping waits for it
+pub struct Busy(());
+
+/// Permission to start a command, or None once stopping
+pub fn busy() -> Option<Busy> {
+    let mut busy = BUSY.lock().unwrap_or_else(PoisonError::into_inner);
+    if STOPPING.load(Ordering::SeqCst) {
+        return None;
+    }
+    *busy += 1;
+    Some(Busy(()))
+}
+
+/// Hold off stopping while finishing something already under way, such as
+/// answering the sender of a message that has been handled
+pub fn hold() -> Busy {
+    *BUSY.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    Busy(())
+}
+
+/// Whether a signal has asked crier to stop
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        *BUSY.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        IDLE.notify_all();
+    }
+}
@@ -0,0 +1,316 @@
+//! Ed25519 message signatures, so listeners only act on messages from
+//! trusted senders whatever the transport
+
+use crate::crypto::key_text;
+use crate::{Error, Incoming, Result};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Marks a signed message: `SIG:` + base64(signature) + `:` + what is
+/// signed, which is the Unix time it was signed at, a base64 nonce, the
+/// base64 topic it was sent to (empty without one) and the message, `:`
+/// between them
+const PREFIX: &str = "SIG:";
+/// How far a signature's time may be from the listener's clock, either way;
+/// the listener remembers nonces for as long
+const WINDOW: Duration = Duration::from_secs(300);
+
+/// A sender's private key (base64 PKCS#8, as written by `crier keygen`)
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// `spec` is a key file or the base64 key itself
+    pub fn load(spec: &str) -> Result<SigningKey> {
+        let der = BASE64
+            .decode(key_text(spec)?)
+            .map_err(|_| Error::Config("The signing key is not valid base64".into()))?;
+        Ed25519KeyPair::from_pkcs8(&der)
+            .map(SigningKey)
+            .map_err(|e| Error::Config(format!("Invalid signing key: {}", e)))
+    }
+
+    /// `message` signed along with the time, a fresh nonce and the `topic`
+    /// it goes to, so it can't be replayed later or posted somewhere else
+    pub fn sign(&self, message: &str, topic: Option<&str>) -> Result<String> {
+        let mut nonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::Config("No randomness available for a nonce".into()))?;
+        let signed = format!(
+            "{}:{}:{}:{}",
+            unix_time(SystemTime::now()),
+            BASE64.encode(nonce),
+            BASE64.encode(topic.unwrap_or_default()),
+            message
+        );
+        let signature = self.0.sign(signed.as_bytes());
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            BASE64.encode(signature.as_ref()),
+            signed
+        ))
+    }
+}
+
+/// A trusted sender's public key (base64, as written by `crier keygen`)
+pub struct VerifyingKey(UnparsedPublicKey<Vec<u8>>);
+
+impl VerifyingKey {
+    /// `spec` is a `.pub` file or the base64 key itself
+    pub fn load(spec: &str) -> Result<VerifyingKey> {
+        match BASE64.decode(key_text(spec)?) {
+            Ok(key) if key.len() == 32 => Ok(VerifyingKey(UnparsedPublicKey::new(&ED25519, key))),
+            _ => Err(Error::Config(format!("Invalid public key '{}'", spec))),
+        }
+    }
+}
+
+/// A message whose signature checks out, before its time, nonce and topic
+/// are
+pub struct Signed<'a> {
+    pub time: u64,
+    pub nonce: &'a str,
+    /// Empty when the sender had none (direct and HTTP modes), and then
+    /// only good where messages come without one too
+    pub topic: String,
+    pub message: &'a str,
+}
+
+/// What is inside `signed` if one of `keys` made the signature
+pub fn verify<'a>(signed: &'a str, keys: &[VerifyingKey]) -> Option<Signed<'a>> {
+    let (signature, content) = signed.strip_prefix(PREFIX)?.split_once(':')?;
+    let signature = BASE64.decode(signature).ok()?;
+    if !keys
+        .iter()
+        .any(|key| key.0.verify(content.as_bytes(), &signature).is_ok())
+    {
+        return None;
+    }
+    let mut fields = content.splitn(4, ':');
+    let (Some(time), Some(nonce), Some(topic), Some(message)) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+    Some(Signed {
+        time: time.parse().ok()?,
+        nonce,
+        topic: String::from_utf8(BASE64.decode(topic).ok()?).ok()?,
+        message,
+    })
+}
+
+/// Wrap `handler` so that, with trusted keys, it only sees messages signed by
+/// one of them (without the signature); anything else is logged and dropped.
+/// So is a message signed more than [`WINDOW`] away from now, one signed for
+/// another topic than it came on (or for none, when it came on one), and one
+/// whose nonce was seen already.
+pub fn verifying(
+    keys: Vec<VerifyingKey>,
+    mut handler: impl FnMut(&Incoming),
+) -> impl FnMut(&Incoming) {
+    // Nonces seen, with when they were signed
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    move |incoming: &Incoming| {
+        if keys.is_empty() {
+            return handler(incoming);
+        }
+        let Some(signed) = verify(&incoming.message, &keys) else {
+            return warn!(
+                "[{}] Missing or untrusted signature, ignoring",
+                incoming.source
+            );
+        };
+        let now = unix_time(SystemTime::now());
+        if signed.time.abs_diff(now) > WINDOW.as_secs() {
+            return warn!(
+                "[{}] Signed {}s away from now, ignoring",
+                incoming.source,
+                signed.time.abs_diff(now)
+            );
+        }
+        if incoming.topic.as_deref().unwrap_or_default() != signed.topic {
+            return match signed.topic.as_str() {
+                "" => warn!("[{}] Signed for no topic, ignoring", incoming.source),
+                topic => warn!("[{}] Signed for topic {}, ignoring", incoming.source, topic),
+            };
+        }
+        seen.retain(|_, time| time.abs_diff(now) <= WINDOW.as_secs());
+        if seen.insert(signed.nonce.to_string(), signed.time).is_some() {
+            return warn!("[{}] Signed message seen before, ignoring", incoming.source);
+        }
+        handler(&Incoming {
+            message: signed.message.to_string(),
+            ..incoming.clone()
+        })
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Default key location: `crier_ed25519` next to the config file
+pub fn default_key_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("crier_ed25519")
+}
+
+/// Create a key pair: the private key at `path` (readable only by the
+/// owner), the public key at `path.pub`. Returns the public key.
+pub fn keygen(path: &Path) -> Result<String> {
+    if path.exists() {
+        return Err(Error::Config(format!("{} already exists", path.display())));
+    }
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|e| Error::Config(format!("Key generation failed: {}", e)))?;
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|e| Error::Config(format!("Key generation failed: {}", e)))?;
+    let public = BASE64.encode(pair.public_key().as_ref());
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", BASE64.encode(pkcs8.as_ref()))?;
+
+    let mut public_path = path.as_os_str().to_owned();
+    public_path.push(".pub");
+    fs::write(&public_path, format!("{}\n", public))?;
+    Ok(public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> (SigningKey, VerifyingKey) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let signing = SigningKey::load(&BASE64.encode(pkcs8.as_ref())).unwrap();
+        let verifying = trusted(&signing);
+        (signing, verifying)
+    }
+
+    fn trusted(key: &SigningKey) -> VerifyingKey {
+        VerifyingKey::load(&BASE64.encode(key.0.public_key().as_ref())).unwrap()
+    }
+
+    /// `message` signed by `key` at `time`, for `topic`, with `nonce`
+    fn signed_at(key: &SigningKey, time: u64, nonce: &str, topic: &str, message: &str) -> String {
+        let content = format!("{}:{}:{}:{}", time, nonce, BASE64.encode(topic), message);
+        format!(
+            "{}{}:{}",
+            PREFIX,
+            BASE64.encode(key.0.sign(content.as_bytes()).as_ref()),
+            content
+        )
+    }
+
+    /// What a verifying handler lets through of `messages`, each arriving
+    /// on `topic`
+    fn accepted(keys: Vec<VerifyingKey>, topic: Option<&str>, messages: &[String]) -> Vec<String> {
+        let mut accepted = Vec::new();
+        let mut handler = verifying(keys, |incoming: &Incoming| {
+            accepted.push(incoming.message.clone())
+        });
+        for message in messages {
+            let mut incoming = Incoming::new(message.clone(), "test".into());
+            incoming.topic = topic.map(str::to_string);
+            handler(&incoming);
+        }
+        drop(handler);
+        accepted
+    }
+
+    #[test]
+    fn round_trip() {
+        let (signing, verifying) = keys();
+        let signed = signing.sign("hello: world", Some("alerts")).unwrap();
+        let opened = verify(&signed, &[verifying]).unwrap();
+        assert_eq!(opened.message, "hello: world");
+        assert_eq!(opened.topic, "alerts");
+        assert!(opened.time.abs_diff(unix_time(SystemTime::now())) < 5);
+    }
+
+    #[test]
+    fn rejects_tampering_and_other_keys() {
+        let (signing, verifying) = keys();
+        let (_, other) = keys();
+        let signed = signing.sign("hello", None).unwrap();
+        assert!(verify(&signed, &[other]).is_none());
+        let keys = [verifying];
+        assert!(verify(&format!("{}!", signed), &keys).is_none());
+        assert!(verify(&signed.replacen(":hello", ":hullo", 1), &keys).is_none());
+        assert!(verify("hello", &keys).is_none());
+        assert!(verify(&signed, &keys).is_some());
+    }
+
+    #[test]
+    fn accepts_with_any_trusted_key() {
+        let (signing, verifying) = keys();
+        let (_, other) = keys();
+        let signed = signing.sign("hello", None).unwrap();
+        assert_eq!(accepted(vec![other, verifying], None, &[signed]), ["hello"]);
+    }
+
+    #[test]
+    fn passes_everything_without_keys() {
+        assert_eq!(accepted(Vec::new(), None, &["hello".into()]), ["hello"]);
+    }
+
+    #[test]
+    fn rejects_outside_the_window() {
+        let (signing, verifying) = keys();
+        let now = unix_time(SystemTime::now());
+        let window = WINDOW.as_secs();
+        let messages = [
+            signed_at(&signing, now - window - 10, "YQ==", "", "old"),
+            signed_at(&signing, now + window + 10, "Yg==", "", "future"),
+            signed_at(&signing, now - window + 10, "Yw==", "", "recent"),
+        ];
+        assert_eq!(accepted(vec![verifying], None, &messages), ["recent"]);
+    }
+
+    #[test]
+    fn rejects_replayed_nonces() {
+        let (signing, verifying) = keys();
+        let signed = signing.sign("hello", Some("alerts")).unwrap();
+        let again = signing.sign("hello", Some("alerts")).unwrap();
+        let messages = [signed.clone(), signed, again];
+        assert_eq!(
+            accepted(vec![verifying], Some("alerts"), &messages),
+            ["hello", "hello"]
+        );
+    }
+
+    #[test]
+    fn rejects_other_topics() {
+        let (signing, _) = keys();
+        let messages = [
+            signing.sign("for alerts", Some("alerts")).unwrap(),
+            signing.sign("for none", None).unwrap(),
+        ];
+        assert_eq!(
+            accepted(vec![trusted(&signing)], Some("alerts"), &messages),
+            ["for alerts"]
+        );
+        assert!(accepted(vec![trusted(&signing)], Some("builds"), &messages).is_empty());
+        assert_eq!(
+            accepted(vec![trusted(&signing)], None, &messages),
+            ["for none"]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! `--spool`: messages a sender could not deliver, kept on disk for their
+//! target, one JSON object per line, until the next send there or `crier
+//! flush` gets them through, oldest first. Those the target refuses go on to
+//! a `.rejected` file next to it.
+
+use crate::{exec, Error, Format, Preset, Priority, Result, Transport};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A message waiting to be sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// When it was spooled, in RFC 3339 (UTC)
+    pub time: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    /// Why the target refused it (in the `.rejected` file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The messages waiting for one target
+pub struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    /// The spool for where `transport` sends to, in
+    /// `~/.local/state/crier/spool/` (or the platform's local data dir)
+    pub fn open(transport: &Transport) -> Result<Spool> {
+        if matches!(transport, Transport::Fanout(_)) {
+            return Err(Error::Unsupported("--spool with a fanout preset"));
+        }
+        let file: String = transport
+            .target()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let base = dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .unwrap_or_else(|| PathBuf::from("."));
+        Ok(Spool {
+            path: base
+                .join("crier")
+                .join("spool")
+                .join(format!("{}.jsonl", file)),
+        })
+    }
+
+    /// Keep `message`, with the title, priority and format `preset` gives it,
+    /// to send later
+    pub fn push(&self, preset: &Preset, message: &str) -> Result<()> {
+        let entry = Entry {
+            time: exec::timestamp(SystemTime::now()),
+            message: message.to_string(),
+            title: preset.title.clone(),
+            priority: preset.priority,
+            format: preset.format,
+            error: None,
+        };
+        let _lock = self.lock()?;
+        append(&self.path, &entry)
+    }
+
+    /// Every message waiting, oldest first; lines that aren't entries (say,
+    /// one cut short by a crash) are skipped
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::Config(format!(
+                    "Cannot read {}: {}",
+                    self.path.display(),
+                    e
+                )))
+            }
+        };
+        Ok(BufReader::new(file)
+            .lines()
+            .map_while(|line| line.ok())
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .collect())
+    }
+
+    /// Send the messages waiting with `preset`, oldest first, calling `sent`
+    /// for each. One the target refuses is moved to the `.rejected` file
+    /// (see [`Error::is_transient`]) and `rejected` called for it; the first
+    /// that can't get through stops it, and it and the rest stay in the spool.
+    pub fn flush(
+        &self,
+        preset: &Preset,
+        mut sent: impl FnMut(&Entry),
+        mut rejected: impl FnMut(&Entry, &Error),
+    ) -> Result<()> {
+        let _lock = self.lock()?;
+        let entries = self.entries()?;
+        for (i, entry) in entries.iter().enumerate() {
+            let spooled = Preset {
+                title: entry.title.clone(),
+                priority: entry.priority,
+                format: entry.format,
+                ..preset.clone()
+            };
+            match crate::send(&spooled, &entry.message) {
+                Ok(()) => sent(entry),
+                Err(e) if e.is_transient() => {
+                    self.keep(&entries[i..])?;
+                    return Err(e);
+                }
+                Err(e) => {
+                    let refused = Entry {
+                        error: Some(e.to_string()),
+                        ..entry.clone()
+                    };
+                    if let Err(e) = append(&self.rejected_path(), &refused) {
+                        self.keep(&entries[i..])?;
+                        return Err(e);
+                    }
+                    rejected(entry, &e);
+                }
+            }
+        }
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Where the messages the target refused go
+    pub fn rejected_path(&self) -> PathBuf {
+        self.path.with_extension("rejected")
+    }
+
+    /// Held until dropped, so that one send or flush at a time reads or
+    /// changes the spool
+    fn lock(&self) -> Result<File> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("lock"))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Replace what is waiting with `entries`
+    fn keep(&self, entries: &[Entry]) -> Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry).map_err(std::io::Error::from)?);
+            lines.push('\n');
+        }
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".new");
+        fs::write(&temporary, lines)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Add `entry` to the end of the file at `path`
+fn append(path: &Path, entry: &Entry) -> Result<()> {
+    let json = serde_json::to_string(entry).map_err(std::io::Error::from)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", json).as_bytes())?;
+    Ok(())
+}
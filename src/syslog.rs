@@ -0,0 +1,381 @@
+//! Syslog listener: RFC 3164 / RFC 5424 datagrams over UDP
+
+use crate::{Error, Incoming, Priority, Result};
+use std::net::UdpSocket;
+use tracing::{error, warn};
+
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// A parsed syslog entry
+#[derive(Debug, Clone)]
+pub struct SyslogMessage {
+    pub facility: u8,
+    pub severity: u8,
+    pub hostname: String,
+    pub app: String,
+    pub message: String,
+}
+
+impl SyslogMessage {
+    pub fn priority(&self) -> Priority {
+        severity_priority(self.severity)
+    }
+}
+
+/// Map a syslog severity (0 = emerg ... 7 = debug) to a priority
+pub fn severity_priority(severity: u8) -> Priority {
+    match severity {
+        0..=2 => Priority::Urgent,
+        3 => Priority::High,
+        4 | 5 => Priority::Normal,
+        _ => Priority::Low,
+    }
+}
+
+/// Which entries to pass on
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// Least severe level accepted (0 = emerg ... 7 = debug)
+    pub max_severity: u8,
+    /// Accepted facilities; empty means all
+    pub facilities: Vec<u8>,
+}
+
+impl Filter {
+    /// Build from level/facility names (e.g. `warning`, `["auth", "daemon"]`)
+    pub fn new(severity: Option<&str>, facilities: &[String]) -> Result<Filter> {
+        let max_severity = match severity {
+            Some(name) => lookup(&SEVERITIES, name, "severity")?,
+            None => 7,
+        };
+        let facilities = facilities
+            .iter()
+            .map(|name| lookup(&FACILITIES, name, "facility"))
+            .collect::<Result<_>>()?;
+        Ok(Filter {
+            max_severity,
+            facilities,
+        })
+    }
+
+    pub fn matches(&self, msg: &SyslogMessage) -> bool {
+        msg.severity <= self.max_severity
+            && (self.facilities.is_empty() || self.facilities.contains(&msg.facility))
+    }
+}
+
+fn lookup(names: &[&str], name: &str, what: &str) -> Result<u8> {
+    let name = name.to_ascii_lowercase();
+    // Accept the common long spellings too
+    let name = match name.as_str() {
+        "emergency" | "panic" => "emerg",
+        "critical" => "crit",
+        "error" => "err",
+        "warn" => "warning",
+        other => other,
+    };
+    names
+        .iter()
+        .position(|n| *n == name)
+        .map(|i| i as u8)
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "Unknown syslog {} '{}' (expected one of: {})",
+                what,
+                name,
+                names.join(", ")
+            ))
+        })
+}
+
+/// Parse an RFC 5424 (`<PRI>1 TS HOST APP ...`) or RFC 3164 (`<PRI>Mmm dd hh:mm:ss HOST TAG: MSG`) line
+pub fn parse(line: &str) -> Option<SyslogMessage> {
+    let line = line.trim_end_matches(['\r', '\n', '\0']);
+    let rest = line.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    let pri: u16 = rest[..end].parse().ok()?;
+    if pri > 191 {
+        return None;
+    }
+    let rest = &rest[end + 1..];
+    let (facility, severity) = ((pri / 8) as u8, (pri % 8) as u8);
+
+    let (hostname, app, message) = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_5424(rest),
+        None => parse_3164(rest),
+    };
+    Some(SyslogMessage {
+        facility,
+        severity,
+        hostname,
+        app,
+        message,
+    })
+}
+
+fn parse_5424(rest: &str) -> (String, String, String) {
+    // TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+    let mut parts = rest.splitn(6, ' ');
+    let _timestamp = parts.next();
+    let hostname = nil(parts.next());
+    let app = nil(parts.next());
+    let _procid = parts.next();
+    let _msgid = parts.next();
+    let rest = parts.next().unwrap_or_default();
+    let message = skip_structured_data(rest);
+    (
+        hostname,
+        app,
+        message.trim_start_matches('\u{feff}').to_string(),
+    )
+}
+
+fn parse_3164(rest: &str) -> (String, String, String) {
+    // "Mmm dd hh:mm:ss" is 15 characters; some senders omit the header entirely
+    let has_timestamp = rest.len() > 16
+        && rest.is_char_boundary(16)
+        && rest.as_bytes()[3] == b' '
+        && rest.as_bytes()[6] == b' '
+        && rest.as_bytes()[9] == b':';
+    if !has_timestamp {
+        return (String::new(), String::new(), rest.to_string());
+    }
+    let rest = &rest[16..];
+    let (hostname, rest) = rest.split_once(' ').unwrap_or(("", rest));
+    match rest.split_once(": ") {
+        Some((tag, message)) if !tag.contains(' ') => {
+            let app = tag.split('[').next().unwrap_or(tag);
+            (hostname.to_string(), app.to_string(), message.to_string())
+        }
+        _ => (hostname.to_string(), String::new(), rest.to_string()),
+    }
+}
+
+fn nil(field: Option<&str>) -> String {
+    match field {
+        Some("-") | None => String::new(),
+        Some(s) => s.to_string(),
+    }
+}
+
+/// Drop the `-` or `[id k="v"]...` element(s) in front of the message
+fn skip_structured_data(rest: &str) -> &str {
+    if let Some(msg) = rest.strip_prefix('-') {
+        return msg.strip_prefix(' ').unwrap_or(msg);
+    }
+    let mut depth = 0;
+    let mut escaped = false;
+    let mut in_quotes = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '[' if !in_quotes => depth += 1,
+            ']' if !in_quotes => depth -= 1,
+            ' ' if depth == 0 && !in_quotes => return &rest[i + 1..],
+            _ => {}
+        }
+    }
+    ""
+}
+
+pub fn bind(addr: &str) -> Result<UdpSocket> {
+    UdpSocket::bind(addr).map_err(|source| Error::Bind {
+        addr: addr.to_string(),
+        source,
+    })
+}
+
+/// Receive datagrams forever, calling `handler` for every entry passing `filter`
+pub fn serve(socket: UdpSocket, filter: &Filter, mut handler: impl FnMut(&Incoming)) -> Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Receive error: {}", e);
+                continue;
+            }
+        };
+        let datagram = String::from_utf8_lossy(&buf[..len]);
+        let Some(entry) = parse(&datagram) else {
+            warn!("[{}] Not a syslog message, ignoring", peer);
+            continue;
+        };
+        if !filter.matches(&entry) {
+            continue;
+        }
+
+        let host = if entry.hostname.is_empty() {
+            peer.ip().to_string()
+        } else {
+            entry.hostname.clone()
+        };
+        let message = if entry.app.is_empty() {
+            entry.message.clone()
+        } else {
+            format!("{}: {}", entry.app, entry.message)
+        };
+        let mut incoming = Incoming::new(message, host);
+        incoming.priority = entry.priority();
+        incoming.peer = Some(peer.ip());
+        handler(&incoming);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(line: &str) -> (u8, u8, String, String, String) {
+        let entry = parse(line).unwrap();
+        (
+            entry.facility,
+            entry.severity,
+            entry.hostname,
+            entry.app,
+            entry.message,
+        )
+    }
+
+    #[test]
+    fn rfc_5424() {
+        let line =
+            "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - 'su root' failed\n";
+        assert_eq!(
+            fields(line),
+            (
+                4,
+                2,
+                "mymachine.example.com".into(),
+                "su".into(),
+                "'su root' failed".into()
+            )
+        );
+    }
+
+    #[test]
+    fn rfc_5424_structured_data() {
+        let line = r#"<165>1 2003-10-11T22:14:15.003Z host app 1234 ID47 [exampleSDID@32473 iut="3" eventSource="Appl ication" note="a \"]\" b"][other@1 x="y"] BOMAn application event"#;
+        let line = line.replace("BOM", "\u{feff}");
+        assert_eq!(
+            fields(&line),
+            (
+                20,
+                5,
+                "host".into(),
+                "app".into(),
+                "An application event".into()
+            )
+        );
+        // Nil fields, and structured data with nothing after it
+        assert_eq!(
+            fields("<13>1 - - - - - -"),
+            (1, 5, String::new(), String::new(), String::new())
+        );
+        assert_eq!(fields("<13>1 - host app - - [a b=\"c\"]").4, "");
+    }
+
+    #[test]
+    fn rfc_3164() {
+        let line = "<13>Feb  5 17:32:18 10.0.0.99 sshd[4242]: Accepted publickey for me";
+        assert_eq!(
+            fields(line),
+            (
+                1,
+                5,
+                "10.0.0.99".into(),
+                "sshd".into(),
+                "Accepted publickey for me".into()
+            )
+        );
+        // No tag
+        let line = "<13>Feb  5 17:32:18 host just a message: with a colon";
+        assert_eq!(
+            fields(line),
+            (
+                1,
+                5,
+                "host".into(),
+                String::new(),
+                "just a message: with a colon".into()
+            )
+        );
+    }
+
+    #[test]
+    fn missing_headers() {
+        assert_eq!(
+            fields("<0>disk on fire"),
+            (0, 0, String::new(), String::new(), "disk on fire".into())
+        );
+        assert_eq!(
+            fields("<191>"),
+            (23, 7, String::new(), String::new(), String::new())
+        );
+        for line in ["no pri", "<>x", "<13", "<x>x", "13>x", "<-1>x", ""] {
+            assert!(parse(line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn pri_over_191() {
+        assert!(parse("<192>x").is_none());
+        assert!(parse("<99999>x").is_none());
+        assert!(parse("<99999999999999999999>x").is_none());
+    }
+
+    #[test]
+    fn non_ascii() {
+        // Multi-byte characters where the 3164 timestamp would be
+        assert_eq!(fields("<13>Février 5 17:32:18 x").4, "Février 5 17:32:18 x");
+        assert_eq!(fields("<13>ééééééééééééééééé").4, "ééééééééééééééééé");
+        let line = "<13>Feb  5 17:32:18 hôte app: ça marche ✓";
+        assert_eq!(
+            fields(line),
+            (1, 5, "hôte".into(), "app".into(), "ça marche ✓".into())
+        );
+        assert_eq!(fields("<13>1 - hôte äpp - - [ï d=\"é\"] ✓").4, "✓");
+        assert!(parse("<１３>x").is_none());
+    }
+
+    #[test]
+    fn filters() {
+        let filter = Filter::new(Some("warn"), &["auth".into(), "DAEMON".into()]).unwrap();
+        assert!(filter.matches(&parse("<36>1 - - - - - - x").unwrap()));
+        assert!(!filter.matches(&parse("<38>1 - - - - - - x").unwrap()));
+        assert!(!filter.matches(&parse("<12>1 - - - - - - x").unwrap()));
+        assert!(Filter::new(Some("loud"), &[]).is_err());
+        assert!(Filter::new(None, &["nope".into()]).is_err());
+    }
+}
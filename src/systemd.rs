@@ -0,0 +1,79 @@
+//! Running under systemd as a `Type=notify` service, optionally with a
+//! socket it opened for us (socket activation)
+
+use crate::{Error, Result};
+use std::net::TcpListener;
+use tracing::warn;
+
+/// First file descriptor systemd passes (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Send `state` (e.g. `READY=1`) to systemd if it is waiting for one; a
+/// no-op anywhere else
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let sent = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return,
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = sent {
+        warn!("Cannot notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// The listening TCP sockets systemd passed us, if any (one per
+/// `ListenStream=`). They are taken only once, and the commands crier runs
+/// don't inherit them.
+#[cfg(unix)]
+pub fn listeners() -> Result<Vec<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds.unwrap_or(0).max(0) {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        if listener.local_addr().is_err() {
+            return Err(Error::Config(
+                "A socket systemd passed is not a TCP socket (use ListenStream=host:port)".into(),
+            ));
+        }
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
@@ -0,0 +1,97 @@
+//! `crier tail`: follow a file like `tail -F` and send its new lines
+
+use crate::{template, Error, Result};
+use regex::Regex;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Default message for a line
+pub const DEFAULT_TEMPLATE: &str = "{line}";
+
+/// How often to look for more
+const POLL: Duration = Duration::from_millis(500);
+
+/// What to follow
+#[derive(Debug, Clone)]
+pub struct Follow {
+    pub path: PathBuf,
+    /// Only lines that match
+    pub grep: Option<Regex>,
+}
+
+impl Follow {
+    /// Fill `{line}`, `{file}` and the named groups of `grep` (`(?P<name>...)`)
+    pub fn render(&self, template: &str, line: &str) -> String {
+        let captures = self.grep.as_ref().and_then(|re| re.captures(line));
+        template::render(template, |name| match name {
+            "line" => Some(line.to_string()),
+            "file" => Some(self.path.display().to_string()),
+            _ => captures
+                .as_ref()?
+                .name(name)
+                .map(|group| group.as_str().to_string()),
+        })
+    }
+}
+
+/// Follow the file from its end, calling `on_line` for every new matching
+/// line once it is complete. A file that is truncated, or replaced (say, by
+/// log rotation), is read again from the start. Returns only on errors.
+pub fn follow(opts: &Follow, mut on_line: impl FnMut(&str)) -> Result<()> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map_err(|e| Error::Config(format!("Cannot open {}: {}", path.display(), e)))
+    };
+    let mut file = open(&opts.path)?;
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut pending = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read > 0 {
+            position += read as u64;
+            pending.extend_from_slice(&chunk[..read]);
+            while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\r', '\n']);
+                if !line.trim().is_empty() && opts.grep.as_ref().is_none_or(|re| re.is_match(line))
+                {
+                    on_line(line);
+                }
+            }
+            continue;
+        }
+        thread::sleep(POLL);
+        match fs::metadata(&opts.path) {
+            Ok(meta) if replaced(&file, &meta)? => {
+                file = open(&opts.path)?;
+                (position, pending) = (0, Vec::new());
+            }
+            Ok(meta) if meta.len() < position => {
+                position = file.seek(SeekFrom::Start(0))?;
+                pending.clear();
+            }
+            // Gone for now (mid-rotation): keep reading what is open
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Whether the path now names another file than the one open
+#[cfg(unix)]
+fn replaced(file: &File, meta: &fs::Metadata) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let open = file.metadata()?;
+    Ok((open.dev(), open.ino()) != (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn replaced(_file: &File, _meta: &fs::Metadata) -> Result<bool> {
+    Ok(false)
+}
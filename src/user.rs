@@ -0,0 +1,153 @@
+//! Running listener commands as another user, so a listener started as root
+//! (e.g. by systemd) doesn't hand root to every command it runs
+
+use crate::{Error, Result};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A user looked up by name, with everything needed to become them
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl User {
+    /// Look `name` up, failing unless this process is allowed to become them
+    #[cfg(unix)]
+    pub fn lookup(name: &str) -> Result<User> {
+        use std::ffi::{CStr, CString};
+        use std::os::unix::ffi::OsStrExt;
+
+        let unknown = || Error::Config(format!("Unknown user '{}'", name));
+        let c_name = CString::new(name).map_err(|_| unknown())?;
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut found = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if rc != 0 || found.is_null() {
+            return Err(unknown());
+        }
+        let home = PathBuf::from(std::ffi::OsStr::from_bytes(
+            unsafe { CStr::from_ptr(passwd.pw_dir) }.to_bytes(),
+        ));
+        let (uid, gid) = (passwd.pw_uid, passwd.pw_gid);
+
+        let mut groups = vec![0 as libc::gid_t; 64];
+        loop {
+            let mut count = groups.len() as libc::c_int;
+            let rc = unsafe {
+                libc::getgrouplist(
+                    c_name.as_ptr(),
+                    gid as _,
+                    groups.as_mut_ptr() as *mut _,
+                    &mut count,
+                )
+            };
+            if rc >= 0 {
+                groups.truncate(count as usize);
+                break;
+            }
+            if groups.len() >= 65536 {
+                return Err(Error::Config(format!(
+                    "Cannot list the groups of '{}'",
+                    name
+                )));
+            }
+            groups.resize(groups.len().max(count as usize) * 2, 0);
+        }
+
+        let euid = unsafe { libc::geteuid() };
+        if euid != 0 && euid != uid {
+            return Err(Error::Config(format!(
+                "Running commands as '{}' needs crier to start as root",
+                name
+            )));
+        }
+        Ok(User {
+            name: name.to_string(),
+            uid,
+            gid,
+            home,
+            groups,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn lookup(_name: &str) -> Result<User> {
+        Err(Error::Unsupported("--run-as on Windows"))
+    }
+
+    /// Set `command` up to run as this user. `HOME` is the user's unless
+    /// already set on `command` (the sandbox sets its own).
+    pub fn apply(&self, command: &mut Command) {
+        if !command.get_envs().any(|(name, _)| name == "HOME") {
+            command.env("HOME", &self.home);
+        }
+        command.env("USER", &self.name).env("LOGNAME", &self.name);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let (uid, gid) = (self.uid, self.gid);
+            let groups = self.groups.clone();
+            // Runs in the child between fork and exec, so only async-signal-safe calls
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::geteuid() == 0
+                        && libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    if libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+}
+
+/// The name of the user this process runs as, if it has one
+pub fn current_name() -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::ffi::CStr;
+
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+        let mut found = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwuid_r(
+                libc::geteuid(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if rc == 0 && !found.is_null() {
+            return Some(
+                unsafe { CStr::from_ptr(passwd.pw_name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+    }
+    ["USER", "USERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+}
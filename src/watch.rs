@@ -0,0 +1,205 @@
+//! `crier watch-fs`: files created, changed or removed under a path, as
+//! messages (inotify, FSEvents or ReadDirectoryChangesW, via `notify`), with
+//! the `watch` feature
+
+use crate::{template, Error, Result};
+use globset::GlobSet;
+#[cfg(feature = "watch")]
+use notify::event::{ModifyKind, RenameMode};
+#[cfg(feature = "watch")]
+use notify::{EventKind, RecursiveMode, Watcher};
+#[cfg(feature = "watch")]
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "watch")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "watch")]
+use std::sync::mpsc;
+use std::time::Duration;
+#[cfg(feature = "watch")]
+use std::time::Instant;
+
+/// Default message for a change
+pub const DEFAULT_TEMPLATE: &str = "{path} {change}";
+
+/// Default time a file must stay quiet before its change is sent
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// What happened to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Change {
+    Created,
+    Modified,
+    Removed,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Removed => "removed",
+        })
+    }
+}
+
+/// What to watch
+#[derive(Debug, Clone)]
+pub struct Watch {
+    /// A file, or a directory and what is in it
+    pub path: PathBuf,
+    /// Also what is in its subdirectories
+    pub recursive: bool,
+    /// Only files whose path (below `path`) matches one of these
+    pub globs: Option<GlobSet>,
+    /// Only these changes; empty means all
+    pub changes: Vec<Change>,
+    /// How long a file must go without changes before its last one is sent
+    pub debounce: Duration,
+}
+
+/// A change to one file, once it has settled
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub path: PathBuf,
+    pub change: Change,
+}
+
+impl Event {
+    /// Fill `{path}`, `{name}`, `{dir}` and `{change}`
+    pub fn render(&self, template: &str) -> String {
+        template::render(template, |name| {
+            Some(match name {
+                "path" => self.path.display().to_string(),
+                "name" => self
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                "dir" => self
+                    .path
+                    .parent()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+                "change" => self.change.to_string(),
+                _ => return None,
+            })
+        })
+    }
+}
+
+/// Watch `opts.path`, calling `on_event` for each file once it has gone
+/// `debounce` without changing. A file created and changed in that time
+/// counts as created; one created and removed again isn't reported at all.
+/// Returns only on errors.
+#[cfg(feature = "watch")]
+pub fn watch(opts: &Watch, mut on_event: impl FnMut(&Event)) -> Result<()> {
+    let (events, received) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events)
+        .map_err(|e| Error::Config(format!("Cannot watch: {}", e)))?;
+    let mode = if opts.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher
+        .watch(&opts.path, mode)
+        .map_err(|e| Error::Config(format!("Cannot watch {}: {}", opts.path.display(), e)))?;
+
+    // Changes not sent yet, with when each file last changed
+    let mut settling: HashMap<PathBuf, (Change, Instant)> = HashMap::new();
+    loop {
+        let wait = settling
+            .values()
+            .map(|(_, last)| (*last + opts.debounce).saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(Duration::MAX);
+        match received.recv_timeout(wait) {
+            Ok(Ok(event)) => {
+                for (path, change) in changes(event) {
+                    if !opts.matches(&path) {
+                        continue;
+                    }
+                    let now = Instant::now();
+                    match (settling.get(&path).map(|(earlier, _)| *earlier), change) {
+                        (Some(Change::Created), Change::Removed) => {
+                            settling.remove(&path);
+                        }
+                        (Some(Change::Created), _) => {
+                            settling.insert(path, (Change::Created, now));
+                        }
+                        _ => {
+                            settling.insert(path, (change, now));
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                return Err(Error::Config(format!(
+                    "Watching {}: {}",
+                    opts.path.display(),
+                    e
+                )))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        let settled: Vec<PathBuf> = settling
+            .iter()
+            .filter(|(_, (_, last))| last.elapsed() >= opts.debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            if let Some((change, _)) = settling.remove(&path) {
+                if opts.changes.is_empty() || opts.changes.contains(&change) {
+                    on_event(&Event { path, change });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+pub fn watch(_opts: &Watch, _on_event: impl FnMut(&Event)) -> Result<()> {
+    Err(Error::Unsupported(
+        "crier watch-fs without the watch feature",
+    ))
+}
+
+#[cfg(feature = "watch")]
+impl Watch {
+    /// Whether changes to `path` count, by the globs
+    fn matches(&self, path: &Path) -> bool {
+        let Some(globs) = &self.globs else {
+            return true;
+        };
+        let relative = path.strip_prefix(&self.path).unwrap_or(path);
+        globs.is_match(relative) || path.file_name().is_some_and(|name| globs.is_match(name))
+    }
+}
+
+/// The files `event` changed, and how; a rename removes one and creates
+/// another. Access and metadata changes don't count.
+#[cfg(feature = "watch")]
+fn changes(event: notify::Event) -> Vec<(PathBuf, Change)> {
+    let change = match event.kind {
+        EventKind::Create(_) => Change::Created,
+        EventKind::Remove(_) => Change::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Change::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Change::Created,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| (from, Change::Removed))
+                .into_iter()
+                .chain(paths.next().map(|to| (to, Change::Created)))
+                .collect();
+        }
+        EventKind::Modify(ModifyKind::Metadata(_)) => return Vec::new(),
+        EventKind::Modify(_) => Change::Modified,
+        _ => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (path, change)).collect()
+}
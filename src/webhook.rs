@@ -0,0 +1,373 @@
+//! GitHub/GitLab/Alertmanager/Grafana webhook payloads mapped to crier messages
+
+use crate::{template, Priority};
+use hmac::{Hmac, KeyInit, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// Webhook events crier understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Commits pushed to a branch
+    Push,
+    /// CI pipeline / workflow run finished
+    Pipeline,
+    /// Issue opened
+    Issue,
+    /// Alertmanager alert firing or resolved
+    Alert,
+    /// Grafana alert state change
+    Grafana,
+}
+
+impl EventKind {
+    /// Key used for this event in a preset's `webhooks:` map
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Push => "push",
+            EventKind::Pipeline => "pipeline",
+            EventKind::Issue => "issue",
+            EventKind::Alert => "alert",
+            EventKind::Grafana => "grafana",
+        }
+    }
+
+    fn default_template(self) -> &'static str {
+        match self {
+            EventKind::Push => "{repo}: {user} pushed {commits} commit(s) to {branch}",
+            EventKind::Pipeline => "{repo}: {pipeline} {status} on {branch}",
+            EventKind::Issue => "{repo}: issue #{number} opened by {user}: {title}",
+            EventKind::Alert => "[{status}] {alertname}: {summary}",
+            EventKind::Grafana => "{summary} {values}",
+        }
+    }
+}
+
+/// A recognised webhook event and the fields available to its template
+#[derive(Debug, Clone)]
+pub struct WebhookEvent {
+    pub kind: EventKind,
+    pub priority: Priority,
+    pub title: Option<String>,
+    pub fields: HashMap<String, String>,
+}
+
+impl WebhookEvent {
+    /// Render the message for this event. With a `webhooks:` map configured,
+    /// only the events listed there produce a message.
+    pub fn render(&self, templates: Option<&HashMap<String, String>>) -> Option<String> {
+        let template = match templates {
+            Some(map) => map.get(self.kind.name())?.as_str(),
+            None => self.kind.default_template(),
+        };
+        let message = template::render(template, |name| self.fields.get(name).cloned());
+        Some(message.trim().to_string())
+    }
+}
+
+/// Map a GitHub event (`X-GitHub-Event` header) to a message event
+pub fn github(event: &str, payload: &Value) -> Option<WebhookEvent> {
+    let repo = text(payload, "/repository/full_name");
+    match event {
+        "push" => Some(WebhookEvent {
+            kind: EventKind::Push,
+            priority: Priority::Normal,
+            title: None,
+            fields: fields([
+                ("repo", repo),
+                ("user", text(payload, "/pusher/name")),
+                ("branch", branch(&text(payload, "/ref"))),
+                ("commits", count(payload, "/commits")),
+                ("url", text(payload, "/compare")),
+            ]),
+        }),
+        "workflow_run" if text(payload, "/action") == "completed" => Some(WebhookEvent {
+            kind: EventKind::Pipeline,
+            priority: pipeline_priority(&text(payload, "/workflow_run/conclusion")),
+            title: None,
+            fields: fields([
+                ("repo", repo),
+                ("user", text(payload, "/sender/login")),
+                ("branch", text(payload, "/workflow_run/head_branch")),
+                ("pipeline", text(payload, "/workflow_run/name")),
+                ("status", text(payload, "/workflow_run/conclusion")),
+                ("url", text(payload, "/workflow_run/html_url")),
+            ]),
+        }),
+        "issues" if text(payload, "/action") == "opened" => Some(WebhookEvent {
+            kind: EventKind::Issue,
+            priority: Priority::Normal,
+            title: None,
+            fields: fields([
+                ("repo", repo),
+                ("user", text(payload, "/issue/user/login")),
+                ("number", text(payload, "/issue/number")),
+                ("title", text(payload, "/issue/title")),
+                ("url", text(payload, "/issue/html_url")),
+            ]),
+        }),
+        _ => None,
+    }
+}
+
+/// Map a GitLab event (`X-Gitlab-Event` header) to a message event
+pub fn gitlab(event: &str, payload: &Value) -> Option<WebhookEvent> {
+    let repo = text(payload, "/project/path_with_namespace");
+    match event {
+        "Push Hook" => Some(WebhookEvent {
+            kind: EventKind::Push,
+            priority: Priority::Normal,
+            title: None,
+            fields: fields([
+                ("repo", repo),
+                ("user", text(payload, "/user_name")),
+                ("branch", branch(&text(payload, "/ref"))),
+                ("commits", text(payload, "/total_commits_count")),
+                ("url", text(payload, "/project/web_url")),
+            ]),
+        }),
+        "Pipeline Hook"
+            if matches!(
+                text(payload, "/object_attributes/status").as_str(),
+                "success" | "failed" | "canceled"
+            ) =>
+        {
+            Some(WebhookEvent {
+                kind: EventKind::Pipeline,
+                priority: pipeline_priority(&text(payload, "/object_attributes/status")),
+                title: None,
+                fields: fields([
+                    ("repo", repo),
+                    ("user", text(payload, "/user/name")),
+                    ("branch", text(payload, "/object_attributes/ref")),
+                    (
+                        "pipeline",
+                        format!("pipeline #{}", text(payload, "/object_attributes/id")),
+                    ),
+                    ("status", text(payload, "/object_attributes/status")),
+                    ("url", text(payload, "/object_attributes/url")),
+                ]),
+            })
+        }
+        "Issue Hook" if text(payload, "/object_attributes/action") == "open" => {
+            Some(WebhookEvent {
+                kind: EventKind::Issue,
+                priority: Priority::Normal,
+                title: None,
+                fields: fields([
+                    ("repo", repo),
+                    ("user", text(payload, "/user/name")),
+                    ("number", text(payload, "/object_attributes/iid")),
+                    ("title", text(payload, "/object_attributes/title")),
+                    ("url", text(payload, "/object_attributes/url")),
+                ]),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Map an Alertmanager webhook (grouped alerts) to one event per alert.
+///
+/// Every label and annotation is available as a template field, plus
+/// `status`, `summary` (summary/description annotation, else alertname),
+/// `severity` and `url` (generator URL).
+pub fn alertmanager(payload: &Value) -> Vec<WebhookEvent> {
+    let Some(alerts) = payload.get("alerts").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    alerts
+        .iter()
+        .map(|alert| {
+            let (priority, fields) = alert_fields(alert);
+            WebhookEvent {
+                kind: EventKind::Alert,
+                priority,
+                title: None,
+                fields,
+            }
+        })
+        .collect()
+}
+
+/// Map a Grafana alert webhook to one event per alert.
+///
+/// Unified alerting payloads are Alertmanager-shaped and get the same fields,
+/// plus `rule`, `values`, `panel_url` and `dashboard_url`. Legacy alerting
+/// payloads (one rule per request) get `rule`, `state`, `status`, `summary`,
+/// `values` and `url`/`panel_url`.
+pub fn grafana(payload: &Value) -> Vec<WebhookEvent> {
+    if let Some(alerts) = payload.get("alerts").and_then(Value::as_array) {
+        return alerts
+            .iter()
+            .map(|alert| {
+                let (priority, mut fields) = alert_fields(alert);
+                let rule = fields["alertname"].clone();
+                let status = fields["status"].clone();
+                let values = alert
+                    .get("values")
+                    .and_then(Value::as_object)
+                    .map(|values| {
+                        values
+                            .iter()
+                            .map(|(k, v)| format!("{}={}", k, v))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .unwrap_or_default();
+                fields.insert("rule".into(), rule.clone());
+                fields.insert("values".into(), values);
+                fields.insert("panel_url".into(), text(alert, "/panelURL"));
+                fields.insert("dashboard_url".into(), text(alert, "/dashboardURL"));
+                WebhookEvent {
+                    kind: EventKind::Grafana,
+                    priority,
+                    title: Some(format!("[{}] {}", status.to_uppercase(), rule)),
+                    fields,
+                }
+            })
+            .collect();
+    }
+
+    if payload.get("ruleName").is_none() {
+        return Vec::new();
+    }
+    let state = text(payload, "/state");
+    let status = match state.as_str() {
+        "alerting" => "firing",
+        "ok" => "resolved",
+        other => other,
+    }
+    .to_string();
+    let priority = match state.as_str() {
+        "alerting" => Priority::High,
+        "ok" => Priority::Low,
+        _ => Priority::Normal,
+    };
+    let rule = text(payload, "/ruleName");
+    let values = payload
+        .get("evalMatches")
+        .and_then(Value::as_array)
+        .map(|matches| {
+            matches
+                .iter()
+                .map(|m| format!("{}={}", text(m, "/metric"), text(m, "/value")))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    let summary = [text(payload, "/message"), rule.clone()]
+        .into_iter()
+        .find(|s| !s.is_empty())
+        .unwrap_or_default();
+    let title = Some(text(payload, "/title")).filter(|t| !t.is_empty());
+    vec![WebhookEvent {
+        kind: EventKind::Grafana,
+        priority,
+        title: title.or_else(|| Some(format!("[{}] {}", status.to_uppercase(), rule))),
+        fields: fields([
+            ("rule", rule.clone()),
+            ("alertname", rule),
+            ("state", state),
+            ("status", status),
+            ("summary", summary),
+            ("values", values),
+            ("url", text(payload, "/ruleUrl")),
+            ("panel_url", text(payload, "/ruleUrl")),
+            ("image_url", text(payload, "/imageUrl")),
+        ]),
+    }]
+}
+
+/// Fields and priority for one Alertmanager-style alert
+fn alert_fields(alert: &Value) -> (Priority, HashMap<String, String>) {
+    let mut fields = HashMap::new();
+    // Annotations first so labels win on name clashes
+    for section in ["/annotations", "/labels"] {
+        if let Some(map) = alert.pointer(section).and_then(Value::as_object) {
+            for key in map.keys() {
+                fields.insert(key.clone(), text(alert, &format!("{}/{}", section, key)));
+            }
+        }
+    }
+    let status = text(alert, "/status");
+    let severity = text(alert, "/labels/severity");
+    let summary = [
+        "/annotations/summary",
+        "/annotations/description",
+        "/labels/alertname",
+    ]
+    .iter()
+    .map(|p| text(alert, p))
+    .find(|s| !s.is_empty())
+    .unwrap_or_default();
+    let priority = if status == "resolved" {
+        Priority::Low
+    } else {
+        Priority::from_severity(&severity)
+    };
+    fields.insert("alertname".into(), text(alert, "/labels/alertname"));
+    fields.insert("severity".into(), severity);
+    fields.insert("summary".into(), summary);
+    fields.insert("url".into(), text(alert, "/generatorURL"));
+    fields.insert("status".into(), status);
+    (priority, fields)
+}
+
+/// Check GitHub's `X-Hub-Signature-256: sha256=<hex>` against the shared secret
+pub fn verify_github(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let Some(sig) = signature.and_then(|s| s.strip_prefix("sha256=")) else {
+        return false;
+    };
+    let Ok(sig) = hex::decode(sig) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&sig).is_ok()
+}
+
+/// Alertmanager (and other senders) pass the secret as `Authorization: Bearer <secret>`
+pub fn bearer(authorization: Option<&str>) -> Option<&str> {
+    authorization.and_then(|a| a.strip_prefix("Bearer "))
+}
+
+/// String (or number) at a JSON pointer, empty if missing
+fn text(v: &Value, pointer: &str) -> String {
+    match v.pointer(pointer) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn count(v: &Value, pointer: &str) -> String {
+    v.pointer(pointer)
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len)
+        .to_string()
+}
+
+fn fields<const N: usize>(pairs: [(&str, String); N]) -> HashMap<String, String> {
+    pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
+
+/// Failed pipelines deserve more attention than passing ones
+fn pipeline_priority(status: &str) -> Priority {
+    match status {
+        "failure" | "failed" | "timed_out" => Priority::High,
+        _ => Priority::Normal,
+    }
+}
+
+/// `refs/heads/main` -> `main`
+fn branch(git_ref: &str) -> String {
+    git_ref
+        .strip_prefix("refs/heads/")
+        .or_else(|| git_ref.strip_prefix("refs/tags/"))
+        .unwrap_or(git_ref)
+        .to_string()
+}
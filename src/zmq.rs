@@ -0,0 +1,92 @@
+//! ZeroMQ relay: brokerless PUB/SUB. Listeners bind a SUB socket, senders
+//! connect a PUB socket to every listener they should reach.
+
+use crate::auth::Tokens;
+use crate::{block_on, relay, Error, Incoming, Result};
+use std::io;
+use std::time::Duration;
+use tracing::warn;
+use zeromq::{PubSocket, Socket, SocketOptions, SocketRecv, SocketSend, SubSocket, ZmqMessage};
+
+/// How long a sender waits after connecting for the listeners' subscriptions
+/// to arrive; PUB drops messages nobody has subscribed to yet
+const SUBSCRIBE_DELAY: Duration = Duration::from_millis(200);
+
+/// Bind every endpoint, subscribe to `topic` (a prefix; empty for everything)
+/// and call `handler` for every authenticated message
+pub fn listen(
+    endpoints: &[String],
+    topic: &str,
+    auth: &Tokens,
+    mut handler: impl FnMut(&Incoming),
+) -> Result<()> {
+    block_on(async {
+        let mut socket = SubSocket::new();
+        for endpoint in endpoints {
+            let endpoint = normalize(endpoint);
+            socket.bind(&endpoint).await.map_err(|e| Error::Bind {
+                addr: endpoint.clone(),
+                source: io::Error::other(e),
+            })?;
+        }
+        socket.subscribe(topic).await.map_err(rejected)?;
+
+        loop {
+            // [topic, payload]
+            let message = socket.recv().await.map_err(rejected)?;
+            let (Some(topic), Some(payload)) = (message.get(0), message.get(1)) else {
+                continue;
+            };
+            let payload = String::from_utf8_lossy(payload);
+            let source = match String::from_utf8_lossy(topic) {
+                topic if topic.is_empty() => "zmq".to_string(),
+                topic => topic.into_owned(),
+            };
+            match relay::open(&payload, auth, source) {
+                Some(incoming) => handler(&incoming),
+                None => warn!("Auth failed, ignoring message"),
+            }
+        }
+    })
+}
+
+/// Publish one message to every listener under `topic`. Fire and forget: a
+/// listener that is not subscribed to `topic` silently drops it.
+pub fn send(endpoints: &[String], topic: &str, message: &str, auth: Option<&str>) -> Result<()> {
+    block_on(async {
+        let mut options = SocketOptions::default();
+        options.connect_timeout(Duration::from_secs(5));
+        let mut socket = PubSocket::with_options(options);
+        for endpoint in endpoints {
+            let endpoint = normalize(endpoint);
+            socket
+                .connect(&endpoint)
+                .await
+                .map_err(|e| Error::Connect {
+                    addr: endpoint.clone(),
+                    source: io::Error::other(e),
+                })?;
+        }
+        tokio::time::sleep(SUBSCRIBE_DELAY).await;
+
+        let mut frames = ZmqMessage::from(topic);
+        frames.push_back(relay::seal(message, auth).into_bytes().into());
+        socket.send(frames).await.map_err(rejected)?;
+        socket.close().await;
+        Ok(())
+    })
+}
+
+/// `host:port` is shorthand for `tcp://host:port`; a `*` host (any interface)
+/// becomes 0.0.0.0, as with libzmq
+fn normalize(endpoint: &str) -> String {
+    let (scheme, rest) = endpoint.split_once("://").unwrap_or(("tcp", endpoint));
+    match rest.strip_prefix("*:") {
+        Some(port) => format!("{}://0.0.0.0:{}", scheme, port),
+        None => format!("{}://{}", scheme, rest),
+    }
+}
+
+fn rejected(e: zeromq::ZmqError) -> Error {
+    Error::Rejected(e.to_string())
+}